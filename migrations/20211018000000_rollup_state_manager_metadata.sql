@@ -0,0 +1,6 @@
+-- facts about the blocks in the db, e.g. the circuit params they are generated with.
+-- older versions created the table on startup, so it may exist already
+CREATE TABLE IF NOT EXISTS rollup_state_manager_metadata (
+    key VARCHAR(64) PRIMARY KEY,
+    value JSONB NOT NULL
+);
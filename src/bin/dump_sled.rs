@@ -1,18 +1,49 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use fluidex_common::fnv::FnvHashMap;
 use fluidex_common::serde::FrBytes;
+use fluidex_common::types::FrExt;
 use fluidex_common::Fr;
-use rollup_state_manager::r#const::sled_db::{ACCOUNTSTATES_KEY, ACCOUNTTREE_KEY, BALANCETREES_KEY, ORDERTREES_KEY};
-use rollup_state_manager::state::AccountState;
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::r#const::sled_db::{ACCOUNTSTATES_KEY, ACCOUNTTREE_KEY, BALANCETREES_KEY, BLOCK_OFFSET_KEY, ORDERTREES_KEY};
+use rollup_state_manager::state::checkpoint::Checkpoint;
+use rollup_state_manager::state::global::{account_db_key, decode_account_state};
+use rollup_state_manager::state::{AccountState, CircuitParams, Genesis, GlobalState};
+use rollup_state_manager::test_utils::types::{get_token_name_by_id, prec_token_id};
+use rollup_state_manager::types::l2::{Order, OrderSide};
+use rollup_state_manager::types::matchengine::offsets::KafkaOffsets;
 use rollup_state_manager::types::merkle_tree::Tree;
 use serde::Serialize;
 
 fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    Settings::init_default();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(String::as_str) {
+        None => dump_trees(),
+        Some("export") => {
+            let db_path = args.get(1).context("usage: dump_sled export <checkpoint.db> [output.json]")?;
+            export(Path::new(db_path), args.get(2).map(Path::new))
+        }
+        Some("genesis") => match (args.get(1), args.get(2)) {
+            (Some(db_path), Some(output)) => export_genesis(Path::new(db_path), Path::new(output)),
+            _ => bail!("usage: dump_sled genesis <checkpoint.db> <genesis.json>"),
+        },
+        Some("diff") => match (args.get(1), args.get(2)) {
+            (Some(a), Some(b)) => diff(Path::new(a), Path::new(b)),
+            _ => bail!("usage: dump_sled diff <a.db> <b.db>"),
+        },
+        Some(other) => bail!("unknown command {}, expected `export`, `genesis` or `diff`", other),
+    }
+}
+
+// dumps the raw trees of the db at SLED_DB_PATH into SLED_DUMP_PATH
+fn dump_trees() -> Result<()> {
     let sled_path: PathBuf = env::var("SLED_DB_PATH")
         .unwrap_or_else(|_| "/tmp/rollup-sled.db".to_string())
         .parse()?;
@@ -30,7 +61,7 @@ fn main() -> Result<()> {
     serde_json::to_writer_pretty(&mut fs::File::create(&dump_path.join("account_tree.json"))?, &account_tree)?;
 
     let account_states = db.open_tree(ACCOUNTSTATES_KEY).unwrap();
-    let loaded_account_states: FnvHashMap<u32, AccountState> = account_tree
+    let loaded_account_states: FnvHashMap<u64, AccountState> = account_tree
         .iter()
         .map(|(id, hash)| {
             #[derive(Serialize)]
@@ -38,13 +69,17 @@ fn main() -> Result<()> {
 
             println!("{} {}", id, hash.to_string());
             let v = account_states
-                .get(bincode::serialize(&Wrapper(*hash)).unwrap())
+                .get(bincode::serialize(&Wrapper(hash)).unwrap())
                 .ok()
                 .flatten()
                 .unwrap();
-            let (stored_id, state): (u32, AccountState) = bincode::deserialize(v.as_ref()).expect("Failed to deserialize");
-            assert_eq!(id, stored_id);
-            (stored_id, state)
+            assert!(
+                v.starts_with(&account_db_key(id)),
+                "account state of {} stored for another account",
+                id
+            );
+            let state = decode_account_state(id, v.as_ref()).expect("Failed to deserialize");
+            (id, state)
         })
         .collect();
 
@@ -57,11 +92,11 @@ fn main() -> Result<()> {
     }
 
     let balance_trees = db.open_tree(BALANCETREES_KEY).unwrap();
-    let loaded_balance_trees: FnvHashMap<u32, Tree> = loaded_account_states
+    let loaded_balance_trees: FnvHashMap<u64, Tree> = loaded_account_states
         .iter()
         .map(|(id, _)| {
             let tree = balance_trees
-                .get(bincode::serialize(&id).unwrap())
+                .get(account_db_key(*id))
                 .ok()
                 .flatten()
                 .and_then(|v| bincode::deserialize(v.as_ref()).ok())
@@ -80,11 +115,11 @@ fn main() -> Result<()> {
 
     let order_trees = db.open_tree(ORDERTREES_KEY).unwrap();
 
-    let loaded_order_trees: FnvHashMap<u32, Tree> = loaded_account_states
+    let loaded_order_trees: FnvHashMap<u64, Tree> = loaded_account_states
         .iter()
         .map(|(id, _)| {
             let tree = order_trees
-                .get(bincode::serialize(&id).unwrap())
+                .get(account_db_key(*id))
                 .ok()
                 .flatten()
                 .and_then(|v| bincode::deserialize(v.as_ref()).ok())
@@ -103,3 +138,204 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[derive(Serialize)]
+struct ExportedCheckpoint {
+    block_offset: Option<usize>,
+    kafka_offsets: KafkaOffsets,
+    circuit_params: Option<CircuitParams>,
+    root: String,
+    accounts: Vec<ExportedAccount>,
+}
+
+#[derive(Serialize)]
+struct ExportedAccount {
+    account_id: u64,
+    nonce: String,
+    sign: String,
+    ay: String,
+    // token name -> balance
+    balances: BTreeMap<String, String>,
+    orders: Vec<ExportedOrder>,
+}
+
+#[derive(Serialize, PartialEq)]
+struct ExportedOrder {
+    order_pos: u32,
+    order_id: u32,
+    side: OrderSide,
+    token_buy: String,
+    token_sell: String,
+    total_buy: String,
+    total_sell: String,
+    filled_buy: String,
+    filled_sell: String,
+    is_active: bool,
+}
+
+fn token_name(token_id: u32) -> String {
+    get_token_name_by_id(token_id).map_or_else(|| token_id.to_string(), String::from)
+}
+
+// amounts of unknown tokens are printed as raw field elements
+fn token_amount(token_id: u32, amount: &Fr) -> String {
+    match get_token_name_by_id(token_id) {
+        Some(_) => amount.to_decimal(prec_token_id(token_id)).to_string(),
+        None => amount.to_decimal_string(),
+    }
+}
+
+impl ExportedOrder {
+    fn new(order_pos: u32, order: &Order) -> Self {
+        let token_buy = order.token_buy.to_u32();
+        let token_sell = order.token_sell.to_u32();
+        Self {
+            order_pos,
+            order_id: order.order_id,
+            side: order.side,
+            token_buy: token_name(token_buy),
+            token_sell: token_name(token_sell),
+            total_buy: token_amount(token_buy, &order.total_buy),
+            total_sell: token_amount(token_sell, &order.total_sell),
+            filled_buy: token_amount(token_buy, &order.filled_buy),
+            filled_sell: token_amount(token_sell, &order.filled_sell),
+            is_active: order.is_active,
+        }
+    }
+}
+
+fn load_state(db: &sled::Db) -> Result<GlobalState> {
+    let levels = Settings::tree_levels();
+    let mut state = GlobalState::new(levels.balance, levels.order, levels.account, false);
+    state.load_persist(db)?;
+    Ok(state)
+}
+
+fn export_genesis(db_path: &Path, output: &Path) -> Result<()> {
+    let db = sled::open(db_path).with_context(|| format!("Failed to open {}", db_path.display()))?;
+    Genesis::export(&load_state(&db)?).to_file(output)
+}
+
+fn open_checkpoint(db_path: &Path) -> Result<(Option<usize>, KafkaOffsets, Option<CircuitParams>, GlobalState)> {
+    let db = sled::open(db_path).with_context(|| format!("Failed to open {}", db_path.display()))?;
+    let block_offset = db.get(BLOCK_OFFSET_KEY)?.map(|v| bincode::deserialize(v.as_ref())).transpose()?;
+    let kafka_offsets = Checkpoint::read_kafka_offsets(&db)?;
+    let circuit_params = Checkpoint::read_circuit_params(&db)?;
+    Ok((block_offset, kafka_offsets, circuit_params, load_state(&db)?))
+}
+
+fn export_account(state: &GlobalState, account_id: u64) -> ExportedAccount {
+    let account = state.get_account(account_id);
+    ExportedAccount {
+        account_id,
+        nonce: account.nonce.to_decimal_string(),
+        sign: account.sign.to_decimal_string(),
+        ay: account.ay.to_hex_string(),
+        balances: state
+            .get_token_balances(account_id)
+            .iter()
+            .map(|(token_id, balance)| (token_name(*token_id), token_amount(*token_id, balance)))
+            .collect(),
+        orders: state
+            .get_account_orders(account_id)
+            .iter()
+            .map(|(order_pos, order)| ExportedOrder::new(*order_pos, order))
+            .collect(),
+    }
+}
+
+fn load_checkpoint(db_path: &Path) -> Result<ExportedCheckpoint> {
+    let (block_offset, kafka_offsets, circuit_params, state) = open_checkpoint(db_path)?;
+    let accounts = state
+        .account_ids()
+        .into_iter()
+        .map(|account_id| export_account(&state, account_id))
+        .collect();
+
+    Ok(ExportedCheckpoint {
+        block_offset,
+        kafka_offsets,
+        circuit_params,
+        root: state.root().to_hex_string(),
+        accounts,
+    })
+}
+
+// writes the checkpoint as json, to stdout if no output path is given
+fn export(db_path: &Path, output: Option<&Path>) -> Result<()> {
+    let checkpoint = load_checkpoint(db_path)?;
+    match output {
+        Some(path) => serde_json::to_writer_pretty(&mut fs::File::create(path)?, &checkpoint)?,
+        None => serde_json::to_writer_pretty(std::io::stdout(), &checkpoint)?,
+    }
+    Ok(())
+}
+
+fn diff(a_path: &Path, b_path: &Path) -> Result<()> {
+    let (a_block_offset, a_kafka_offsets, a_circuit_params, a) = open_checkpoint(a_path)?;
+    let (b_block_offset, b_kafka_offsets, b_circuit_params, b) = open_checkpoint(b_path)?;
+    for (name, path, block_offset, kafka_offsets, circuit_params, state) in [
+        ("a", a_path, a_block_offset, a_kafka_offsets, &a_circuit_params, &a),
+        ("b", b_path, b_block_offset, b_kafka_offsets, &b_circuit_params, &b),
+    ] {
+        println!(
+            "{}: {} block_offset {:?} kafka_offsets {:?} root {}",
+            name,
+            path.display(),
+            block_offset,
+            kafka_offsets,
+            state.root().to_hex_string()
+        );
+        if let Some(circuit_params) = circuit_params {
+            println!("{}: circuit params {}", name, circuit_params);
+        }
+    }
+
+    // only the accounts with different leaves in the account trees are exported and compared
+    let a_ids: BTreeSet<u64> = a.account_ids().into_iter().collect();
+    let b_ids: BTreeSet<u64> = b.account_ids().into_iter().collect();
+    for account_id in a.changed_accounts(&b) {
+        match (a_ids.contains(&account_id), b_ids.contains(&account_id)) {
+            (true, false) => println!("account {}: only in a", account_id),
+            (false, true) => println!("account {}: only in b", account_id),
+            _ => diff_account(&export_account(&a, account_id), &export_account(&b, account_id)),
+        }
+    }
+    Ok(())
+}
+
+fn diff_account(a: &ExportedAccount, b: &ExportedAccount) {
+    let account_id = a.account_id;
+    if a.nonce != b.nonce {
+        println!("account {}: nonce {} -> {}", account_id, a.nonce, b.nonce);
+    }
+    if a.sign != b.sign || a.ay != b.ay {
+        println!("account {}: key ({}, {}) -> ({}, {})", account_id, a.sign, a.ay, b.sign, b.ay);
+    }
+
+    let tokens: BTreeSet<&String> = a.balances.keys().chain(b.balances.keys()).collect();
+    for token in tokens {
+        let a_balance = a.balances.get(token).map_or("0", String::as_str);
+        let b_balance = b.balances.get(token).map_or("0", String::as_str);
+        if a_balance != b_balance {
+            println!("account {}: balance {} {} -> {}", account_id, token, a_balance, b_balance);
+        }
+    }
+
+    let a_orders: BTreeMap<u32, &ExportedOrder> = a.orders.iter().map(|order| (order.order_id, order)).collect();
+    let b_orders: BTreeMap<u32, &ExportedOrder> = b.orders.iter().map(|order| (order.order_id, order)).collect();
+    let order_ids: BTreeSet<u32> = a_orders.keys().chain(b_orders.keys()).copied().collect();
+    let to_json = |order: Option<&&ExportedOrder>| order.map_or_else(|| "none".to_string(), |order| serde_json::to_string(order).unwrap());
+    for order_id in order_ids {
+        let (a_order, b_order) = (a_orders.get(&order_id), b_orders.get(&order_id));
+        if a_order != b_order {
+            println!(
+                "account {}: order {} {} -> {}",
+                account_id,
+                order_id,
+                to_json(a_order),
+                to_json(b_order)
+            );
+        }
+    }
+}
@@ -1,21 +1,32 @@
 #![allow(clippy::unnecessary_wraps)]
 #![allow(dead_code)]
 
+use anyhow::Context;
 use crossbeam_channel::RecvTimeoutError;
 use fluidex_common::db::models::tablenames;
 use fluidex_common::db::models::task::TaskStatus;
 use fluidex_common::db::MIGRATOR;
 use fluidex_common::non_blocking_tracing;
 use fluidex_common::types::FrExt;
-use rollup_state_manager::config::Settings;
+use rollup_state_manager::config::{Settings, StorageBackend};
 use rollup_state_manager::grpc::run_grpc_server;
+use rollup_state_manager::msg::dlq::{self, DeadLetter, DeadLetterQueue, ErrorClass};
+use rollup_state_manager::msg::msg_processor::ProcessError;
+use rollup_state_manager::msg::sig_verifier::{self, VerifiedMessage};
+use rollup_state_manager::msg::wal::{self, WalEntry, WalWriter};
 use rollup_state_manager::msg::{msg_loader, msg_processor};
-use rollup_state_manager::params;
+use rollup_state_manager::r#const::db::{CIRCUIT_PARAMS_KEY, METADATA_TABLE};
 #[cfg(feature = "persist_sled")]
 use rollup_state_manager::r#const::sled_db::*;
-use rollup_state_manager::state::{GlobalState, ManagerWrapper};
-use rollup_state_manager::test_utils::messages::WrappedMessage;
+use rollup_state_manager::shutdown::Shutdown;
+#[cfg(feature = "persist_sled")]
+use rollup_state_manager::state::checkpoint::Checkpoint;
+use rollup_state_manager::state::{CircuitParams, Genesis, GlobalState, ManagerWrapper};
 use rollup_state_manager::types::l2::{L2Block, L2BlockSerde, L2PubDataAux};
+use rollup_state_manager::types::matchengine::offsets::KafkaOffsets;
+#[cfg(feature = "persist_sled")]
+use rollup_state_manager::types::node_store::{SledStore, StateStore};
+use sqlx::migrate::Migrator;
 use sqlx::postgres::PgPool;
 use sqlx::Row;
 use std::option::Option::None;
@@ -23,6 +34,19 @@ use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 
+// the tables of the state manager itself, besides the ones of fluidex-common
+static STATE_MANAGER_MIGRATOR: Migrator = sqlx::migrate!();
+
+// how often the queue depths and the order tree occupancy are logged, also the longest wait for a message
+const STATS_INTERVAL: Duration = Duration::from_secs(120);
+// how many accounts are listed when logging the order tree occupancy
+const BUSIEST_ORDER_TREES: usize = 3;
+// how many accounts are listed when logging the tree memory on startup
+const LARGEST_ACCOUNTS: usize = 3;
+// nodes cached in memory for every state tree with the sled storage
+#[cfg(feature = "persist_sled")]
+const TREE_CACHE_CAPACITY: usize = 64;
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
@@ -35,114 +59,247 @@ async fn main() {
     run().await;
 }
 
-fn grpc_run(state: Arc<RwLock<GlobalState>>) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+fn grpc_run(state: Arc<RwLock<GlobalState>>, shutdown: Shutdown) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
         let addr = Settings::grpc_addr().parse()?;
-        run_grpc_server(addr, state)
+        run_grpc_server(addr, state, shutdown)
     }))
 }
 
 fn process_msgs(
-    msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
+    msg_receiver: crossbeam_channel::Receiver<VerifiedMessage>,
     block_sender: crossbeam_channel::Sender<L2Block>,
     state: Arc<RwLock<GlobalState>>,
     block_offset: Option<usize>,
-) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    kafka_offsets: KafkaOffsets,
+    wal_entries: Vec<WalEntry>,
+    dlq: DeadLetterQueue,
+) -> Option<std::thread::JoinHandle<anyhow::Result<ManagerWrapper>>> {
     Some(std::thread::spawn(move || {
-        let manager = ManagerWrapper::new(state, *params::NTXS, block_offset, *params::VERBOSE);
+        let mut manager = ManagerWrapper::new(state, Settings::ntxs(), block_offset, Settings::verbose());
+        manager.set_kafka_offsets(kafka_offsets);
+        manager.set_seal_policy(Settings::seal_policy().clone());
+        manager.set_block_sizes(Settings::block_sizes())?;
+        #[cfg(feature = "persist_sled")]
+        manager.set_persist_enabled(Settings::checkpoints_enabled());
         // TODO: change to to_hex_string, remove 'Fr(' and ')'
         log::info!("genesis root {}", manager.root().to_string());
 
-        run_msg_processor(msg_receiver, block_sender, manager)
+        run_msg_processor(msg_receiver, block_sender, manager, wal_entries, dlq)
     }))
 }
 
 async fn run() {
-    let state = Arc::new(RwLock::new(GlobalState::new(
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS,
-        *params::VERBOSE,
-    )));
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signals();
 
-    let (block_offset, kafka_offset) = get_persistent_offsets(Arc::clone(&state));
+    let circuit_params = CircuitParams::from(Settings::get());
+    log::info!("circuit params: {}", circuit_params);
+    let db_pool = PgPool::connect(Settings::db()).await.unwrap();
+    MIGRATOR.run(&db_pool).await.ok();
+    STATE_MANAGER_MIGRATOR.run(&db_pool).await.unwrap();
+    if let Err(e) = check_db_circuit_params(&db_pool, &circuit_params).await {
+        panic!("refuse to start: {:#}", e);
+    }
 
-    let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
-    let (blk_sender, blk_receiver) = crossbeam_channel::unbounded();
+    let levels = Settings::tree_levels();
+    let state = Arc::new(RwLock::new(GlobalState::new(
+        levels.balance,
+        levels.order,
+        levels.account,
+        Settings::verbose(),
+    )));
+    state.write().unwrap().set_order_slot_strategy(Settings::order_slot_strategy());
+    #[cfg(feature = "persist_sled")]
+    if Settings::storage() == StorageBackend::Sled {
+        state.write().unwrap().set_node_store(open_node_store().unwrap());
+    }
 
-    let loader_thread = msg_loader::load_msgs_from_mq(Settings::brokers(), kafka_offset, msg_sender);
-    let replay_thread = process_msgs(msg_receiver, blk_sender, Arc::clone(&state), block_offset);
-    let server_thread = grpc_run(state);
+    let (block_offset, kafka_offsets) = get_persistent_offsets(Arc::clone(&state));
+    if block_offset.is_none() {
+        if let Some(path) = Settings::genesis() {
+            log::info!("no checkpoint found, start from genesis {}", path.display());
+            let genesis = Genesis::from_file(path).unwrap();
+            genesis.apply(&mut state.write().unwrap()).unwrap();
+        }
+    }
+    log_tree_stats(&mut state.write().unwrap());
+
+    // messages applied after the checkpoint are replayed from the wal,
+    // so kafka only needs to be consumed from the last logged offsets
+    let wal_entries = if Settings::wal_enabled() {
+        let wal_path = Settings::persist_dir().join(wal::WAL_FILE_NAME);
+        let wal_entries = wal::read_wal(&wal_path, &kafka_offsets).unwrap();
+        log::info!("found {} wal entries after the checkpoint", wal_entries.len());
+        wal::truncate_wal(&wal_path, &kafka_offsets).unwrap();
+        wal_entries
+    } else {
+        Vec::new()
+    };
+    let mut consumed_offsets = kafka_offsets.clone();
+    for entry in &wal_entries {
+        consumed_offsets.update(&entry.offset);
+    }
 
-    let db_pool = PgPool::connect(Settings::db()).await.unwrap();
-    MIGRATOR.run(&db_pool).await.ok();
+    let dlq = DeadLetterQueue::open(&Settings::persist_dir().join(dlq::DLQ_FILE_NAME), Settings::error_policy().clone()).unwrap();
+
+    // bounded queues, so a slow db writer throttles the processor, which in turn throttles kafka consuming
+    let (msg_sender, msg_receiver) = crossbeam_channel::bounded(Settings::msg_queue_capacity());
+    let (verified_sender, verified_receiver) = crossbeam_channel::bounded(Settings::msg_queue_capacity());
+    let (blk_sender, blk_receiver) = crossbeam_channel::bounded(Settings::block_queue_capacity());
+
+    let loader_thread = msg_loader::load_msgs_from_mq(
+        Settings::brokers(),
+        Settings::kafka_topics(),
+        consumed_offsets,
+        msg_sender,
+        dlq.clone(),
+        shutdown.clone(),
+    );
+    // signatures are verified in parallel ahead of the processor, the messages keep their order
+    let verifier_thread = sig_verifier::spawn(msg_receiver, verified_sender, Arc::clone(&state), Settings::sig_verify_workers());
+    let replay_thread = process_msgs(
+        verified_receiver,
+        blk_sender,
+        Arc::clone(&state),
+        block_offset,
+        kafka_offsets,
+        wal_entries,
+        dlq,
+    );
+    let server_thread = grpc_run(state, shutdown.clone());
 
     for block in blk_receiver.iter() {
+        let timing = Instant::now();
+        let block_id = block.block_id;
         save_block_to_db(&db_pool, &block).await.unwrap();
         save_task_to_db(&db_pool, block).await.unwrap();
+        log::debug!(
+            "save block {} in {}s, {} blocks pending",
+            block_id,
+            timing.elapsed().as_secs_f32(),
+            blk_receiver.len()
+        );
     }
 
-    loader_thread.map(|h| h.join().expect("loader thread failed"));
-    replay_thread.map(|h| h.join().expect("loader thread failed"));
-    server_thread.map(|h| h.join().expect("loader thread failed"));
+    // the processor exits once the msg queue is drained on shutdown, or on an error. either way the rest stops too
+    shutdown.trigger();
+    match replay_thread.map(|h| h.join().expect("processor thread panicked")) {
+        Some(Ok(manager)) => take_final_checkpoint(manager),
+        Some(Err(e)) => log::error!("processor failed: {:#}", e),
+        None => {}
+    }
+    if let Some(Err(e)) = loader_thread.map(|h| h.join().expect("loader thread panicked")) {
+        log::error!("loader failed: {:#}", e);
+    }
+    verifier_thread.join().expect("sig verifier thread panicked");
+    if let Some(Err(e)) = server_thread.map(|h| h.join().expect("grpc thread panicked")) {
+        log::error!("grpc server failed: {:#}", e);
+    }
+    log::info!("state manager stopped");
 }
 
+// every block is in the db by now, so restarting from this checkpoint loses nothing
+#[cfg(feature = "persist_sled")]
+fn take_final_checkpoint(mut manager: ManagerWrapper) {
+    if let Err(e) = manager.checkpoint() {
+        log::warn!("skip the final checkpoint, {}", e);
+    }
+    manager.wait_checkpoints();
+}
+
+#[cfg(not(feature = "persist_sled"))]
+fn take_final_checkpoint(_manager: ManagerWrapper) {}
+
 fn run_msg_processor(
-    msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
+    msg_receiver: crossbeam_channel::Receiver<VerifiedMessage>,
     block_sender: crossbeam_channel::Sender<L2Block>,
     mut manager: ManagerWrapper,
-) -> anyhow::Result<()> {
+    wal_entries: Vec<WalEntry>,
+    dlq: DeadLetterQueue,
+) -> anyhow::Result<ManagerWrapper> {
     let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .expect("Build runtime");
 
     rt.block_on(async {
-        let mut processor = msg_processor::Processor::default();
+        let mut processor = msg_processor::Processor {
+            allow_missing_nonce: Settings::allow_missing_nonce(),
+            ..Default::default()
+        };
+
+        replay_wal(&mut processor, &mut manager, wal_entries)?;
+        let mut wal_writer = if Settings::wal_enabled() {
+            Some(WalWriter::open(
+                &Settings::persist_dir().join(wal::WAL_FILE_NAME),
+                Settings::wal_sync(),
+            )?)
+        } else {
+            None
+        };
 
         let timing = Instant::now();
         let db_pool = PgPool::connect(Settings::db()).await.unwrap();
         let mut old_block_check = true;
         let mut old_block_num = 0;
+        let mut replayed = true;
+        let mut stopped = false;
+        let mut last_stats = Instant::now();
+        let idle_timeout = Settings::seal_policy().idle_timeout_secs.map(Duration::from_secs);
         loop {
-            // In the worst case we wait for about 119 seconds timeout until we try to
-            // generate a block, if there's any tx.
-            // TODO: dynamic timeout
-            match msg_receiver.recv_timeout(Duration::from_secs(120)) {
-                Ok(msg) => {
-                    log::debug!("recv new msg {:?}", msg);
-                    match msg {
-                        WrappedMessage::DEPOSIT(deposit) => {
-                            processor.handle_deposit_msg(&mut manager, deposit);
-                        }
-                        WrappedMessage::ORDER(order) => {
-                            processor.handle_order_msg(&mut manager, order);
-                        }
-                        WrappedMessage::TRADE(trade) => {
-                            processor.handle_trade_msg(&mut manager, trade);
-                        }
-                        WrappedMessage::TRANSFER(transfer) => {
-                            processor.handle_transfer_msg(&mut manager, transfer);
-                        }
-                        WrappedMessage::USER(user) => {
-                            processor.handle_user_msg(&mut manager, user);
-                        }
-                        WrappedMessage::WITHDRAW(withdraw) => {
-                            processor.handle_withdraw_msg(&mut manager, withdraw);
+            // blocks sealed while replaying the wal are handled before receiving any new message
+            if !replayed {
+                // sealing by the idle timeout is optional, since it depends on the wall clock
+                match msg_receiver.recv_timeout(idle_timeout.unwrap_or(STATS_INTERVAL)) {
+                    Ok(verified) => {
+                        let msg = &verified.msg;
+                        log::debug!("recv new msg {:?}", msg);
+                        // handlers consume the message, so the log line is prepared beforehand
+                        let offset = msg.offset().cloned();
+                        let type_name = msg.type_name();
+                        let value = msg.to_value()?;
+                        match processor.handle_verified_msg(&mut manager, verified) {
+                            Ok(()) => {
+                                if let (Some(wal_writer), Some(offset)) = (wal_writer.as_mut(), offset) {
+                                    wal_writer.append(&offset, type_name, value, &manager.root())?;
+                                }
+                            }
+                            // rejected messages leave the state untouched, so they are not logged in the wal
+                            Err(ProcessError::Invalid(err)) => dlq.reject(DeadLetter {
+                                class: ErrorClass::Validation,
+                                reason: format!("{:#}", err),
+                                offset,
+                                msg_type: Some(type_name.to_string()),
+                                payload: value.to_string(),
+                            })?,
+                            Err(err) => return Err(err.into()),
                         }
                     }
-                }
-                Err(err) => match err {
-                    RecvTimeoutError::Timeout => {
-                        if manager.has_raw_tx() {
-                            manager.flush_with_nop();
+                    Err(err) => match err {
+                        RecvTimeoutError::Timeout => {
+                            if idle_timeout.is_some() && manager.has_raw_tx() {
+                                manager.flush_with_nop();
+                            }
                         }
-                    }
-                    RecvTimeoutError::Disconnected => break,
-                },
-            };
+                        // the msg queue is drained after the loader stopped, the blocks popped below are the last ones
+                        RecvTimeoutError::Disconnected => {
+                            if Settings::seal_policy().on_shutdown {
+                                manager.flush_with_nop();
+                            }
+                            stopped = true;
+                        }
+                    },
+                };
+            }
+            replayed = false;
 
-            for block in manager.pop_all_blocks() {
+            let blocks = manager.pop_all_blocks();
+            // a block in the db must never be ahead of the wal
+            if let Some(wal_writer) = wal_writer.as_mut().filter(|_| !blocks.is_empty()) {
+                wal_writer.sync()?;
+            }
+            for block in blocks {
                 if old_block_check && is_present_block(&db_pool, &block).await.unwrap() {
                     // Skips this old block.
                     old_block_num += 1;
@@ -152,7 +309,14 @@ fn run_msg_processor(
                 // Once the block is a new one, no need to check if old.
                 old_block_check = false;
 
-                block_sender.try_send(block).unwrap();
+                if block_sender.is_full() {
+                    log::warn!("block queue is full ({}), wait for the db writer", block_sender.len());
+                }
+                block_sender.send(block).map_err(|_| anyhow::anyhow!("block queue disconnected"))?;
+            }
+
+            if let (Some(wal_writer), Some(offsets)) = (wal_writer.as_mut(), manager.take_checkpointed_offsets()) {
+                wal_writer.truncate(&offsets)?;
             }
 
             let block_num = manager.get_block_generate_num() - old_block_num;
@@ -160,14 +324,116 @@ fn run_msg_processor(
             log::info!(
                 "generate {} blocks with block_size {} in {}s: average TPS: {}",
                 block_num,
-                *params::NTXS,
+                Settings::ntxs(),
                 secs,
-                (*params::NTXS * block_num) as f32 / secs
+                (Settings::ntxs() * block_num) as f32 / secs
             );
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                last_stats = Instant::now();
+                log::info!(
+                    "queue depth: msgs {}/{}, blocks {}/{}",
+                    msg_receiver.len(),
+                    Settings::msg_queue_capacity(),
+                    block_sender.len(),
+                    Settings::block_queue_capacity()
+                );
+                // scans every order tree, so it is skipped unless asked for
+                if log::log_enabled!(log::Level::Debug) {
+                    for (account_id, occupancy) in manager.busiest_order_trees(BUSIEST_ORDER_TREES) {
+                        log::debug!(
+                            "order tree of account {}: {} open, {} closed, capacity {}",
+                            account_id,
+                            occupancy.open,
+                            occupancy.closed,
+                            occupancy.capacity
+                        );
+                    }
+                }
+            }
+            if stopped {
+                break;
+            }
         }
 
-        Ok(())
-    })
+        Ok::<(), anyhow::Error>(())
+    })?;
+    Ok(manager)
+}
+
+// the checkpoints saved by older versions may still hold default nodes, they are dropped here
+fn log_tree_stats(state: &mut GlobalState) {
+    let removed = state.compact_trees();
+    let stats = state.tree_stats();
+    log::info!(
+        "state trees: {} bytes, {} account nodes, {} balance nodes, {} order nodes, {} default nodes compacted",
+        stats.bytes(),
+        stats.account_tree.nodes(),
+        stats.balance_trees.nodes(),
+        stats.order_trees.nodes(),
+        removed
+    );
+    for (account_id, account_stats) in state.largest_accounts(LARGEST_ACCOUNTS) {
+        log::info!(
+            "trees of account {}: {} bytes, {} nodes",
+            account_id,
+            account_stats.bytes,
+            account_stats.nodes()
+        );
+    }
+}
+
+// re-applies the logged messages on top of the checkpoint,
+// the state must reach exactly the same root after each of them
+fn replay_wal(processor: &mut msg_processor::Processor, manager: &mut ManagerWrapper, wal_entries: Vec<WalEntry>) -> anyhow::Result<()> {
+    let timing = Instant::now();
+    let replay_num = wal_entries.len();
+    for entry in wal_entries {
+        processor.handle_msg(manager, entry.msg)?;
+        let root = manager.root().to_hex_string();
+        if root != entry.root {
+            anyhow::bail!(
+                "state diverged from wal at offset {:?}: logged root {}, replayed root {}",
+                entry.offset,
+                entry.root,
+                root
+            );
+        }
+    }
+    if replay_num > 0 {
+        log::info!("replay {} wal entries in {}s", replay_num, timing.elapsed().as_secs_f32());
+    }
+    Ok(())
+}
+
+// the params are recorded by the first run, the later runs must generate blocks of the same params as the ones in the db
+async fn check_db_circuit_params(pool: &PgPool, params: &CircuitParams) -> anyhow::Result<()> {
+    let row = sqlx::query(&format!("select value from {} where key = $1", METADATA_TABLE))
+        .bind(CIRCUIT_PARAMS_KEY)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(row) = row {
+        let recorded: sqlx::types::Json<CircuitParams> = row.try_get(0)?;
+        return params
+            .check_compatible(&recorded.0)
+            .with_context(|| format!("the blocks in the db are generated with {}", recorded.0));
+    }
+
+    let has_blocks = sqlx::query(&format!("select block_id from {} limit 1", tablenames::L2_BLOCK))
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+    if has_blocks {
+        log::warn!(
+            "no circuit params recorded for the blocks in the db, assume they are generated with {}",
+            params
+        );
+    }
+    sqlx::query(&format!("insert into {} (key, value) values ($1, $2)", METADATA_TABLE))
+        .bind(CIRCUIT_PARAMS_KEY)
+        .bind(sqlx::types::Json(params))
+        .execute(pool)
+        .await?;
+    Ok(())
 }
 
 // Returns true if already present in DB, otherwise false.
@@ -221,7 +487,7 @@ async fn save_block_to_db(pool: &PgPool, block: &L2Block) -> anyhow::Result<()>
 }
 
 async fn save_task_to_db(pool: &PgPool, block: L2Block) -> anyhow::Result<()> {
-    let tx_num = block.detail.encoded_txs.len();
+    let block_size = block.block_size;
     let input = L2BlockSerde::from(block.detail);
     let task_id = unique_task_id();
 
@@ -230,7 +496,7 @@ async fn save_task_to_db(pool: &PgPool, block: L2Block) -> anyhow::Result<()> {
         tablenames::TASK
     ))
     .bind(task_id)
-    .bind(format!("block_{}", tx_num))
+    .bind(format!("block_{}", block_size))
     .bind(block.block_id as i64) // TODO: will it overflow?
     .bind(sqlx::types::Json(input))
     .bind(TaskStatus::Inited)
@@ -257,34 +523,73 @@ fn get_latest_dump() -> anyhow::Result<Option<usize>> {
     Ok(dumps.last().copied())
 }
 
+// the trees are rebuilt from the checkpoint or genesis, so the nodes of the last run are dropped
+#[cfg(feature = "persist_sled")]
+fn open_node_store() -> anyhow::Result<StateStore> {
+    let path = Settings::persist_dir().join(NODE_STORE_DIR);
+    if path.exists() {
+        fs::remove_dir_all(&path)?;
+    }
+    let db = sled::open(&path)?;
+    Ok(StateStore::Sled(SledStore::new(
+        db.open_tree(NODE_STORE_TREE)?,
+        TREE_CACHE_CAPACITY,
+    )))
+}
+
 #[cfg(feature = "persist_sled")]
 fn get_block_offset(db: &Option<sled::Db>, state: Arc<RwLock<GlobalState>>) -> Option<usize> {
     db.as_ref().and_then(|db| {
-        state.write().unwrap().load_persist(db).unwrap();
+        if let Err(e) = state.write().unwrap().load_persist(db) {
+            panic!("refuse to start from the checkpoint: {}", e);
+        }
         db.get(BLOCK_OFFSET_KEY).ok().flatten().and_then(|v| bincode::deserialize(&v).ok())
     })
 }
 
 #[cfg(feature = "persist_sled")]
-fn get_kafka_offset(db: &Option<sled::Db>) -> Option<i64> {
+fn check_checkpoint_circuit_params(id: usize, db: &Option<sled::Db>) {
+    let recorded = match db.as_ref().map(Checkpoint::read_circuit_params).transpose().unwrap().flatten() {
+        Some(recorded) => recorded,
+        None if Settings::allow_legacy_checkpoint() => {
+            log::warn!("no circuit params recorded in dump #{}, only the tree heights are checked", id);
+            return;
+        }
+        None => panic!(
+            "refuse to start from dump #{} without recorded circuit params, set allow_legacy_checkpoint to only check its tree heights",
+            id
+        ),
+    };
+    if let Err(e) = CircuitParams::from(Settings::get()).check_compatible(&recorded) {
+        panic!("refuse to start from dump #{} generated with {}: {}", id, recorded, e);
+    }
+}
+
+#[cfg(feature = "persist_sled")]
+fn get_kafka_offsets(db: &Option<sled::Db>) -> KafkaOffsets {
     db.as_ref()
-        .and_then(|db| db.get(KAFKA_OFFSET_KEY).ok().flatten().and_then(|v| bincode::deserialize(&v).ok()))
+        .map(|db| Checkpoint::read_kafka_offsets(db).unwrap())
+        .unwrap_or_default()
 }
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "persist_sled")] {
-        fn get_persistent_offsets(state: Arc<RwLock<GlobalState>>) -> (Option<usize>, Option<i64>) {
+        fn get_persistent_offsets(state: Arc<RwLock<GlobalState>>) -> (Option<usize>, KafkaOffsets) {
+            if !Settings::checkpoints_enabled() {
+                return (None, KafkaOffsets::default());
+            }
             get_latest_dump().unwrap().map_or_else(
-                || (None, None),
+                || (None, KafkaOffsets::default()),
                 |id| {
                 log::info!("found dump #{}", id);
                 let db = sled::open(Settings::persist_dir().join(format!("{}.db", id))).ok();
-                (get_block_offset(&db, state), get_kafka_offset(&db))
+                check_checkpoint_circuit_params(id, &db);
+                (get_block_offset(&db, state), get_kafka_offsets(&db))
                 })
         }
     } else {
-        fn get_persistent_offsets(_state: Arc<RwLock<GlobalState>>) -> (Option<usize>, Option<i64>) {
-            (None, None)
+        fn get_persistent_offsets(_state: Arc<RwLock<GlobalState>>) -> (Option<usize>, KafkaOffsets) {
+            (None, KafkaOffsets::default())
         }
     }
 }
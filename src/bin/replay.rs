@@ -0,0 +1,164 @@
+// replays messages offline into a fresh or checkpointed state and prints the root of every block,
+// it never writes to the db or the persist dir.
+//
+// usage: replay <msgs.jsonl | kafka:<topic>:<partition>> [options]
+//   --checkpoint <n.db>      start from a checkpoint instead of an empty state
+//   --genesis <genesis.json> start from a genesis snapshot
+//   --start <offset>         first offset (line number of a file) to replay
+//   --end <offset>           last offset to replay, inclusive
+//   --types <a,b,..>         only replay these message types, e.g. `DepositMessage,TradeMessage`
+//   --stop-at-block <id>     stop once block <id> is generated
+//   --flush                  seal the last partial block with nops
+
+use anyhow::{bail, Context, Result};
+use fluidex_common::types::FrExt;
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::msg::msg_loader::{self, MsgRange};
+use rollup_state_manager::msg::msg_processor::Processor;
+#[cfg(feature = "persist_sled")]
+use rollup_state_manager::r#const::sled_db::BLOCK_OFFSET_KEY;
+#[cfg(feature = "persist_sled")]
+use rollup_state_manager::state::checkpoint::Checkpoint;
+use rollup_state_manager::state::{Genesis, GlobalState, ManagerWrapper};
+use rollup_state_manager::types::matchengine::offsets::KafkaOffsets;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+#[derive(Default)]
+struct Options {
+    source: String,
+    checkpoint: Option<PathBuf>,
+    genesis: Option<PathBuf>,
+    range: MsgRange,
+    stop_at_block: Option<usize>,
+    flush: bool,
+}
+
+fn parse_args() -> Result<Options> {
+    let mut args = env::args().skip(1);
+    let mut opts = Options {
+        source: args
+            .next()
+            .context("usage: replay <msgs.jsonl | kafka:<topic>:<partition>> [options]")?,
+        ..Default::default()
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("missed value of {}", arg));
+        match arg.as_str() {
+            "--checkpoint" => opts.checkpoint = Some(value()?.into()),
+            "--genesis" => opts.genesis = Some(value()?.into()),
+            "--start" => opts.range.start = Some(value()?.parse()?),
+            "--end" => opts.range.end = Some(value()?.parse()?),
+            "--types" => opts.range.types = value()?.split(',').map(str::to_string).collect(),
+            "--stop-at-block" => opts.stop_at_block = Some(value()?.parse()?),
+            "--flush" => opts.flush = true,
+            other => bail!("unknown option {}", other),
+        }
+    }
+    if opts.checkpoint.is_some() && opts.genesis.is_some() {
+        bail!("--checkpoint and --genesis can not be used together");
+    }
+    Ok(opts)
+}
+
+#[cfg(feature = "persist_sled")]
+fn load_checkpoint(path: &Path, state: &mut GlobalState) -> Result<(Option<usize>, KafkaOffsets)> {
+    let db = sled::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    state.load_persist(&db)?;
+    let block_offset = db.get(BLOCK_OFFSET_KEY)?.map(|v| bincode::deserialize(&v)).transpose()?;
+    Ok((block_offset, Checkpoint::read_kafka_offsets(&db)?))
+}
+
+#[cfg(not(feature = "persist_sled"))]
+fn load_checkpoint(_path: &Path, _state: &mut GlobalState) -> Result<(Option<usize>, KafkaOffsets)> {
+    bail!("--checkpoint requires the persist_sled feature")
+}
+
+fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    Settings::init_default();
+    let mut opts = parse_args()?;
+
+    let levels = Settings::tree_levels();
+    let mut state = GlobalState::new(levels.balance, levels.order, levels.account, Settings::verbose());
+    let (block_offset, kafka_offsets) = match (&opts.checkpoint, &opts.genesis) {
+        (Some(path), _) => load_checkpoint(path, &mut state)?,
+        (_, Some(path)) => {
+            Genesis::from_file(path)?.apply(&mut state)?;
+            (None, KafkaOffsets::default())
+        }
+        _ => (None, KafkaOffsets::default()),
+    };
+    println!(
+        "start from block {} root {}",
+        block_offset.unwrap_or(0),
+        state.root().to_hex_string()
+    );
+
+    let (msg_sender, msg_receiver) = crossbeam_channel::bounded(Settings::msg_queue_capacity());
+    let loader_thread = match opts.source.strip_prefix("kafka:") {
+        Some(stream) => {
+            let (topic, partition) = stream.rsplit_once(':').context("expect kafka:<topic>:<partition>")?;
+            let partition: i32 = partition.parse()?;
+            // continues right after the checkpoint unless told otherwise
+            if opts.range.start.is_none() {
+                opts.range.start = kafka_offsets.get(topic, partition).map(|offset| offset + 1);
+            }
+            msg_loader::load_msgs_from_mq_range(Settings::brokers(), topic, partition, opts.range.clone(), msg_sender)
+        }
+        None => msg_loader::load_msgs_from_file_range(&opts.source, opts.range.clone(), msg_sender),
+    };
+
+    let mut manager = ManagerWrapper::new(Arc::new(RwLock::new(state)), Settings::ntxs(), block_offset, Settings::verbose());
+    manager.set_kafka_offsets(kafka_offsets);
+    manager.set_seal_policy(Settings::seal_policy().clone());
+    manager.set_block_sizes(Settings::block_sizes())?;
+    #[cfg(feature = "persist_sled")]
+    manager.set_persist_enabled(false);
+    let mut processor = Processor {
+        allow_missing_nonce: Settings::allow_missing_nonce(),
+        ..Default::default()
+    };
+
+    let mut msg_num = 0;
+    let mut stopped = false;
+    for msg in msg_receiver.iter() {
+        let offset = msg.offset().cloned();
+        processor
+            .handle_msg(&mut manager, msg)
+            .with_context(|| format!("failed at message #{} offset {:?}", msg_num, offset))?;
+        msg_num += 1;
+        if print_blocks(&mut manager, opts.stop_at_block) {
+            stopped = true;
+            break;
+        }
+    }
+    if !stopped && opts.flush && manager.has_raw_tx() {
+        manager.flush_with_nop();
+        print_blocks(&mut manager, opts.stop_at_block);
+    }
+    // the loader may be blocked on a full queue after stopping early
+    drop(msg_receiver);
+    if let Some(handle) = loader_thread {
+        if let Err(e) = handle.join().expect("loader thread failed") {
+            if !stopped {
+                return Err(e);
+            }
+        }
+    }
+
+    println!("replayed {} messages, root {}", msg_num, manager.root().to_hex_string());
+    Ok(())
+}
+
+// returns true once the block to stop at is generated
+fn print_blocks(manager: &mut ManagerWrapper, stop_at_block: Option<usize>) -> bool {
+    for block in manager.pop_all_blocks() {
+        println!("block {} root {}", block.block_id, block.detail.new_root.to_hex_string());
+        if stop_at_block == Some(block.block_id) {
+            return true;
+        }
+    }
+    false
+}
@@ -1,19 +1,179 @@
 use std::env;
 use std::path::Path;
 
-use once_cell::sync::OnceCell;
+use crate::msg::dlq::ErrorPolicy;
+use crate::msg::wal::WalSync;
+use crate::state::{OrderSlotStrategy, SealPolicy};
+use anyhow::{bail, ensure};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::Deserialize;
+use std::collections::HashSet;
 
 #[doc(hidden)]
 static SETTINGS: OnceCell<Settings> = OnceCell::new();
 
+// the circuit params used to be read from these env vars, they still override the config files
+const ENV_OVERRIDES: [(&str, &str); 6] = [
+    ("NTXS", "ntxs"),
+    ("BALANCELEVELS", "tree_levels.balance"),
+    ("ORDERLEVELS", "tree_levels.order"),
+    ("ACCOUNTLEVELS", "tree_levels.account"),
+    ("VERBOSE", "verbose"),
+    ("CIRCUIT_VER", "circuit_version"),
+];
+
+// token ids and order positions are u32, the account tree is limited by the flattened node index
+const MAX_BALANCE_LEVELS: usize = 31;
+const MAX_ORDER_LEVELS: usize = 31;
+const MAX_ACCOUNT_LEVELS: usize = 62;
+
+/// The tokens used when the config does not list any.
+/// Should be consistent with dingir-exchange/migrations/20210223072038_markets_preset.sql
+pub static DEFAULT_TOKENS: Lazy<Vec<Token>> = Lazy::new(|| {
+    // only USDT can be quote, quote prec = price prec + amount prec
+    [("ETH", 4), ("USDT", 4 + 2), ("UNI", 4), ("LINK", 4), ("YFI", 4), ("MATIC", 4)]
+        .iter()
+        .enumerate()
+        .map(|(id, (symbol, prec))| Token {
+            id: id as u32,
+            symbol: symbol.to_string(),
+            prec: *prec,
+        })
+        .collect()
+});
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct KafkaTopic {
+    pub name: String,
+    pub partitions: Vec<i32>,
+}
+
+fn default_msg_queue_capacity() -> usize {
+    10000
+}
+
+fn default_block_queue_capacity() -> usize {
+    16
+}
+
+fn default_sig_verify_workers() -> usize {
+    4
+}
+
+fn default_tokens() -> Vec<Token> {
+    DEFAULT_TOKENS.clone()
+}
+
+fn default_kafka_topics() -> Vec<KafkaTopic> {
+    vec![KafkaTopic {
+        name: "unifyevents".to_string(),
+        partitions: vec![0],
+    }]
+}
+
+// the heights of the state trees, which must match the circuit and the checkpoints
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TreeLevels {
+    pub balance: usize,
+    pub order: usize,
+    pub account: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Token {
+    pub id: u32,
+    pub symbol: String,
+    // decimal places of the amounts in the l2 txs
+    pub prec: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    // the nodes of the state trees are kept in memory
+    Memory,
+    // the nodes are kept in a sled db under `persist_dir`, with only the hot ones cached in memory.
+    // it is slower, but bounds the memory used by a large state. the db is rebuilt on every start
+    Sled,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        Self::Memory
+    }
+}
+
+fn default_checkpoints_enabled() -> bool {
+    cfg!(feature = "persist_sled")
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Settings {
+    // the largest block size, a circuit of it must be deployed
+    pub ntxs: usize,
+    pub tree_levels: TreeLevels,
+    #[serde(default)]
+    pub verbose: bool,
+    // the version in `circuits/circuits.ver` of the deployed circuits
+    #[serde(default)]
+    pub circuit_version: Option<String>,
+    // the account receiving the trading fees, recorded along with the circuit params
+    #[serde(default)]
+    pub fee_account: Option<u64>,
+    #[serde(default = "default_tokens")]
+    pub tokens: Vec<Token>,
+    // where the nodes of the state trees are stored
+    #[serde(default)]
+    pub storage: StorageBackend,
+    // checkpoints are dumped into sled dbs under `persist_dir` every `persist_every_n_block` blocks.
+    // without them the state is rebuilt from genesis and kafka on every start
+    #[serde(default = "default_checkpoints_enabled")]
+    pub checkpoints_enabled: bool,
     pub brokers: String,
+    // the messages of all the partitions are merged into a single stream. the merged order depends on
+    // when the idle partitions are given up on, so merging several partitions requires the wal to record it
+    #[serde(default = "default_kafka_topics")]
+    pub kafka_topics: Vec<KafkaTopic>,
     pub grpc_addr: String,
     pub db: String,
     pub persist_dir: Box<Path>,
     pub persist_every_n_block: usize,
+    // starts from a checkpoint written before the circuit params were recorded. only its tree heights
+    // are checked against the config, the other params can not be, so it is refused unless this is set
+    #[serde(default)]
+    pub allow_legacy_checkpoint: bool,
+    #[serde(default)]
+    pub genesis: Option<Box<Path>>,
+    // whether to halt or skip after a message is put into the dead-letter queue
+    #[serde(default)]
+    pub error_policy: ErrorPolicy,
+    // logs the applied messages, so the ones after the latest checkpoint are replayed without kafka.
+    // it is truncated once a checkpoint is written, so it requires the checkpoints
+    #[serde(default = "default_checkpoints_enabled")]
+    pub wal_enabled: bool,
+    // whether the wal is fsynced after every message or only before a sealed block is handed over
+    #[serde(default)]
+    pub wal_sync: WalSync,
+    // accepts the transfers and withdrawals without a nonce, which the older producers send and the older wal
+    // entries carry. they are signed with nonce 0 rather than the sender's one. a nonce which is present is always checked
+    #[serde(default)]
+    pub allow_missing_nonce: bool,
+    // the pipeline is throttled once a queue is full, so these bound the memory used by pending messages and blocks
+    #[serde(default = "default_msg_queue_capacity")]
+    pub msg_queue_capacity: usize,
+    #[serde(default = "default_block_queue_capacity")]
+    pub block_queue_capacity: usize,
+    // threads verifying the signatures of incoming messages ahead of the processor
+    #[serde(default = "default_sig_verify_workers")]
+    pub sig_verify_workers: usize,
+    #[serde(default)]
+    pub seal_policy: SealPolicy,
+    // block sizes with a circuit deployed, besides `ntxs`. partially filled blocks are sealed into the smallest fitting one
+    #[serde(default)]
+    pub block_sizes: Vec<usize>,
+    // how a filled or cancelled order is picked for replacement once the order tree of an account is full
+    #[serde(default)]
+    pub order_slot_strategy: OrderSlotStrategy,
 }
 
 impl Default for Settings {
@@ -37,19 +197,123 @@ impl Settings {
         conf.merge(config_rs::File::with_name(&format!("config/{}", run_mode)).required(false))
             .unwrap();
 
-        Self::set(conf.try_into().unwrap());
+        for (var, key) in ENV_OVERRIDES {
+            if let Ok(value) = env::var(var) {
+                conf.set(key, value).unwrap();
+            }
+        }
+
+        let settings: Self = conf.try_into().unwrap();
+        if let Err(e) = settings.validate() {
+            panic!("invalid config: {:#}", e);
+        }
+        Self::set(settings);
     }
 
     pub fn new() -> Self {
         Settings {
+            ntxs: 2,
+            tree_levels: TreeLevels {
+                balance: 3,
+                order: 3,
+                account: 5,
+            },
+            verbose: false,
+            circuit_version: None,
+            fee_account: None,
+            tokens: default_tokens(),
+            storage: StorageBackend::default(),
+            checkpoints_enabled: default_checkpoints_enabled(),
             brokers: String::new(),
+            kafka_topics: default_kafka_topics(),
             grpc_addr: String::new(),
             db: String::new(),
             persist_dir: Box::from(Path::new(".")),
             persist_every_n_block: 0,
+            allow_legacy_checkpoint: false,
+            genesis: None,
+            error_policy: ErrorPolicy::default(),
+            wal_enabled: default_checkpoints_enabled(),
+            wal_sync: WalSync::default(),
+            allow_missing_nonce: false,
+            msg_queue_capacity: default_msg_queue_capacity(),
+            block_queue_capacity: default_block_queue_capacity(),
+            sig_verify_workers: default_sig_verify_workers(),
+            seal_policy: SealPolicy::default(),
+            block_sizes: Vec::new(),
+            order_slot_strategy: OrderSlotStrategy::default(),
         }
     }
 
+    // checks the settings are consistent with each other, so a bad config is refused on startup
+    // rather than failing on the first message or block
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(self.ntxs > 0, "ntxs should be positive");
+        if let Some(size) = self.block_sizes.iter().find(|size| **size == 0 || **size > self.ntxs) {
+            bail!("invalid block size {}, should be in 1..={}", size, self.ntxs);
+        }
+
+        let levels = self.tree_levels;
+        for (name, level, max) in [
+            ("balance", levels.balance, MAX_BALANCE_LEVELS),
+            ("order", levels.order, MAX_ORDER_LEVELS),
+            ("account", levels.account, MAX_ACCOUNT_LEVELS),
+        ] {
+            ensure!((1..=max).contains(&level), "{} levels {} should be in 1..={}", name, level, max);
+        }
+        if let Some(fee_account) = self.fee_account {
+            ensure!(
+                fee_account < 1 << levels.account,
+                "fee account {} overflows for account levels {}",
+                fee_account,
+                levels.account
+            );
+        }
+
+        ensure!(!self.tokens.is_empty(), "no token configured");
+        let mut ids = HashSet::new();
+        let mut symbols = HashSet::new();
+        for token in &self.tokens {
+            ensure!(ids.insert(token.id), "duplicated token id {}", token.id);
+            ensure!(symbols.insert(token.symbol.as_str()), "duplicated token {}", token.symbol);
+            ensure!(
+                u64::from(token.id) < 1 << levels.balance,
+                "token {} id {} overflows for balance levels {}",
+                token.symbol,
+                token.id,
+                levels.balance
+            );
+        }
+
+        ensure!(!self.kafka_topics.is_empty(), "no kafka topic configured");
+        for topic in &self.kafka_topics {
+            ensure!(
+                !topic.partitions.is_empty(),
+                "no partition configured for kafka topic {}",
+                topic.name
+            );
+        }
+
+        if self.storage == StorageBackend::Sled {
+            ensure!(cfg!(feature = "persist_sled"), "sled storage requires the persist_sled feature");
+        }
+        if self.checkpoints_enabled {
+            ensure!(cfg!(feature = "persist_sled"), "checkpoints require the persist_sled feature");
+            ensure!(self.persist_every_n_block > 0, "persist_every_n_block should be positive");
+        }
+        ensure!(
+            !self.wal_enabled || self.checkpoints_enabled,
+            "the wal requires checkpoints, it would never be truncated"
+        );
+        let stream_num: usize = self.kafka_topics.iter().map(|topic| topic.partitions.len()).sum();
+        ensure!(
+            stream_num == 1 || self.wal_enabled,
+            "merging {} kafka partitions requires the wal, the merged order can not be reproduced from kafka",
+            stream_num
+        );
+        Ok(())
+    }
+
     /// Sets the contents of this cell to the singleton `Settings`
     /// and returns the reference to it.
     ///
@@ -68,12 +332,71 @@ impl Settings {
         SETTINGS.get().unwrap()
     }
 
+    /// Gets the reference to the singleton `Settings`, or `None` if it is not set yet.
+    pub fn try_get() -> Option<&'static Self> {
+        SETTINGS.get()
+    }
+
+    /// Shortcut of `Self::get().ntxs`
+    #[inline(always)]
+    pub fn ntxs() -> usize {
+        Self::get().ntxs
+    }
+
+    /// Shortcut of `Self::get().tree_levels`
+    #[inline(always)]
+    pub fn tree_levels() -> TreeLevels {
+        Self::get().tree_levels
+    }
+
+    /// Shortcut of `Self::get().verbose`
+    #[inline(always)]
+    pub fn verbose() -> bool {
+        Self::get().verbose
+    }
+
+    /// Shortcut of `Self::get().circuit_version.as_deref()`
+    #[inline(always)]
+    pub fn circuit_version() -> Option<&'static str> {
+        Self::get().circuit_version.as_deref()
+    }
+
+    /// Shortcut of `Self::get().fee_account`
+    #[inline(always)]
+    pub fn fee_account() -> Option<u64> {
+        Self::get().fee_account
+    }
+
+    /// Shortcut of `Self::get().tokens.as_slice()`
+    #[inline(always)]
+    pub fn tokens() -> &'static [Token] {
+        Self::get().tokens.as_slice()
+    }
+
+    /// Shortcut of `Self::get().storage`
+    #[inline(always)]
+    pub fn storage() -> StorageBackend {
+        Self::get().storage
+    }
+
+    /// Shortcut of `Self::get().checkpoints_enabled`
+    #[inline(always)]
+    pub fn checkpoints_enabled() -> bool {
+        Self::get().checkpoints_enabled
+    }
+
     /// Shortcut of `Self::get().brokers.as_str()`
     #[inline(always)]
     pub fn brokers() -> &'static str {
         Self::get().brokers.as_str()
     }
 
+    /// Shortcut of `Self::get().kafka_topics.as_slice()`
+    #[inline(always)]
+    pub fn kafka_topics() -> &'static [KafkaTopic] {
+        Self::get().kafka_topics.as_slice()
+    }
+
     /// Shortcut of `Self::get().grpc_addr.as_str()`
     #[inline(always)]
     pub fn grpc_addr() -> &'static str {
@@ -95,4 +418,115 @@ impl Settings {
     pub fn persist_every_n_block() -> usize {
         Self::get().persist_every_n_block
     }
+
+    /// Shortcut of `Self::get().allow_legacy_checkpoint`
+    #[inline(always)]
+    pub fn allow_legacy_checkpoint() -> bool {
+        Self::get().allow_legacy_checkpoint
+    }
+
+    /// Shortcut of `Self::get().genesis`
+    #[inline(always)]
+    pub fn genesis() -> Option<&'static Path> {
+        Self::get().genesis.as_deref()
+    }
+
+    /// Shortcut of `Self::get().error_policy`
+    #[inline(always)]
+    pub fn error_policy() -> &'static ErrorPolicy {
+        &Self::get().error_policy
+    }
+
+    /// Shortcut of `Self::get().wal_enabled`
+    #[inline(always)]
+    pub fn wal_enabled() -> bool {
+        Self::get().wal_enabled
+    }
+
+    /// Shortcut of `Self::get().wal_sync`
+    #[inline(always)]
+    pub fn wal_sync() -> WalSync {
+        Self::get().wal_sync
+    }
+
+    /// Shortcut of `Self::get().allow_missing_nonce`
+    #[inline(always)]
+    pub fn allow_missing_nonce() -> bool {
+        Self::get().allow_missing_nonce
+    }
+
+    /// Shortcut of `Self::get().msg_queue_capacity`
+    #[inline(always)]
+    pub fn msg_queue_capacity() -> usize {
+        Self::get().msg_queue_capacity
+    }
+
+    /// Shortcut of `Self::get().block_queue_capacity`
+    #[inline(always)]
+    pub fn block_queue_capacity() -> usize {
+        Self::get().block_queue_capacity
+    }
+
+    /// Shortcut of `Self::get().sig_verify_workers`
+    #[inline(always)]
+    pub fn sig_verify_workers() -> usize {
+        Self::get().sig_verify_workers
+    }
+
+    /// Shortcut of `Self::get().seal_policy`
+    #[inline(always)]
+    pub fn seal_policy() -> &'static SealPolicy {
+        &Self::get().seal_policy
+    }
+
+    /// Shortcut of `Self::get().block_sizes.as_slice()`
+    #[inline(always)]
+    pub fn block_sizes() -> &'static [usize] {
+        Self::get().block_sizes.as_slice()
+    }
+
+    /// Shortcut of `Self::get().order_slot_strategy`
+    #[inline(always)]
+    pub fn order_slot_strategy() -> OrderSlotStrategy {
+        Self::get().order_slot_strategy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut settings = Settings::new();
+        settings.persist_every_n_block = 100;
+        settings.validate().unwrap();
+
+        settings.tree_levels.account = MAX_ACCOUNT_LEVELS + 1;
+        assert!(settings.validate().is_err());
+        settings.tree_levels.account = MAX_ACCOUNT_LEVELS;
+        settings.fee_account = Some(1 << settings.tree_levels.account);
+        assert!(settings.validate().is_err());
+        settings.fee_account = Some(0);
+        settings.validate().unwrap();
+        settings.tokens.push(Token {
+            id: 1 << settings.tree_levels.balance,
+            symbol: "DAI".to_string(),
+            prec: 4,
+        });
+        assert!(settings.validate().is_err());
+        settings.tokens.pop();
+        settings.block_sizes = vec![settings.ntxs + 1];
+        assert!(settings.validate().is_err());
+        settings.block_sizes.clear();
+        settings.checkpoints_enabled = true;
+        settings.persist_every_n_block = 0;
+        assert!(settings.validate().is_err());
+        settings.checkpoints_enabled = false;
+        settings.wal_enabled = true;
+        assert!(settings.validate().is_err());
+        settings.wal_enabled = false;
+        settings.kafka_topics[0].partitions.push(1);
+        assert!(settings.validate().is_err());
+    }
 }
@@ -1,11 +1,25 @@
 #[cfg(feature = "persist_sled")]
 pub mod sled_db {
     pub const BLOCK_OFFSET_KEY: &str = "block_offset";
+    // only read from checkpoints written before KAFKA_OFFSETS_KEY
     pub const KAFKA_OFFSET_KEY: &str = "kafka_offset";
+    pub const KAFKA_OFFSETS_KEY: &str = "kafka_offsets";
     pub const ACCOUNTTREE_KEY: &str = "account_tree";
     pub const ACCOUNTSTATES_KEY: &str = "account_states";
     pub const BALANCETREES_KEY: &str = "balance_trees";
     pub const ORDERTREES_KEY: &str = "order_trees";
     pub const ORDERSTATES_KEY: &str = "order_states";
     pub const NEXT_ORDER_POSITIONS_KEY: &str = "next_order_positions";
+    // only missed in checkpoints written before the free slots were persisted
+    pub const FREE_ORDER_SLOTS_KEY: &str = "free_order_slots";
+    pub const CIRCUIT_PARAMS_KEY: &str = "circuit_params";
+    // the nodes of the state trees with the sled storage, not a checkpoint
+    pub const NODE_STORE_DIR: &str = "nodes";
+    pub const NODE_STORE_TREE: &str = "nodes";
+}
+
+pub mod db {
+    // created by the migrations of the state manager, the other tables come from the migrations of fluidex-common
+    pub const METADATA_TABLE: &str = "rollup_state_manager_metadata";
+    pub const CIRCUIT_PARAMS_KEY: &str = "circuit_params";
 }
@@ -108,7 +108,8 @@ impl Controller {
                         new_balance,
                     })
                 }
-                TxType::Transfer => {
+                // a leg of a batch transfer has the same layout as a transfer
+                TxType::Transfer | TxType::BatchTransfer => {
                     let from = tx[tx_detail_idx::ACCOUNT_ID1].0.to_u32();
                     let to = tx[tx_detail_idx::ACCOUNT_ID2].0.to_u32();
 
@@ -220,7 +221,7 @@ impl Controller {
             ));
         };
 
-        let balance = self.state.read().unwrap().get_token_balance(request.account_id, token_id);
+        let balance = self.state.read().unwrap().get_token_balance(request.account_id.into(), token_id);
         let precision = prec_token_id(token_id);
 
         Ok(TokenBalanceQueryResponse {
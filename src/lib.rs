@@ -10,7 +10,7 @@ pub mod config;
 pub mod r#const;
 pub mod grpc;
 pub mod msg;
-pub mod params;
+pub mod shutdown;
 pub mod state;
 pub mod test_utils;
 pub mod types;
@@ -0,0 +1,102 @@
+use crate::types::matchengine::offsets::MsgOffset;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const DLQ_FILE_NAME: &str = "dlq.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    // the message can not be decoded
+    Parse,
+    // the message is decoded but rejected by the processor before touching the state
+    Validation,
+    // some offsets of a partition are missing
+    OffsetGap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorAction {
+    Halt,
+    Skip,
+}
+
+// what to do after a message is put into the dlq, for each error class.
+// halting on everything keeps the old behaviour.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ErrorPolicy {
+    pub parse: ErrorAction,
+    pub validation: ErrorAction,
+    pub offset_gap: ErrorAction,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            parse: ErrorAction::Halt,
+            validation: ErrorAction::Halt,
+            offset_gap: ErrorAction::Halt,
+        }
+    }
+}
+
+impl ErrorPolicy {
+    pub fn action(&self, class: ErrorClass) -> ErrorAction {
+        match class {
+            ErrorClass::Parse => self.parse,
+            ErrorClass::Validation => self.validation,
+            ErrorClass::OffsetGap => self.offset_gap,
+        }
+    }
+}
+
+// one line of the dlq file
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub class: ErrorClass,
+    pub reason: String,
+    pub offset: Option<MsgOffset>,
+    // the kafka key or the `type` of a wrapped message
+    pub msg_type: Option<String>,
+    pub payload: String,
+}
+
+// shared by the loader and the processor, both append to the same file
+#[derive(Clone)]
+pub struct DeadLetterQueue {
+    writer: Arc<Mutex<BufWriter<File>>>,
+    policy: ErrorPolicy,
+}
+
+impl DeadLetterQueue {
+    pub fn open(path: &Path, policy: ErrorPolicy) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+            policy,
+        })
+    }
+
+    // records the letter, then returns an error if the policy says the pipeline should halt
+    pub fn reject(&self, letter: DeadLetter) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            serde_json::to_writer(&mut *writer, &letter)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+        match self.policy.action(letter.class) {
+            ErrorAction::Skip => {
+                log::warn!("skip message at {:?}: {}", letter.offset, letter.reason);
+                Ok(())
+            }
+            ErrorAction::Halt => Err(anyhow!("halt on message at {:?}: {}", letter.offset, letter.reason)),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use super::msg_utils::{bytes_to_sig, decode_l2_pubkey, TokenIdPair};
+use crate::test_utils::messages::WrappedMessage;
+use crate::test_utils::types::try_get_token_id_by_name;
+use crate::types::matchengine::messages::{
+    BatchTransferMessage, DepositMessage, Order, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage,
+};
+use anyhow::{anyhow, ensure};
+use fluidex_common::rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// version 0 is the unwrapped payload published by dingir-exchange so far, whose type is given
+// out of band: by the kafka key, or by the `type` field of a line in the test data files.
+// version 1 wraps the same payload together with its type, source and id.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("malformed message: {0}")]
+    Malformed(anyhow::Error),
+    #[error("invalid message: {0}")]
+    Invalid(anyhow::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    // the service which published the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    // unique in the source, only used for tracing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>,
+    // same as `WrappedMessage::type_name`
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub value: Value,
+}
+
+impl Envelope {
+    pub fn from_legacy(msg_type: &str, value: Value) -> Self {
+        Self {
+            version: 0,
+            source: None,
+            msg_id: None,
+            msg_type: msg_type.to_string(),
+            value,
+        }
+    }
+
+    // a kafka payload is either an envelope, or an unwrapped message of `msg_type`
+    pub fn from_kafka(msg_type: &str, payload: Value) -> Result<Self, DecodeError> {
+        if payload.get("version").is_some() {
+            serde_json::from_value(payload).map_err(|e| DecodeError::Malformed(anyhow!("wrong envelope: {}", e)))
+        } else {
+            Ok(Self::from_legacy(msg_type, payload))
+        }
+    }
+
+    // a line of the test data files is either an envelope, or `{"type": .., "value": ..}`
+    pub fn from_value(v: &Value) -> Result<Self, DecodeError> {
+        if v.get("version").is_some() {
+            return serde_json::from_value(v.clone()).map_err(|e| DecodeError::Malformed(anyhow!("wrong envelope: {}", e)));
+        }
+        match &v["type"] {
+            Value::String(msg_type) => Ok(Self::from_legacy(msg_type, v["value"].clone())),
+            _ => Err(DecodeError::Malformed(anyhow!("missed or unexpected type field"))),
+        }
+    }
+
+    pub fn decode(self) -> Result<WrappedMessage, DecodeError> {
+        let msg = match self.version {
+            0 | 1 => decode_v1(&self.msg_type, self.value)?,
+            version => return Err(DecodeError::Malformed(anyhow!("unsupported schema version {}", version))),
+        };
+        if let (Some(source), Some(msg_id)) = (&self.source, &self.msg_id) {
+            log::debug!("decoded {} {} from {}", self.msg_type, msg_id, source);
+        }
+        validate(&msg).map_err(DecodeError::Invalid)?;
+        Ok(msg)
+    }
+}
+
+fn decode_v1(msg_type: &str, value: Value) -> Result<WrappedMessage, DecodeError> {
+    fn parse<T: DeserializeOwned>(value: Value, name: &str) -> Result<T, DecodeError> {
+        serde_json::from_value(value).map_err(|e| DecodeError::Malformed(anyhow!("wrong {}: {}", name, e)))
+    }
+
+    let msg = match msg_type {
+        "DepositMessage" => WrappedMessage::DEPOSIT(parse::<DepositMessage>(value, "deposit")?.into()),
+        "OrderMessage" => WrappedMessage::ORDER(parse::<OrderMessage>(value, "order")?.into()),
+        "TradeMessage" => WrappedMessage::TRADE(parse::<TradeMessage>(value, "trade")?.into()),
+        "TransferMessage" => WrappedMessage::TRANSFER(parse::<TransferMessage>(value, "transfer")?.into()),
+        "BatchTransferMessage" => WrappedMessage::BATCH_TRANSFER(parse::<BatchTransferMessage>(value, "batch transfer")?.into()),
+        "UserMessage" => WrappedMessage::USER(parse::<UserMessage>(value, "user")?.into()),
+        "WithdrawMessage" => WrappedMessage::WITHDRAW(parse::<WithdrawMessage>(value, "withdraw")?.into()),
+        other => return Err(DecodeError::Malformed(anyhow!("unrecognized type field {}", other))),
+    };
+    Ok(msg)
+}
+
+// checks which only depend on the message itself, the ones depending on the state are left to the processor
+pub fn validate(msg: &WrappedMessage) -> anyhow::Result<()> {
+    match msg {
+        WrappedMessage::DEPOSIT(deposit) => {
+            known_asset(&deposit.asset)?;
+            non_negative(deposit.change, "deposit change")?;
+            non_negative(deposit.balance, "balance")?;
+        }
+        WrappedMessage::ORDER(order) => {
+            TokenIdPair::try_from_market(&order.order.market)?;
+            known_asset(&order.base)?;
+            known_asset(&order.quote)?;
+            non_negative(order.order.amount, "order amount")?;
+            positive(order.order.price, "order price")?;
+        }
+        WrappedMessage::TRADE(trade) => {
+            TokenIdPair::try_from_market(&trade.market)?;
+            ensure!(
+                trade.market == format!("{}_{}", trade.base, trade.quote),
+                "market {} does not match {}/{}",
+                trade.market,
+                trade.base,
+                trade.quote
+            );
+            positive(trade.price, "trade price")?;
+            non_negative(trade.amount, "trade amount")?;
+            non_negative(trade.quote_amount, "trade quote amount")?;
+            for order in trade.ask_order.iter().chain(trade.bid_order.iter()) {
+                validate_trade_order(order, &trade.market)?;
+            }
+        }
+        WrappedMessage::TRANSFER(transfer) => {
+            known_asset(&transfer.asset)?;
+            non_negative(transfer.amount, "transfer amount")?;
+            bytes_to_sig(transfer.signature)?;
+        }
+        WrappedMessage::BATCH_TRANSFER(batch) => {
+            known_asset(&batch.asset)?;
+            ensure!(!batch.legs.is_empty(), "batch transfer without legs");
+            for leg in &batch.legs {
+                ensure!(leg.user_to != batch.user_from, "batch transfer to the sender {}", leg.user_to);
+                non_negative(leg.amount, "transfer amount")?;
+            }
+            bytes_to_sig(batch.signature)?;
+        }
+        WrappedMessage::USER(user) => {
+            decode_l2_pubkey(&user.l2_pubkey)?;
+        }
+        WrappedMessage::WITHDRAW(withdraw) => {
+            known_asset(&withdraw.asset)?;
+            ensure!(!withdraw.change.is_sign_positive(), "positive withdraw change {}", withdraw.change);
+            non_negative(withdraw.balance, "balance")?;
+            bytes_to_sig(withdraw.signature)?;
+        }
+    }
+    Ok(())
+}
+
+// the orders carried by a trade are put into the order tree, so they must be signed
+fn validate_trade_order(order: &Order, market: &str) -> anyhow::Result<()> {
+    ensure!(
+        order.market == market,
+        "order {} of market {} in a trade of {}",
+        order.id,
+        order.market,
+        market
+    );
+    non_negative(order.amount, "order amount")?;
+    positive(order.price, "order price")?;
+    bytes_to_sig(order.signature)?;
+    Ok(())
+}
+
+fn known_asset(asset: &str) -> anyhow::Result<()> {
+    try_get_token_id_by_name(asset)
+        .map(|_| ())
+        .ok_or_else(|| anyhow!("unknown token {}", asset))
+}
+
+fn non_negative(amount: Decimal, name: &str) -> anyhow::Result<()> {
+    ensure!(!amount.is_sign_negative(), "negative {} {}", name, amount);
+    Ok(())
+}
+
+// a zero price gives an order nothing to buy or sell for
+fn positive(amount: Decimal, name: &str) -> anyhow::Result<()> {
+    ensure!(amount.is_sign_positive() && !amount.is_zero(), "non-positive {} {}", name, amount);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deposit(change: &str) -> Value {
+        json!({
+            "timestamp": 1.0,
+            "user_id": 0,
+            "asset": "ETH",
+            "business": "deposit",
+            "change": change,
+            "balance": change,
+            "balance_available": change,
+            "balance_frozen": "0",
+            "detail": "",
+        })
+    }
+
+    #[test]
+    fn test_decode_versions() {
+        let legacy = Envelope::from_kafka("DepositMessage", deposit("1.5")).unwrap();
+        assert_eq!(legacy.version, 0);
+        assert!(matches!(legacy.decode(), Ok(WrappedMessage::DEPOSIT(_))));
+
+        let wrapped = json!({
+            "version": SCHEMA_VERSION,
+            "source": "matchengine",
+            "msg_id": "42",
+            "type": "DepositMessage",
+            "value": deposit("1.5"),
+        });
+        let envelope = Envelope::from_kafka("DepositMessage", wrapped).unwrap();
+        assert_eq!(envelope.msg_id.as_deref(), Some("42"));
+        assert!(matches!(envelope.decode(), Ok(WrappedMessage::DEPOSIT(_))));
+
+        let unknown = json!({"version": SCHEMA_VERSION + 1, "type": "DepositMessage", "value": deposit("1.5")});
+        let envelope = Envelope::from_value(&unknown).unwrap();
+        assert!(matches!(envelope.decode(), Err(DecodeError::Malformed(_))));
+
+        let negative = Envelope::from_legacy("DepositMessage", deposit("-1"));
+        assert!(matches!(negative.decode(), Err(DecodeError::Invalid(_))));
+    }
+}
@@ -1,3 +1,7 @@
+pub mod dlq;
+pub mod envelope;
 pub mod msg_loader;
 pub mod msg_processor;
 pub mod msg_utils;
+pub mod sig_verifier;
+pub mod wal;
@@ -1,54 +1,162 @@
+use crate::config::KafkaTopic;
+use crate::msg::dlq::{DeadLetter, DeadLetterQueue, ErrorClass};
+use crate::msg::envelope::{DecodeError, Envelope};
+use crate::shutdown::Shutdown;
 use crate::test_utils::messages::{parse_msg, WrappedMessage};
-use crate::types::matchengine::messages::{DepositMessage, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage};
+use crate::types::matchengine::offsets::{KafkaOffsets, MsgOffset};
 //use fluidex_common::message::consumer::{Simple, SimpleConsumer, SimpleMessageHandler};
+use anyhow::Context;
+use crossbeam_channel::SendTimeoutError;
 use fluidex_common::rdkafka;
-use rdkafka::consumer::{Consumer, ConsumerContext, MessageStream, StreamConsumer};
+use rdkafka::consumer::{BaseConsumer, Consumer, StreamConsumer};
 use rdkafka::error::KafkaError;
 use rdkafka::message::{BorrowedMessage, Message};
 use rdkafka::{Offset, TopicPartitionList};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 //use std::sync::{Mutex};
 use futures::StreamExt;
 
+// selects the messages to load. the offsets of a file are its line numbers, starting from 0
+#[derive(Debug, Clone, Default)]
+pub struct MsgRange {
+    pub start: Option<i64>,
+    // inclusive
+    pub end: Option<i64>,
+    // `WrappedMessage::type_name`s to keep, all types are kept if empty
+    pub types: Vec<String>,
+}
+
+impl MsgRange {
+    fn is_before_start(&self, offset: i64) -> bool {
+        self.start.map_or(false, |start| offset < start)
+    }
+
+    fn is_after_end(&self, offset: i64) -> bool {
+        self.end.map_or(false, |end| offset > end)
+    }
+
+    fn accepts(&self, msg: &WrappedMessage) -> bool {
+        self.types.is_empty() || self.types.iter().any(|t| t == msg.type_name())
+    }
+}
+
 pub fn load_msgs_from_file(
     filepath: &str,
     sender: crossbeam_channel::Sender<WrappedMessage>,
+) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    load_msgs_from_file_range(filepath, MsgRange::default(), sender)
+}
+
+pub fn load_msgs_from_file_range(
+    filepath: &str,
+    range: MsgRange,
+    sender: crossbeam_channel::Sender<WrappedMessage>,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     let filepath = filepath.to_string();
     println!("loading from {}", filepath);
     Some(std::thread::spawn(move || {
-        let file = File::open(filepath)?;
+        let file = File::open(&filepath)?;
         // since
-        for l in BufReader::new(file).lines() {
-            let msg = parse_msg(l?).expect("invalid data");
-            sender.try_send(msg)?;
+        for (offset, l) in BufReader::new(file).lines().enumerate() {
+            let offset = offset as i64;
+            if range.is_after_end(offset) {
+                break;
+            }
+            let l = l?;
+            if range.is_before_start(offset) {
+                continue;
+            }
+            let msg =
+                parse_msg(l).with_context(|| format!("invalid message at line {} (offset {}) of {}", offset + 1, offset, filepath))?;
+            if range.accepts(&msg) {
+                sender.send(msg)?;
+            }
+        }
+        Ok(())
+    }))
+}
+
+// loads a fixed range of a single partition and stops, used for replaying offline
+pub fn load_msgs_from_mq_range(
+    brokers: &str,
+    topic: &str,
+    partition: i32,
+    range: MsgRange,
+    sender: crossbeam_channel::Sender<WrappedMessage>,
+) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    let brokers = brokers.to_owned();
+    let topic = topic.to_owned();
+    Some(std::thread::spawn(move || {
+        let consumer: BaseConsumer = rdkafka::config::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", "rollup_msg_replay")
+            .set("enable.partition.eof", "true")
+            .set("enable.auto.commit", "false")
+            .create()?;
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition_offset(&topic, partition, range.start.map_or(Offset::Beginning, Offset::Offset))?;
+        consumer.assign(&partitions)?;
+        loop {
+            match consumer.poll(Duration::from_secs(10)) {
+                None => log::info!("waiting for messages of {}/{}", topic, partition),
+                Some(Err(KafkaError::PartitionEOF(_))) => {
+                    log::info!("reach the end of {}/{}", topic, partition);
+                    break;
+                }
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(m)) => {
+                    if range.is_after_end(m.offset()) {
+                        break;
+                    }
+                    let offset = MsgOffset::new(&topic, partition, m.offset());
+                    if let Some(msg) = parse_kafka_msg(m.key(), m.payload(), offset)? {
+                        if range.accepts(&msg) {
+                            sender.send(msg)?;
+                        }
+                    }
+                }
+            }
         }
         Ok(())
     }))
 }
 
-const UNIFY_TOPIC: &str = "unifyevents";
+// how often a loader blocked by a full msg queue checks for the shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// how often the streams without pending messages are checked for being caught up
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const WATERMARK_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+// the clock skew tolerated between this host and the producers
+const WATERMARK_SKEW: Duration = Duration::from_secs(1);
+
 const MSG_TYPE_DEPOSITS: &str = "deposits";
 const MSG_TYPE_ORDERS: &str = "orders";
 const MSG_TYPE_TRADES: &str = "trades";
 const MSG_TYPE_TRANSFERS: &str = "transfers";
+const MSG_TYPE_BATCH_TRANSFERS: &str = "batchtransfers";
 const MSG_TYPE_USERS: &str = "registeruser";
 const MSG_TYPE_WITHDRAWS: &str = "withdraws";
 
 pub fn load_msgs_from_mq(
     brokers: &str,
-    offset: Option<i64>,
+    topics: &[KafkaTopic],
+    offsets: KafkaOffsets,
     sender: crossbeam_channel::Sender<WrappedMessage>,
+    dlq: DeadLetterQueue,
+    shutdown: Shutdown,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     let brokers = brokers.to_owned();
+    let streams: Vec<(String, i32)> = topics
+        .iter()
+        .flat_map(|topic| topic.partitions.iter().map(move |partition| (topic.name.clone(), *partition)))
+        .collect();
     Some(std::thread::spawn(move || {
         let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
 
-        let mut writer = MessageWriter {
-            sender,
-            offset: offset.unwrap_or(-1),
-        };
+        let mut writer = MessageWriter::new(sender, streams, &offsets, dlq, shutdown.clone());
         rt.block_on(async move {
             let mut config = rdkafka::config::ClientConfig::new();
             config
@@ -57,116 +165,341 @@ pub fn load_msgs_from_mq(
                 .set("enable.partition.eof", "false")
                 .set("session.timeout.ms", "6000")
                 .set("enable.auto.commit", "false");
-            if offset.is_none() {
+            if offsets.is_empty() {
                 config.set("auto.offset.reset", "earliest");
             }
             let mut consumer: StreamConsumer = config.create().unwrap();
             loop {
                 //alway reset to last offset
                 let handle = tokio::runtime::Handle::current();
-                let assign_offset = writer.last_offset();
+                let assignment = writer.assignment();
                 let join_handle = handle.spawn_blocking(move || {
                     let mut partitions = TopicPartitionList::new();
-                    let offset = if assign_offset < 0 {
-                        Offset::Beginning
-                    } else {
-                        Offset::Offset(assign_offset)
-                    };
-                    log::debug!("assign offset {:?} to consumer", offset);
-                    partitions.add_partition_offset(UNIFY_TOPIC, 0, offset).unwrap();
+                    for (topic, partition, offset) in assignment {
+                        log::debug!("assign offset {:?} of {}/{} to consumer", offset, topic, partition);
+                        partitions.add_partition_offset(&topic, partition, offset).unwrap();
+                    }
                     consumer.assign(&partitions).unwrap();
                     return consumer;
                 });
 
                 consumer = join_handle.await.unwrap();
 
+                // dropping the sender closes the msg queue, the stages downstream exit once they drain it
                 tokio::select! {
-                    _ = tokio::signal::ctrl_c() => {
-                        log::info!("Ctrl-c received, shutting down");
-                        return;
+                    _ = shutdown.wait() => {
+                        log::info!("stop consuming kafka");
+                        return Ok(());
                     },
 
-                    err = writer.handle_stream(consumer.stream()) => {
-                        log::error!("Kafka consumer error: {}", err);
+                    err = writer.handle_stream(&consumer) => match err {
+                        StreamError::Kafka(err) => log::error!("Kafka consumer error: {}", err),
+                        StreamError::Halt(err) => return Err(err),
+                        StreamError::Shutdown => {
+                            log::info!("stop consuming kafka");
+                            return Ok(());
+                        }
                     }
                 }
             }
-        });
-
-        Ok(())
+        })
     }))
 }
 
+// merges several streams into one, the message with the earliest (timestamp, stream index) goes first.
+// a message is emitted once no stream can yield an earlier one any more: every stream without a pending message
+// must have its watermark, the least timestamp it may still yield, past the message.
+// the watermark follows the messages of the stream, and is raised by the caller once the stream is idle,
+// so an idle stream only holds back the others until then. a late message of an idle stream may then go after
+// a later one of another stream, so the merged order is not reproducible from kafka, the wal records it instead.
+struct OrderedMerge<T> {
+    queues: Vec<VecDeque<(i64, T)>>,
+    watermarks: Vec<i64>,
+}
+
+impl<T> OrderedMerge<T> {
+    fn new(stream_num: usize) -> Self {
+        Self {
+            queues: (0..stream_num).map(|_| VecDeque::new()).collect(),
+            watermarks: vec![i64::MIN; stream_num],
+        }
+    }
+
+    fn push(&mut self, stream: usize, timestamp: i64, item: T) {
+        self.queues[stream].push_back((timestamp, item));
+        self.advance(stream, timestamp);
+    }
+
+    // the stream yields no message earlier than `watermark` from now on
+    fn advance(&mut self, stream: usize, watermark: i64) {
+        self.watermarks[stream] = self.watermarks[stream].max(watermark);
+    }
+
+    fn is_pending(&self, stream: usize) -> bool {
+        !self.queues[stream].is_empty()
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let next = (0..self.queues.len())
+            .filter_map(|idx| self.queues[idx].front().map(|(timestamp, _)| (*timestamp, idx)))
+            .min()?;
+        if (0..self.queues.len()).any(|idx| !self.is_pending(idx) && (self.watermarks[idx], idx) < next) {
+            return None;
+        }
+        self.queues[next.1].pop_front().map(|(_, item)| item)
+    }
+}
+
+enum StreamError {
+    // the consumer is re-assigned and the stream is consumed again
+    Kafka(KafkaError),
+    // the error policy asks to stop consuming
+    Halt(anyhow::Error),
+    Shutdown,
+}
+
 struct MessageWriter {
     sender: crossbeam_channel::Sender<WrappedMessage>,
-    offset: i64,
+    dlq: DeadLetterQueue,
+    // (topic, partition) of each stream, the index is used as the tie breaker of the merge
+    streams: Vec<(String, i32)>,
+    // the last received offset of each stream
+    offsets: Vec<i64>,
+    merge: OrderedMerge<WrappedMessage>,
+    shutdown: Shutdown,
 }
 
 impl MessageWriter {
-    fn last_offset(&self) -> i64 {
-        self.offset
+    fn new(
+        sender: crossbeam_channel::Sender<WrappedMessage>,
+        streams: Vec<(String, i32)>,
+        offsets: &KafkaOffsets,
+        dlq: DeadLetterQueue,
+        shutdown: Shutdown,
+    ) -> Self {
+        let merge = OrderedMerge::new(streams.len());
+        let offsets = streams
+            .iter()
+            .map(|(topic, partition)| offsets.get(topic, *partition).unwrap_or(-1))
+            .collect();
+        Self {
+            sender,
+            dlq,
+            streams,
+            offsets,
+            merge,
+            shutdown,
+        }
     }
 
-    async fn handle_stream<C, R>(&mut self, mut strm: MessageStream<'_, C, R>) -> KafkaError
-    where
-        C: ConsumerContext + 'static,
-    {
+    fn assignment(&self) -> Vec<(String, i32, Offset)> {
+        self.streams
+            .iter()
+            .zip(self.offsets.iter())
+            .map(|((topic, partition), offset)| {
+                let offset = if *offset < 0 { Offset::Beginning } else { Offset::Offset(*offset) };
+                (topic.clone(), *partition, offset)
+            })
+            .collect()
+    }
+
+    async fn handle_stream(&mut self, consumer: &StreamConsumer) -> StreamError {
+        let mut strm = consumer.stream();
+        let mut last_idle_check = Instant::now();
         loop {
-            match strm.next().await.expect("Kafka's stream has no EOF") {
+            // the stream may keep yielding ready messages, so the shutdown is also checked here
+            if self.shutdown.is_triggered() {
+                return StreamError::Shutdown;
+            }
+            // the other streams may keep yielding messages as well, so it is not only done on timeouts
+            if last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+                last_idle_check = Instant::now();
+                if let Err(e) = tokio::task::block_in_place(|| self.on_idle(consumer)) {
+                    return StreamError::Halt(e);
+                }
+            }
+            let next = match tokio::time::timeout(IDLE_CHECK_INTERVAL, strm.next()).await {
+                Ok(next) => next.expect("Kafka's stream has no EOF"),
+                Err(_) => continue,
+            };
+            match next {
                 Err(KafkaError::NoMessageReceived) => {} //nothing to do yet
                 Err(KafkaError::PartitionEOF(_)) => {}   //simply omit this type of error
                 Err(e) => {
-                    return e;
+                    return StreamError::Kafka(e);
+                }
+                Ok(m) => {
+                    if let Err(e) = self.on_message(&m) {
+                        return StreamError::Halt(e);
+                    }
                 }
-                Ok(m) => self.on_message(&m),
             }
         }
     }
 
-    fn on_message(&mut self, msg: &BorrowedMessage<'_>) {
-        let msg_type = std::str::from_utf8(msg.key().unwrap()).unwrap();
-        let msg_payload = std::str::from_utf8(msg.payload().unwrap()).unwrap();
+    // malformed messages are put into the dlq, an error is returned only when the pipeline should halt
+    fn on_message(&mut self, msg: &BorrowedMessage<'_>) -> anyhow::Result<()> {
+        let stream = match self
+            .streams
+            .iter()
+            .position(|(topic, partition)| topic == msg.topic() && *partition == msg.partition())
+        {
+            Some(stream) => stream,
+            None => {
+                log::warn!("got message from unassigned partition {}/{}", msg.topic(), msg.partition());
+                return Ok(());
+            }
+        };
         let offset: i64 = msg.offset();
-        log::debug!("got message at offset {}", offset);
+        log::debug!("got message at offset {} of {}/{}", offset, msg.topic(), msg.partition());
+        let msg_offset = MsgOffset::new(msg.topic(), msg.partition(), offset);
+        let msg_type = msg.key().map(|key| String::from_utf8_lossy(key).into_owned());
+        let msg_payload = msg.payload().map(|payload| String::from_utf8_lossy(payload).into_owned());
+        let dead_letter = |class, reason| DeadLetter {
+            class,
+            reason,
+            offset: Some(msg_offset.clone()),
+            msg_type: msg_type.clone(),
+            payload: msg_payload.clone().unwrap_or_default(),
+        };
 
-        let last_offset: i64 = self.offset;
+        let last_offset: i64 = self.offsets[stream];
         //tolerance re-winded msg
         if offset <= last_offset {
-            return;
+            return Ok(());
         }
-        if last_offset != 0 && offset != last_offset + 1 {
-            panic!("offset not continuous {} {}", last_offset, offset);
+        if last_offset >= 0 && offset != last_offset + 1 {
+            // when skipped, the missing messages are lost and the consuming goes on from this one
+            self.dlq.reject(dead_letter(
+                ErrorClass::OffsetGap,
+                format!("offset not continuous, last offset {}", last_offset),
+            ))?;
         }
-        self.offset = offset;
+        self.offsets[stream] = offset;
 
-        let message = match msg_type {
-            MSG_TYPE_DEPOSITS => {
-                let data: DepositMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::DEPOSIT((data, offset).into())
-            }
-            MSG_TYPE_ORDERS => {
-                let data: OrderMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::ORDER((data, offset).into())
-            }
-            MSG_TYPE_TRADES => {
-                let data: TradeMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::TRADE((data, offset).into())
-            }
-            MSG_TYPE_USERS => {
-                let data: UserMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::USER((data, offset).into())
+        let message = match parse_kafka_msg(msg.key(), msg.payload(), msg_offset.clone()) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(DecodeError::Malformed(e)) => return self.dlq.reject(dead_letter(ErrorClass::Parse, format!("{:#}", e))),
+            Err(DecodeError::Invalid(e)) => return self.dlq.reject(dead_letter(ErrorClass::Validation, format!("{:#}", e))),
+        };
+
+        // the timestamp is stored in kafka together with the message, so it is the same when re-consumed
+        let timestamp = msg.timestamp().to_millis().unwrap_or(0);
+        self.merge.push(stream, timestamp, message);
+        self.flush()
+    }
+
+    // a stream with no pending message and nothing left in kafka can only yield the messages produced from now on,
+    // so its watermark is raised to the current time. the order emitted this way depends on the local clock, it is
+    // only ever applied once: after restarting, the messages it covers are replayed from the wal in the logged order
+    fn on_idle(&mut self, consumer: &StreamConsumer) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let watermark = (now - WATERMARK_SKEW).as_millis() as i64;
+        for (stream, (topic, partition)) in self.streams.iter().enumerate() {
+            if self.merge.is_pending(stream) {
+                continue;
             }
-            MSG_TYPE_TRANSFERS => {
-                let data: TransferMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::TRANSFER((data, offset).into())
+            match consumer.fetch_watermarks(topic, *partition, WATERMARK_QUERY_TIMEOUT) {
+                Ok((_, high)) if self.offsets[stream] + 1 >= high => self.merge.advance(stream, watermark),
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to fetch the watermarks of {}/{}: {}", topic, partition, e),
             }
-            MSG_TYPE_WITHDRAWS => {
-                let data: WithdrawMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::WITHDRAW((data, offset).into())
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        while let Some(message) = self.merge.pop() {
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    // blocks until the processor catches up, the stream is not polled meanwhile so kafka consuming is throttled.
+    // on shutdown the message is dropped, it is consumed again after restarting since its offset is not recorded
+    fn send(&self, mut message: WrappedMessage) -> anyhow::Result<()> {
+        if self.sender.is_full() {
+            log::warn!("msg queue is full ({}), throttle kafka consuming", self.sender.len());
+        }
+        tokio::task::block_in_place(|| loop {
+            match self.sender.send_timeout(message, SHUTDOWN_POLL_INTERVAL) {
+                Ok(()) => return Ok(()),
+                Err(SendTimeoutError::Timeout(_)) if self.shutdown.is_triggered() => return Ok(()),
+                Err(SendTimeoutError::Timeout(m)) => message = m,
+                Err(SendTimeoutError::Disconnected(_)) => anyhow::bail!("msg queue disconnected"),
             }
-            _ => return,
-        };
+        })
+    }
+}
+
+// returns None for the message types not handled by the rollup
+fn parse_kafka_msg(key: Option<&[u8]>, payload: Option<&[u8]>, offset: MsgOffset) -> Result<Option<WrappedMessage>, DecodeError> {
+    let key = key.ok_or_else(|| DecodeError::Malformed(anyhow::anyhow!("missed key")))?;
+    let msg_type = match std::str::from_utf8(key).map_err(|e| DecodeError::Malformed(e.into()))? {
+        MSG_TYPE_DEPOSITS => "DepositMessage",
+        MSG_TYPE_ORDERS => "OrderMessage",
+        MSG_TYPE_TRADES => "TradeMessage",
+        MSG_TYPE_USERS => "UserMessage",
+        MSG_TYPE_TRANSFERS => "TransferMessage",
+        MSG_TYPE_BATCH_TRANSFERS => "BatchTransferMessage",
+        MSG_TYPE_WITHDRAWS => "WithdrawMessage",
+        _ => return Ok(None),
+    };
+    let payload = payload.ok_or_else(|| DecodeError::Malformed(anyhow::anyhow!("missed payload")))?;
+    let payload = serde_json::from_slice(payload).map_err(|e| DecodeError::Malformed(e.into()))?;
+    let message = Envelope::from_kafka(msg_type, payload)?.decode()?;
+    Ok(Some(message.with_offset(offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordered_merge() {
+        let mut merge = OrderedMerge::new(2);
+        merge.push(0, 10, "a0");
+        merge.push(0, 30, "a1");
+        // waits for the other stream
+        assert_eq!(merge.pop(), None);
+        merge.push(1, 20, "b0");
+        merge.push(1, 30, "b1");
+        assert_eq!(merge.pop(), Some("a0"));
+        assert_eq!(merge.pop(), Some("b0"));
+        // same timestamp, the lower stream index goes first
+        assert_eq!(merge.pop(), Some("a1"));
+        assert_eq!(merge.pop(), None);
+        merge.push(0, 25, "a2");
+        assert_eq!(merge.pop(), Some("a2"));
+        assert_eq!(merge.pop(), None);
+    }
 
-        self.sender.try_send(message).unwrap();
+    #[test]
+    fn test_ordered_merge_idle_stream() {
+        let mut merge = OrderedMerge::new(3);
+        merge.push(0, 10, "a0");
+        merge.push(0, 30, "a1");
+        merge.push(1, 20, "b0");
+        // stream 2 never yields anything
+        assert_eq!(merge.pop(), None);
+        merge.advance(2, 15);
+        assert_eq!(merge.pop(), Some("a0"));
+        assert_eq!(merge.pop(), None);
+        merge.advance(2, 30);
+        assert_eq!(merge.pop(), Some("b0"));
+        // stream 1 may still yield one before 30
+        assert_eq!(merge.pop(), None);
+        // the idle streams have higher indices, so the ties go to stream 0
+        merge.advance(1, 30);
+        assert_eq!(merge.pop(), Some("a1"));
+        assert_eq!(merge.pop(), None);
+        // a watermark never goes backwards
+        merge.advance(2, 0);
+        merge.push(1, 40, "b1");
+        assert_eq!(merge.pop(), None);
+        merge.advance(0, 50);
+        merge.advance(2, 50);
+        assert_eq!(merge.pop(), Some("b1"));
     }
 }
@@ -1,50 +1,114 @@
-use crate::msg::msg_utils::bytes_to_sig;
+use crate::msg::msg_utils::{bytes_to_sig, decode_l2_pubkey};
+use crate::msg::sig_verifier::{VerifiedMessage, VerifiedSig};
 use crate::state::ManagerWrapper;
-use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
+use crate::test_utils::messages::WrappedMessage;
+use crate::test_utils::types::{prec_token_id, try_get_token_id_by_name};
 use crate::types::l2::{self, OrderInput, OrderSide};
 use crate::types::matchengine::messages;
-use fluidex_common::babyjubjub_rs::{self, Point};
-use fluidex_common::ff::Field;
 use fluidex_common::l2::account::{Signature, SignatureBJJ};
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::types::{DecimalExt, FrExt};
 use fluidex_common::Fr;
 use num::Zero;
-use std::convert::TryInto;
+use std::cmp::Ordering;
 use std::time::Instant;
 
-use super::msg_utils::{check_state, exchange_order_to_rollup_order, TokenIdPair, TokenPair};
+use super::msg_utils::{check_state, exchange_order_to_rollup_order, TokenIdPair};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError {
+    // the message is rejected before the state is touched, so it can be skipped
+    #[error("invalid message: {0}")]
+    Invalid(anyhow::Error),
+    // the state is already updated or no longer matches the exchange, the message can not be skipped
+    #[error("state diverged: {0}")]
+    Diverged(anyhow::Error),
+}
+
+macro_rules! ensure_valid {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(ProcessError::Invalid(anyhow::anyhow!($($arg)+)));
+        }
+    };
+}
+
+// rejects a signed tx whose nonce differs from the one of the sender, replaying an applied tx gives `Stale`
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum NonceError {
+    #[error("missed nonce of account {account_id}")]
+    Missing { account_id: u32 },
+    #[error("stale nonce {got} of account {account_id}, expect {expected}")]
+    Stale { account_id: u32, expected: u32, got: u32 },
+    #[error("future nonce {got} of account {account_id}, expect {expected}")]
+    Future { account_id: u32, expected: u32, got: u32 },
+}
+
+fn token_id_of(asset: &str) -> Result<u32, ProcessError> {
+    try_get_token_id_by_name(asset).ok_or_else(|| ProcessError::Invalid(anyhow::anyhow!("unknown token {}", asset)))
+}
 
 pub struct Processor {
     pub enable_check_sig: bool,
+    // the messages generated before nonces were signed carry none, they are only accepted if this is set.
+    // a nonce which is present is always checked, see `Settings::allow_missing_nonce`
+    pub allow_missing_nonce: bool,
     pub balance_tx_total_time: f32,
     pub trade_tx_total_time: f32,
     pub transfer_tx_total_time: f32,
+    // signatures of the message being handled which are verified ahead by `sig_verifier`
+    verified_sigs: Vec<VerifiedSig>,
 }
 
 impl Default for Processor {
     fn default() -> Self {
         Processor {
             enable_check_sig: true,
+            allow_missing_nonce: false,
             balance_tx_total_time: 0.0,
             trade_tx_total_time: 0.0,
             transfer_tx_total_time: 0.0,
+            verified_sigs: Vec::new(),
         }
     }
 }
 
 impl Processor {
-    pub fn handle_user_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::UserMessage>) {
+    pub fn handle_msg(&mut self, manager: &mut ManagerWrapper, msg: WrappedMessage) -> Result<(), ProcessError> {
+        let timestamp = msg.timestamp();
+        match msg {
+            WrappedMessage::DEPOSIT(deposit) => self.handle_deposit_msg(manager, deposit),
+            WrappedMessage::ORDER(order) => self.handle_order_msg(manager, order),
+            WrappedMessage::TRADE(trade) => self.handle_trade_msg(manager, trade),
+            WrappedMessage::TRANSFER(transfer) => self.handle_transfer_msg(manager, transfer),
+            WrappedMessage::BATCH_TRANSFER(batch) => self.handle_batch_transfer_msg(manager, batch),
+            WrappedMessage::USER(user) => self.handle_user_msg(manager, user),
+            WrappedMessage::WITHDRAW(withdraw) => self.handle_withdraw_msg(manager, withdraw),
+        }?;
+        // sealing is driven by the time of the applied messages, so replaying gives the same blocks
+        if let Some(timestamp) = timestamp {
+            manager.advance_time((timestamp * 1000.0) as i64);
+        }
+        Ok(())
+    }
+    // same as handle_msg, except that the signatures verified ahead are not verified again
+    pub fn handle_verified_msg(&mut self, manager: &mut ManagerWrapper, verified: VerifiedMessage) -> Result<(), ProcessError> {
+        self.verified_sigs = verified.sigs;
+        let ret = self.handle_msg(manager, verified.msg);
+        self.verified_sigs.clear();
+        ret
+    }
+    pub fn handle_user_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::UserMessage>,
+    ) -> Result<(), ProcessError> {
         let (user_info, offset) = message.into_parts();
         //println!("handle_user_msg {:#?}", user_info);
         let account_id = user_info.user_id;
-        assert!(!manager.has_account(account_id));
-        let l2_pubkey: String = user_info.l2_pubkey;
-        let l2_pubkey: Vec<u8> = hex::decode(l2_pubkey.trim_start_matches("0x")).unwrap();
-        let bjj_compressed: [u8; 32] = l2_pubkey.try_into().unwrap();
-        let l2_pubkey_point: Point = babyjubjub_rs::decompress_point(bjj_compressed).unwrap();
+        ensure_valid!(!manager.has_account(account_id), "account {} already registered", account_id);
+        let (sign, l2_pubkey_point) = decode_l2_pubkey(&user_info.l2_pubkey).map_err(ProcessError::Invalid)?;
         let eth_addr = Fr::from_str(&user_info.l1_address);
-        let sign = if bjj_compressed[31] & 0x80 != 0x00 { Fr::one() } else { Fr::zero() };
         // TODO: remove '0x' from eth addr?
         manager
             .key_update(
@@ -58,20 +122,31 @@ impl Processor {
                 },
                 offset,
             )
-            .unwrap();
+            .map_err(ProcessError::Diverged)
     }
-    pub fn handle_deposit_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::DepositMessage>) {
+    pub fn handle_deposit_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::DepositMessage>,
+    ) -> Result<(), ProcessError> {
         let (deposit, offset) = message.into_parts();
-        assert!(!deposit.change.is_sign_negative(), "should be a deposit");
+        ensure_valid!(!deposit.change.is_sign_negative(), "should be a deposit");
 
-        let token_id = get_token_id_by_name(&deposit.asset);
+        let token_id = token_id_of(&deposit.asset)?;
         let account_id = deposit.user_id;
 
         let balance_before = deposit.balance - deposit.change;
-        assert!(!balance_before.is_sign_negative(), "invalid balance {:?}", deposit);
+        ensure_valid!(!balance_before.is_sign_negative(), "invalid balance {:?}", deposit);
 
         let expected_balance_before = manager.get_token_balance(deposit.user_id, token_id);
-        assert_eq!(expected_balance_before, balance_before.to_fr(prec_token_id(token_id)));
+        ensure_valid!(
+            expected_balance_before == balance_before.to_fr(prec_token_id(token_id)),
+            "uid {} token {} balance mismatch, local {} remote {}",
+            account_id,
+            token_id,
+            expected_balance_before.to_decimal(prec_token_id(token_id)),
+            balance_before
+        );
 
         let timing = Instant::now();
         let amount = deposit.change.to_u64(prec_token_id(token_id));
@@ -90,38 +165,55 @@ impl Processor {
                 },
                 offset,
             )
-            .unwrap();
+            .map_err(ProcessError::Diverged)?;
 
         self.balance_tx_total_time += timing.elapsed().as_secs_f32();
+        Ok(())
     }
-    pub fn handle_withdraw_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::WithdrawMessage>) {
+    pub fn handle_withdraw_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::WithdrawMessage>,
+    ) -> Result<(), ProcessError> {
         let (withdraw, offset) = message.into_parts();
-        assert!(!withdraw.change.is_sign_positive(), "should be a withdraw");
+        ensure_valid!(!withdraw.change.is_sign_positive(), "should be a withdraw");
 
-        let token_id = get_token_id_by_name(&withdraw.asset);
+        let token_id = token_id_of(&withdraw.asset)?;
         let account_id = withdraw.user_id;
 
         // balance_before = balance_after + withdraw_amount = balance_after - (-withdraw_amount) = balance - change
         let balance_before = withdraw.balance - withdraw.change;
-        assert!(!balance_before.is_sign_negative(), "invalid balance {:?}", withdraw);
+        ensure_valid!(!balance_before.is_sign_negative(), "invalid balance {:?}", withdraw);
 
         let expected_balance_before = manager.get_token_balance(account_id, token_id);
-        assert_eq!(expected_balance_before, balance_before.to_fr(prec_token_id(token_id)));
-
-        let precision = prec_token_id(token_id);
-        let amount = (-withdraw.change).to_u64(precision);
+        ensure_valid!(
+            expected_balance_before == balance_before.to_fr(prec_token_id(token_id)),
+            "uid {} token {} balance mismatch, local {} remote {}",
+            account_id,
+            token_id,
+            expected_balance_before.to_decimal(prec_token_id(token_id)),
+            balance_before
+        );
 
         let timing = Instant::now();
-        let raw_sig = bytes_to_sig(withdraw.signature);
-        let mut withdraw_tx = l2::WithdrawTx::new(account_id, token_id, amount as u128, balance_before.to_fr(precision));
-        withdraw_tx.sig = Signature::from_raw(withdraw_tx.hash(), &raw_sig);
+        let raw_sig = bytes_to_sig(withdraw.signature).map_err(ProcessError::Invalid)?;
+        self.check_nonce(manager, account_id, withdraw.nonce)?;
+        let mut withdraw_tx = withdraw_tx_of(&withdraw, token_id);
+        let hash = withdraw_tx.hash();
+        withdraw_tx.sig = Signature::from_raw(hash, &raw_sig);
         if self.enable_check_sig {
-            check_withdraw_sig(manager, &withdraw_tx, &raw_sig);
+            self.check_sig(manager, account_id, &hash, &raw_sig)
+                .map_err(|e| ProcessError::Invalid(e.context(format!("invalid sig for withdraw {:?}", withdraw_tx))))?;
         }
         manager.withdraw(withdraw_tx, offset);
         self.transfer_tx_total_time += timing.elapsed().as_secs_f32();
+        Ok(())
     }
-    pub fn handle_order_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::OrderMessage>) {
+    pub fn handle_order_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::OrderMessage>,
+    ) -> Result<(), ProcessError> {
         let (order, _) = message.into_parts();
         match order.event {
             messages::OrderEventType::FINISH => {
@@ -144,23 +236,36 @@ impl Processor {
                 log::debug!("skip order msg {:?}", order.event);
             }
         }
+        Ok(())
     }
-    pub fn handle_trade_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::TradeMessage>) {
+    pub fn handle_trade_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::TradeMessage>,
+    ) -> Result<(), ProcessError> {
         let (trade, offset) = message.into_parts();
         //log::debug!("handle_trade_msg {:#?}", trade);
+        let id_pair = TokenIdPair::try_from_market(&trade.market).map_err(ProcessError::Invalid)?;
         if let Some(state_before) = &trade.state_before {
-            check_state(manager, state_before, &trade);
+            check_state(manager, state_before, &trade).map_err(ProcessError::Invalid)?;
         }
 
         let timing = Instant::now();
         let mut taker_order: Option<l2::Order> = None;
         let mut maker_order: Option<l2::Order> = None;
         if let Some(ask_order_origin) = &trade.ask_order {
-            let ask_order_input = exchange_order_to_rollup_order(ask_order_origin);
+            let ask_order_input = exchange_order_to_rollup_order(ask_order_origin).map_err(ProcessError::Invalid)?;
             if self.enable_check_sig {
-                self.check_order_sig(manager, &ask_order_input);
+                self.check_order_sig(manager, &ask_order_input)?;
             }
-            assert!(!manager.has_order(ask_order_input.account_id, ask_order_input.order_id));
+            ensure_valid!(
+                !manager.has_order(ask_order_input.account_id, ask_order_input.order_id),
+                "order {} already exists",
+                ask_order_input.order_id
+            );
+            manager
+                .check_order_slot(ask_order_input.account_id, ask_order_input.order_id)
+                .map_err(|e| ProcessError::Invalid(e.into()))?;
             let ask_order = l2::Order::from(ask_order_input);
             match trade.ask_role {
                 messages::MarketRole::MAKER => {
@@ -172,11 +277,18 @@ impl Processor {
             };
         }
         if let Some(bid_order_origin) = &trade.bid_order {
-            let bid_order_input = exchange_order_to_rollup_order(bid_order_origin);
+            let bid_order_input = exchange_order_to_rollup_order(bid_order_origin).map_err(ProcessError::Invalid)?;
             if self.enable_check_sig {
-                self.check_order_sig(manager, &bid_order_input);
+                self.check_order_sig(manager, &bid_order_input)?;
             }
-            assert!(!manager.has_order(bid_order_input.account_id, bid_order_input.order_id));
+            ensure_valid!(
+                !manager.has_order(bid_order_input.account_id, bid_order_input.order_id),
+                "order {} already exists",
+                bid_order_input.order_id
+            );
+            manager
+                .check_order_slot(bid_order_input.account_id, bid_order_input.order_id)
+                .map_err(|e| ProcessError::Invalid(e.into()))?;
             let bid_order = l2::Order::from(bid_order_input);
             match trade.bid_role {
                 messages::MarketRole::MAKER => {
@@ -188,41 +300,109 @@ impl Processor {
             };
         }
         let tx = l2::FullSpotTradeTx {
-            trade: self.trade_into_spot_tx(&trade),
+            trade: self.trade_into_spot_tx(&trade, id_pair),
             taker_order,
             maker_order,
         };
         manager.full_spot_trade(tx, offset);
         self.trade_tx_total_time += timing.elapsed().as_secs_f32();
         if let Some(state_after) = &trade.state_after {
-            check_state(manager, state_after, &trade);
+            check_state(manager, state_after, &trade).map_err(ProcessError::Diverged)?;
         }
+        Ok(())
     }
-    pub fn handle_transfer_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::TransferMessage>) {
+    pub fn handle_transfer_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::TransferMessage>,
+    ) -> Result<(), ProcessError> {
         let (transfer, offset) = message.into_parts();
         let amount = transfer.amount;
-        assert!(!amount.is_sign_negative(), "Transfer amount must not be negative");
+        ensure_valid!(!amount.is_sign_negative(), "Transfer amount must not be negative");
 
-        let token_id = get_token_id_by_name(&transfer.asset);
+        let token_id = token_id_of(&transfer.asset)?;
         let from = transfer.user_from;
+        ensure_valid!(manager.has_account(from), "invalid account {}", from);
         let from_balance = manager.get_token_balance(from, token_id).to_decimal(prec_token_id(token_id));
-        assert!(from_balance >= amount, "From user must have sufficient balance");
-
-        let to = transfer.user_to;
+        ensure_valid!(from_balance >= amount, "From user must have sufficient balance");
 
         let timing = Instant::now();
-        let raw_sig = bytes_to_sig(transfer.signature);
-        let mut transfer_tx = l2::TransferTx::new(from, to, token_id, amount.to_u64(prec_token_id(token_id)) as u128);
-        transfer_tx.sig = Signature::from_raw(transfer_tx.hash(), &raw_sig);
+        let raw_sig = bytes_to_sig(transfer.signature).map_err(ProcessError::Invalid)?;
+        self.check_nonce(manager, from, transfer.nonce)?;
+        let mut transfer_tx = transfer_tx_of(&transfer, token_id);
+        let hash = transfer_tx.hash();
+        transfer_tx.sig = Signature::from_raw(hash, &raw_sig);
         if self.enable_check_sig {
-            check_transfer_sig(manager, &transfer_tx, &raw_sig);
+            self.check_sig(manager, from, &hash, &raw_sig)
+                .map_err(|e| ProcessError::Invalid(e.context(format!("invalid sig for transfer {:?}", transfer_tx))))?;
         }
         manager.transfer(transfer_tx, offset);
         self.transfer_tx_total_time += timing.elapsed().as_secs_f32();
+        Ok(())
     }
-    fn trade_into_spot_tx(&self, trade: &messages::TradeMessage) -> l2::SpotTradeTx {
+    // all the legs are checked before the first one is applied, a single bad leg rejects the whole batch
+    pub fn handle_batch_transfer_msg(
+        &mut self,
+        manager: &mut ManagerWrapper,
+        message: messages::Message<messages::BatchTransferMessage>,
+    ) -> Result<(), ProcessError> {
+        let (batch, offset) = message.into_parts();
+        ensure_valid!(!batch.legs.is_empty(), "Batch transfer must have legs");
+
+        let token_id = token_id_of(&batch.asset)?;
+        let precision = prec_token_id(token_id);
+        let from = batch.user_from;
+        ensure_valid!(manager.has_account(from), "invalid account {}", from);
+        let mut total = Decimal::zero();
+        for leg in &batch.legs {
+            ensure_valid!(!leg.amount.is_sign_negative(), "Transfer amount must not be negative");
+            ensure_valid!(
+                leg.user_to != from && manager.has_account(leg.user_to),
+                "invalid batch transfer target {}",
+                leg.user_to
+            );
+            total = total
+                .checked_add(leg.amount)
+                .ok_or_else(|| ProcessError::Invalid(anyhow::anyhow!("batch transfer amount overflows")))?;
+        }
+        let from_balance = manager.get_token_balance(from, token_id).to_decimal(precision);
+        ensure_valid!(
+            from_balance >= total,
+            "From user must have sufficient balance for the whole batch, {} < {}",
+            from_balance,
+            total
+        );
+
+        let timing = Instant::now();
+        let raw_sig = bytes_to_sig(batch.signature).map_err(ProcessError::Invalid)?;
+        self.check_nonce(manager, from, batch.nonce)?;
+        let mut batch_tx = batch_transfer_tx_of(&batch, token_id);
+        let hash = batch_tx.hash();
+        batch_tx.sig = Signature::from_raw(hash, &raw_sig);
+        if self.enable_check_sig {
+            self.check_sig(manager, from, &hash, &raw_sig)
+                .map_err(|e| ProcessError::Invalid(e.context(format!("invalid sig for batch transfer {:?}", batch_tx))))?;
+        }
+        manager.batch_transfer(batch_tx, offset).map_err(ProcessError::Invalid)?;
+        self.transfer_tx_total_time += timing.elapsed().as_secs_f32();
+        Ok(())
+    }
+    fn check_nonce(&self, manager: &ManagerWrapper, account_id: u32, nonce: Option<u32>) -> Result<(), ProcessError> {
+        let got = match nonce {
+            Some(nonce) => nonce,
+            None if self.allow_missing_nonce => return Ok(()),
+            None => return Err(ProcessError::Invalid(NonceError::Missing { account_id }.into())),
+        };
+        let expected = manager.get_account_nonce(account_id).to_u32();
+        let err = match got.cmp(&expected) {
+            Ordering::Equal => return Ok(()),
+            Ordering::Less => NonceError::Stale { account_id, expected, got },
+            Ordering::Greater => NonceError::Future { account_id, expected, got },
+        };
+        Err(ProcessError::Invalid(err.into()))
+    }
+    fn trade_into_spot_tx(&self, trade: &messages::TradeMessage, id_pair: TokenIdPair) -> l2::SpotTradeTx {
         //allow information can be obtained from trade
-        let id_pair = TokenIdPair::from(TokenPair::from(trade.market.as_str()));
 
         match trade.ask_role {
             messages::MarketRole::MAKER => l2::SpotTradeTx {
@@ -247,12 +427,13 @@ impl Processor {
             },
         }
     }
-    fn parse_order_from_msg(order_msg: &messages::OrderMessage) -> OrderInput {
+    fn parse_order_from_msg(order_msg: &messages::OrderMessage) -> Result<OrderInput, ProcessError> {
         let order: &messages::Order = &order_msg.order;
-        let base_token_id = get_token_id_by_name(&order_msg.base);
-        let quote_token_id = get_token_id_by_name(&order_msg.quote);
+        let base_token_id = token_id_of(&order_msg.base)?;
+        let quote_token_id = token_id_of(&order_msg.quote)?;
         let base_amount = order.amount;
-        assert_ne!(order.price, Decimal::zero());
+        ensure_valid!(!order.price.is_zero(), "order {} has a zero price", order.id);
+        let sig = bytes_to_sig(order.signature).map_err(ProcessError::Invalid)?;
         let quote_amount = order.amount * order.price;
         let is_ask = matches!(order.side, messages::OrderSide::ASK);
         let (tokensell, tokenbuy) = if is_ask {
@@ -266,23 +447,33 @@ impl Processor {
             (quote_amount, base_amount)
         };
 
-        OrderInput {
+        Ok(OrderInput {
             order_id: order.id as u32,
             token_sell: Fr::from_u32(tokensell),
             token_buy: Fr::from_u32(tokenbuy),
             total_sell: total_sell.to_fr(prec_token_id(tokensell)),
             total_buy: total_buy.to_fr(prec_token_id(tokenbuy)),
-            sig: Some(bytes_to_sig(order.signature)),
+            sig: Some(sig),
             account_id: order.user,
             side: if is_ask { OrderSide::Sell } else { OrderSide::Buy },
-        }
+        })
     }
-    fn check_order_sig(&mut self, manager: &ManagerWrapper, order_to_put: &OrderInput) {
+    fn check_order_sig(&mut self, manager: &ManagerWrapper, order_to_put: &OrderInput) -> Result<(), ProcessError> {
         let msg = order_to_put.hash();
         let sig = order_to_put.sig.clone().unwrap();
-        manager
-            .check_sig(order_to_put.account_id, &msg, &sig)
-            .unwrap_or_else(|_| panic!("invalid sig for order {:?}", order_to_put));
+        self.check_sig(manager, order_to_put.account_id, &msg, &sig)
+            .map_err(|e| ProcessError::Invalid(e.context(format!("invalid sig for order {:?}", order_to_put))))
+    }
+    // a sig verified ahead only counts if the account still has the key it was verified with
+    fn check_sig(&self, manager: &ManagerWrapper, account_id: u32, msg: &Fr, sig: &SignatureBJJ) -> anyhow::Result<()> {
+        let verified = self
+            .verified_sigs
+            .iter()
+            .any(|v| v.account_id == account_id && v.hash == *msg && manager.has_l2_key(account_id, &v.sign, &v.ay));
+        if verified {
+            return Ok(());
+        }
+        manager.check_sig(account_id, msg, sig)
     }
 
     pub fn take_bench(&mut self) -> (f32, f32) {
@@ -293,16 +484,151 @@ impl Processor {
     }
 }
 
-fn check_transfer_sig(manager: &ManagerWrapper, transfer: &l2::TransferTx, sig: &SignatureBJJ) {
-    let msg = transfer.hash();
-    manager
-        .check_sig(transfer.from, &msg, sig)
-        .unwrap_or_else(|_| panic!("invalid sig for transfer {:?}", transfer));
+// the txs are built from the messages alone, so the signed hashes can be computed ahead of the processor.
+// messages without a nonce are the older ones signed with nonce 0, they are only accepted with `allow_missing_nonce`
+fn transfer_tx_of(transfer: &messages::TransferMessage, token_id: u32) -> l2::TransferTx {
+    let amount = transfer.amount.to_u64(prec_token_id(token_id)) as u128;
+    let mut tx = l2::TransferTx::new(transfer.user_from, transfer.user_to, token_id, amount);
+    tx.from_nonce = Fr::from_u32(transfer.nonce.unwrap_or(0));
+    tx
+}
+
+fn withdraw_tx_of(withdraw: &messages::WithdrawMessage, token_id: u32) -> l2::WithdrawTx {
+    let precision = prec_token_id(token_id);
+    let amount = (-withdraw.change).to_u64(precision) as u128;
+    let balance_before = withdraw.balance - withdraw.change;
+    let mut tx = l2::WithdrawTx::new(withdraw.user_id, token_id, amount, balance_before.to_fr(precision));
+    tx.nonce = Fr::from_u32(withdraw.nonce.unwrap_or(0));
+    tx
+}
+
+fn batch_transfer_tx_of(batch: &messages::BatchTransferMessage, token_id: u32) -> l2::BatchTransferTx {
+    let precision = prec_token_id(token_id);
+    let legs = batch
+        .legs
+        .iter()
+        .map(|leg| (leg.user_to, leg.amount.to_u64(precision) as u128))
+        .collect();
+    let mut tx = l2::BatchTransferTx::new(batch.user_from, token_id, legs);
+    tx.from_nonce = Fr::from_u32(batch.nonce.unwrap_or(0));
+    tx
 }
 
-fn check_withdraw_sig(manager: &ManagerWrapper, withdraw: &l2::WithdrawTx, sig: &SignatureBJJ) {
-    let msg = withdraw.hash();
-    manager
-        .check_sig(withdraw.account_id, &msg, sig)
-        .unwrap_or_else(|_| panic!("invalid sig for withdraw {:?}", withdraw));
+// (signer, signed hash, sig) of every signature carried by the message, the undecodable ones are left out
+pub fn signed_hashes(msg: &WrappedMessage) -> Vec<(u32, Fr, SignatureBJJ)> {
+    let mut ret = Vec::new();
+    match msg {
+        WrappedMessage::TRANSFER(transfer) => {
+            if let (Some(token_id), Ok(sig)) = (try_get_token_id_by_name(&transfer.asset), bytes_to_sig(transfer.signature)) {
+                ret.push((transfer.user_from, transfer_tx_of(transfer, token_id).hash(), sig));
+            }
+        }
+        WrappedMessage::BATCH_TRANSFER(batch) => {
+            if let (Some(token_id), Ok(sig)) = (try_get_token_id_by_name(&batch.asset), bytes_to_sig(batch.signature)) {
+                ret.push((batch.user_from, batch_transfer_tx_of(batch, token_id).hash(), sig));
+            }
+        }
+        WrappedMessage::WITHDRAW(withdraw) => {
+            if let (Some(token_id), Ok(sig)) = (try_get_token_id_by_name(&withdraw.asset), bytes_to_sig(withdraw.signature)) {
+                ret.push((withdraw.user_id, withdraw_tx_of(withdraw, token_id).hash(), sig));
+            }
+        }
+        WrappedMessage::TRADE(trade) => {
+            for order in trade.ask_order.iter().chain(trade.bid_order.iter()) {
+                if let Ok(order) = exchange_order_to_rollup_order(order) {
+                    let sig = order.sig.clone().unwrap();
+                    ret.push((order.account_id, order.hash(), sig));
+                }
+            }
+        }
+        _ => {}
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::state::GlobalState;
+    use crate::test_utils::types::get_mnemonic_by_account_id;
+    use fluidex_common::ff::Field;
+    use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+
+    fn signed_transfer(acc: &Account, nonce: u32) -> messages::Message<messages::TransferMessage> {
+        let mut tx = l2::TransferTx::new(1, 2, 1, 1_000_000);
+        tx.from_nonce = Fr::from_u32(nonce);
+        messages::TransferMessage {
+            time: 0.0,
+            user_from: 1,
+            user_to: 2,
+            asset: "USDT".to_string(),
+            amount: Decimal::from_str("1").unwrap(),
+            nonce: Some(nonce),
+            signature: acc.sign_hash_raw(tx.hash()).unwrap().compress(),
+        }
+        .into()
+    }
+
+    fn nonce_error(result: Result<(), ProcessError>) -> NonceError {
+        match result {
+            Err(ProcessError::Invalid(e)) => e.downcast().expect("should be a nonce error"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_signed_transfer() {
+        let acc = Account::from_mnemonic(1, &get_mnemonic_by_account_id(1)).unwrap();
+        let mut state = GlobalState::new(3, 4, 4, false);
+        for _ in 0..3 {
+            state.create_new_account(1).unwrap();
+        }
+        state.set_account_l2_addr(1, acc.sign(), acc.ay());
+        state.set_token_balance(1, 1, Fr::from_u32(10_000_000));
+        let mut manager = ManagerWrapper::new(Arc::new(RwLock::new(state)), 16, None, false);
+        #[cfg(feature = "persist_sled")]
+        manager.set_persist_enabled(false);
+        let mut processor = Processor::default();
+
+        let transfer = signed_transfer(&acc, 0);
+        processor.handle_transfer_msg(&mut manager, transfer.clone()).unwrap();
+        assert_eq!(manager.get_account_nonce(1), Fr::one());
+
+        let root = manager.root();
+        let err = nonce_error(processor.handle_transfer_msg(&mut manager, transfer));
+        assert_eq!(
+            err,
+            NonceError::Stale {
+                account_id: 1,
+                expected: 1,
+                got: 0
+            }
+        );
+        let err = nonce_error(processor.handle_transfer_msg(&mut manager, signed_transfer(&acc, 2)));
+        assert!(matches!(err, NonceError::Future { expected: 1, got: 2, .. }));
+        let mut unsigned = signed_transfer(&acc, 1);
+        unsigned.nonce = None;
+        let err = nonce_error(processor.handle_transfer_msg(&mut manager, unsigned));
+        assert_eq!(err, NonceError::Missing { account_id: 1 });
+        // accepting the older messages without a nonce does not let a signed one be replayed
+        processor.allow_missing_nonce = true;
+        let err = nonce_error(processor.handle_transfer_msg(&mut manager, signed_transfer(&acc, 0)));
+        assert!(matches!(err, NonceError::Stale { expected: 1, got: 0, .. }));
+        processor.allow_missing_nonce = false;
+        assert_eq!(manager.root(), root);
+
+        processor.handle_transfer_msg(&mut manager, signed_transfer(&acc, 1)).unwrap();
+        assert_eq!(manager.get_account_nonce(1), Fr::from_u32(2));
+
+        // an unregistered sender is rejected even if nothing is transferred
+        let mut unknown = signed_transfer(&acc, 0);
+        unknown.user_from = 5;
+        unknown.amount = Decimal::zero();
+        assert!(matches!(
+            processor.handle_transfer_msg(&mut manager, unknown),
+            Err(ProcessError::Invalid(_))
+        ));
+    }
 }
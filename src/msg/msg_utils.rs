@@ -1,9 +1,11 @@
 #![allow(clippy::let_and_return)]
 use crate::state::ManagerWrapper;
-use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
+use crate::test_utils::types::{get_token_id_by_name, prec_token_id, try_get_token_id_by_name};
 use crate::types::l2::{self, OrderSide};
 use crate::types::matchengine::{self, messages};
-use fluidex_common::babyjubjub_rs;
+use anyhow::{anyhow, bail, ensure, Result};
+use fluidex_common::babyjubjub_rs::{self, Point};
+use fluidex_common::ff::Field;
 use fluidex_common::l2::account::SignatureBJJ;
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::types::{DecimalExt, FrExt};
@@ -31,6 +33,21 @@ impl<'c> From<TokenPair<'c>> for TokenIdPair {
     }
 }
 
+impl TokenIdPair {
+    // a market is named as `{base}_{quote}`
+    pub fn try_from_market(market: &str) -> Result<Self> {
+        let mut assets = market.split('_');
+        match (assets.next(), assets.next(), assets.next()) {
+            (Some(base), Some(quote), None) => {
+                let base_id = try_get_token_id_by_name(base).ok_or_else(|| anyhow!("unknown token {}", base))?;
+                let quote_id = try_get_token_id_by_name(quote).ok_or_else(|| anyhow!("unknown token {}", quote))?;
+                Ok(TokenIdPair(base_id, quote_id))
+            }
+            _ => bail!("invalid market {}", market),
+        }
+    }
+}
+
 impl From<String> for TokenIdPair {
     fn from(origin: String) -> Self {
         let mut assets = origin.split('_');
@@ -49,21 +66,31 @@ pub fn string_to_sig(signature: String) -> SignatureBJJ {
     babyjubjub_rs::decompress_signature(&sig_packed_vec.try_into().unwrap()).unwrap()
 }
 
-pub fn bytes_to_sig(signature: [u8; 64]) -> SignatureBJJ {
+pub fn bytes_to_sig(signature: [u8; 64]) -> Result<SignatureBJJ> {
     if signature == [0; 64] {
-        panic!("empty signature");
+        bail!("empty signature");
     }
     //println!("SignatureBJJ {:?}", signature);
-    babyjubjub_rs::decompress_signature(&signature).unwrap()
+    babyjubjub_rs::decompress_signature(&signature).map_err(|e| anyhow!("invalid signature: {}", e))
+}
+
+// the compressed l2 pubkey in hex str repr, returns the sign and the point
+pub fn decode_l2_pubkey(l2_pubkey: &str) -> Result<(Fr, Point)> {
+    let l2_pubkey = hex::decode(l2_pubkey.trim_start_matches("0x"))?;
+    let bjj_compressed: [u8; 32] = l2_pubkey.try_into().map_err(|_| anyhow!("l2 pubkey should be 32 bytes"))?;
+    let point = babyjubjub_rs::decompress_point(bjj_compressed).map_err(|e| anyhow!("invalid l2 pubkey: {}", e))?;
+    let sign = if bjj_compressed[31] & 0x80 != 0x00 { Fr::one() } else { Fr::zero() };
+    Ok((sign, point))
 }
 
-pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) -> l2::OrderInput {
-    assert!(origin.finished_base.is_zero());
-    assert!(origin.finished_quote.is_zero());
-    let TokenIdPair(base_token_id, quote_token_id) = origin.market.clone().into();
+pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) -> Result<l2::OrderInput> {
+    ensure!(origin.finished_base.is_zero(), "order {} is partially filled", origin.id);
+    ensure!(origin.finished_quote.is_zero(), "order {} is partially filled", origin.id);
+    let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::try_from_market(&origin.market)?;
     let base_prec = prec_token_id(base_token_id);
     let quote_prec = prec_token_id(quote_token_id);
-    match origin.side {
+    let sig = Some(bytes_to_sig(origin.signature)?);
+    let order = match origin.side {
         matchengine::messages::OrderSide::ASK => {
             l2::OrderInput {
                 order_id: origin.id as u32,
@@ -73,7 +100,7 @@ pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) ->
                 //filled_buy: fixnum::decimal_to_fr(&origin.finished_quote, quote_token_id),
                 total_sell: origin.amount.to_fr(base_prec),
                 total_buy: (origin.amount * origin.price).to_fr(quote_prec),
-                sig: Some(bytes_to_sig(origin.signature)),
+                sig,
                 account_id: origin.user,
                 side: OrderSide::Sell,
             }
@@ -87,27 +114,30 @@ pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) ->
                 //filled_buy: fixnum::decimal_to_fr(&origin.finished_base, base_token_id),
                 total_sell: (origin.amount * origin.price).to_fr(quote_prec),
                 total_buy: origin.amount.to_fr(base_prec),
-                sig: Some(bytes_to_sig(origin.signature)),
+                sig,
                 account_id: origin.user,
                 side: OrderSide::Buy,
             }
         }
-    }
+    };
+    Ok(order)
 }
-pub fn check_state(manager: &ManagerWrapper, state: &messages::VerboseTradeState, trade: &messages::TradeMessage) {
-    let token_pair = TokenPair::from(trade.market.as_str());
-    let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::from(token_pair);
+pub fn check_state(manager: &ManagerWrapper, state: &messages::VerboseTradeState, trade: &messages::TradeMessage) -> Result<()> {
+    let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::try_from_market(&trade.market)?;
     for balance_state in &state.balance_states {
         // assert_balance_state(&state.balance, manager, trade.bid_user_id, trade.ask_user_id, id_pair);
         let balance_remote = balance_state.balance;
-        let token_id = get_token_id_by_name(&balance_state.asset);
+        let token_id = try_get_token_id_by_name(&balance_state.asset).ok_or_else(|| anyhow!("unknown token {}", balance_state.asset))?;
         let balance_local = manager
             .get_token_balance(balance_state.user_id, token_id)
             .to_decimal(prec_token_id(token_id));
-        assert_eq!(
-            balance_remote, balance_local,
+        ensure!(
+            balance_remote == balance_local,
             "uid {} token {} remote balance {} local balance {}",
-            balance_state.user_id, token_id, balance_remote, balance_local
+            balance_state.user_id,
+            token_id,
+            balance_remote,
+            balance_local
         );
     }
     for order_state in &state.order_states {
@@ -121,23 +151,43 @@ pub fn check_state(manager: &ManagerWrapper, state: &messages::VerboseTradeState
                     let remote_filled_sell = order_state.finished_quote;
                     let local_filled_buy = order_local.filled_buy.to_decimal(prec_token_id(base_token_id));
                     let local_filled_sell = order_local.filled_sell.to_decimal(prec_token_id(quote_token_id));
-                    assert_eq!(remote_filled_buy, local_filled_buy);
-                    assert_eq!(remote_filled_sell, local_filled_sell);
+                    ensure!(
+                        remote_filled_buy == local_filled_buy && remote_filled_sell == local_filled_sell,
+                        "uid {} order {} remote filled ({}, {}) local filled ({}, {})",
+                        account_id,
+                        order_id,
+                        remote_filled_buy,
+                        remote_filled_sell,
+                        local_filled_buy,
+                        local_filled_sell
+                    );
                 }
                 messages::OrderSide::ASK => {
                     let remote_filled_buy = order_state.finished_quote;
                     let remote_filled_sell = order_state.finished_base;
                     let local_filled_buy = order_local.filled_buy.to_decimal(prec_token_id(quote_token_id));
                     let local_filled_sell = order_local.filled_sell.to_decimal(prec_token_id(base_token_id));
-                    assert_eq!(remote_filled_buy, local_filled_buy);
-                    assert_eq!(remote_filled_sell, local_filled_sell);
+                    ensure!(
+                        remote_filled_buy == local_filled_buy && remote_filled_sell == local_filled_sell,
+                        "uid {} order {} remote filled ({}, {}) local filled ({}, {})",
+                        account_id,
+                        order_id,
+                        remote_filled_buy,
+                        remote_filled_sell,
+                        local_filled_buy,
+                        local_filled_sell
+                    );
                 }
             }
         } else {
             // the only possible path reaching here, is that the order is a new order in 'state_before'
             // so it is unknown for manager
-            assert_eq!(order_state.finished_base, Decimal::zero(), "{:?}", order_state);
-            assert_eq!(order_state.finished_quote, Decimal::zero(), "{:?}", order_state);
+            ensure!(
+                order_state.finished_base == Decimal::zero() && order_state.finished_quote == Decimal::zero(),
+                "unknown order is filled {:?}",
+                order_state
+            );
         }
     }
+    Ok(())
 }
@@ -0,0 +1,244 @@
+use super::msg_processor::signed_hashes;
+use super::msg_utils::decode_l2_pubkey;
+use crate::state::{GlobalState, PubkeyCache};
+use crate::test_utils::messages::WrappedMessage;
+use crossbeam_channel::{Receiver, Sender};
+use fluidex_common::babyjubjub_rs::Point;
+use fluidex_common::ff::Field;
+use fluidex_common::l2::account::L2Account;
+use fluidex_common::Fr;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+
+// a signature verified ahead of the processor, against the key the account is expected to have
+// when the message is applied. the processor still compares it with the key in the state.
+#[derive(Debug, Clone)]
+pub struct VerifiedSig {
+    pub account_id: u32,
+    pub hash: Fr,
+    pub sign: Fr,
+    pub ay: Fr,
+}
+
+// only the valid signatures are listed, the invalid ones are verified again and rejected by the processor
+#[derive(Debug)]
+pub struct VerifiedMessage {
+    pub msg: WrappedMessage,
+    pub sigs: Vec<VerifiedSig>,
+}
+
+impl From<WrappedMessage> for VerifiedMessage {
+    fn from(msg: WrappedMessage) -> Self {
+        Self { msg, sigs: Vec::new() }
+    }
+}
+
+#[derive(Clone)]
+struct ExpectedKey {
+    sign: Fr,
+    ay: Fr,
+    point: Point,
+}
+
+struct Job {
+    seq: u64,
+    msg: WrappedMessage,
+    keys: HashMap<u32, ExpectedKey>,
+}
+
+// verifies the signatures of the messages on `workers` threads, the messages are sent out in the order they come in.
+// the keys are resolved in that order too: from the state, or from the user messages not applied yet.
+pub fn spawn(
+    input: Receiver<WrappedMessage>,
+    output: Sender<VerifiedMessage>,
+    state: Arc<RwLock<GlobalState>>,
+    workers: usize,
+) -> JoinHandle<()> {
+    let workers = workers.max(1);
+    let (job_sender, job_receiver) = crossbeam_channel::bounded::<Job>(workers * 2);
+    let (result_sender, result_receiver) = crossbeam_channel::bounded::<(u64, VerifiedMessage)>(workers * 2);
+
+    // every thread exits once its upstream is drained or its downstream is dropped
+    std::thread::spawn(move || dispatch(input, job_sender, state));
+    for _ in 0..workers {
+        let job_receiver = job_receiver.clone();
+        let result_sender = result_sender.clone();
+        std::thread::spawn(move || verify(job_receiver, result_sender));
+    }
+    drop(result_sender);
+
+    std::thread::spawn(move || {
+        let mut pending = BTreeMap::new();
+        let mut next_seq = 0;
+        for (seq, verified) in result_receiver.iter() {
+            pending.insert(seq, verified);
+            while let Some(verified) = pending.remove(&next_seq) {
+                if output.send(verified).is_err() {
+                    return;
+                }
+                next_seq += 1;
+            }
+        }
+    })
+}
+
+fn dispatch(input: Receiver<WrappedMessage>, jobs: Sender<Job>, state: Arc<RwLock<GlobalState>>) {
+    let mut cache = PubkeyCache::default();
+    // keys of the user messages dispatched, which may not be in the state yet
+    let mut registered: HashMap<u32, ExpectedKey> = HashMap::new();
+    for (seq, msg) in input.iter().enumerate() {
+        let mut keys = HashMap::new();
+        for account_id in signers(&msg) {
+            if keys.contains_key(&account_id) {
+                continue;
+            }
+            let acc = state.read().unwrap().get_account(account_id.into());
+            let key = if !acc.ay.is_zero() {
+                registered.remove(&account_id);
+                cache.get(account_id, acc.sign, acc.ay).ok().map(|point| ExpectedKey {
+                    sign: acc.sign,
+                    ay: acc.ay,
+                    point,
+                })
+            } else {
+                registered.get(&account_id).cloned()
+            };
+            if let Some(key) = key {
+                keys.insert(account_id, key);
+            }
+        }
+        if let WrappedMessage::USER(user) = &msg {
+            if let Ok((sign, point)) = decode_l2_pubkey(&user.l2_pubkey) {
+                registered.entry(user.user_id).or_insert(ExpectedKey { sign, ay: point.y, point });
+            }
+        }
+        let job = Job {
+            seq: seq as u64,
+            msg,
+            keys,
+        };
+        if jobs.send(job).is_err() {
+            break;
+        }
+    }
+}
+
+fn verify(jobs: Receiver<Job>, results: Sender<(u64, VerifiedMessage)>) {
+    for job in jobs.iter() {
+        let sigs = signed_hashes(&job.msg)
+            .into_iter()
+            .filter_map(|(account_id, hash, sig)| {
+                let key = job.keys.get(&account_id)?;
+                L2Account::verify_raw_using_pubkey(hash, sig, key.point.clone()).then(|| VerifiedSig {
+                    account_id,
+                    hash,
+                    sign: key.sign,
+                    ay: key.ay,
+                })
+            })
+            .collect();
+        if results.send((job.seq, VerifiedMessage { msg: job.msg, sigs })).is_err() {
+            break;
+        }
+    }
+}
+
+fn signers(msg: &WrappedMessage) -> Vec<u32> {
+    match msg {
+        WrappedMessage::TRANSFER(transfer) => vec![transfer.user_from],
+        WrappedMessage::BATCH_TRANSFER(batch) => vec![batch.user_from],
+        WrappedMessage::WITHDRAW(withdraw) => vec![withdraw.user_id],
+        WrappedMessage::TRADE(trade) => trade
+            .ask_order
+            .iter()
+            .chain(trade.bid_order.iter())
+            .map(|order| order.user)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::test_utils::types::get_mnemonic_by_account_id;
+    use crate::types::l2::TransferTx;
+    use crate::types::matchengine::messages::{TransferMessage, UserMessage};
+    use fluidex_common::rust_decimal::Decimal;
+    use fluidex_common::types::FrExt;
+
+    fn transfer(signer: &Account, nonce: u32) -> WrappedMessage {
+        let mut tx = TransferTx::new(1, 2, 1, 1_000_000);
+        tx.from_nonce = Fr::from_u32(nonce);
+        WrappedMessage::TRANSFER(
+            TransferMessage {
+                time: 0.0,
+                user_from: 1,
+                user_to: 2,
+                asset: "USDT".to_string(),
+                amount: Decimal::new(1, 0),
+                nonce: Some(nonce),
+                signature: signer.sign_hash_raw(tx.hash()).unwrap().compress(),
+            }
+            .into(),
+        )
+    }
+
+    #[test]
+    fn test_verify_in_order() {
+        let user1 = Account::from_mnemonic(1, &get_mnemonic_by_account_id(1)).unwrap();
+        let user2 = Account::from_mnemonic(2, &get_mnemonic_by_account_id(2)).unwrap();
+        let register = WrappedMessage::USER(
+            UserMessage {
+                user_id: 1,
+                l1_address: user1.eth_addr_str(),
+                l2_pubkey: user1.bjj_pub_key(),
+            }
+            .into(),
+        );
+
+        let (input_sender, input) = crossbeam_channel::unbounded();
+        let (output, output_receiver) = crossbeam_channel::unbounded();
+        let state = Arc::new(RwLock::new(GlobalState::new(2, 2, 2, false)));
+        let handle = spawn(input, output, state, 3);
+        // the transfer before the registration can not be verified ahead, neither can the one signed by another key
+        let msgs = vec![
+            transfer(&user1, 0),
+            register,
+            transfer(&user1, 0),
+            transfer(&user2, 1),
+            transfer(&user1, 1),
+        ];
+        for msg in msgs {
+            input_sender.send(msg).unwrap();
+        }
+        drop(input_sender);
+        handle.join().unwrap();
+
+        let verified: Vec<VerifiedMessage> = output_receiver.iter().collect();
+        let kinds: Vec<&str> = verified.iter().map(|v| v.msg.type_name()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "TransferMessage",
+                "UserMessage",
+                "TransferMessage",
+                "TransferMessage",
+                "TransferMessage"
+            ]
+        );
+        let nonces: Vec<Option<u32>> = verified
+            .iter()
+            .map(|v| match &v.msg {
+                WrappedMessage::TRANSFER(transfer) => transfer.nonce,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(nonces, vec![Some(0), None, Some(0), Some(1), Some(1)]);
+        let sig_nums: Vec<usize> = verified.iter().map(|v| v.sigs.len()).collect();
+        assert_eq!(sig_nums, vec![0, 0, 1, 0, 1]);
+        assert_eq!(verified[2].sigs[0].ay, user1.ay());
+    }
+}
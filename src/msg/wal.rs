@@ -0,0 +1,254 @@
+use crate::test_utils::messages::{parse_msg_value, WrappedMessage};
+use crate::types::matchengine::offsets::{KafkaOffsets, MsgOffset};
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const WAL_FILE_NAME: &str = "wal.jsonl";
+
+// when the appended entries are fsynced. syncing every message survives a power loss without replaying
+// anything from kafka, syncing every block only makes sure a block is never handed over before its messages are durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSync {
+    Msg,
+    Block,
+}
+
+impl Default for WalSync {
+    fn default() -> Self {
+        Self::Block
+    }
+}
+
+// one line of the wal: `{"offset": {"topic": .., "partition": .., "offset": ..}, "root": .., "type": .., "value": ..}`,
+// where `type` and `value` are the same as the ones accepted by `parse_msg`,
+// and `root` is the state root after the message has been applied
+pub struct WalEntry {
+    pub offset: MsgOffset,
+    pub root: String,
+    pub msg: WrappedMessage,
+}
+
+pub struct WalWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    sync: WalSync,
+}
+
+impl WalWriter {
+    pub fn open(path: &Path, sync: WalSync) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: open_append(path)?,
+            sync,
+        })
+    }
+
+    pub fn append(&mut self, offset: &MsgOffset, type_name: &str, value: Value, root: &Fr) -> Result<()> {
+        let line = json!({
+            "offset": offset,
+            "root": root.to_hex_string(),
+            "type": type_name,
+            "value": value,
+        });
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        if self.sync == WalSync::Msg {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    // makes every appended entry durable, called before the sealed blocks are handed over
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // drops the entries covered by a written checkpoint, they are never replayed again
+    pub fn truncate(&mut self, consumed: &KafkaOffsets) -> Result<()> {
+        self.sync()?;
+        truncate_wal(&self.path, consumed)?;
+        self.writer = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+// returns entries not covered by `consumed`, in the order they were applied
+pub fn read_wal(path: &Path, consumed: &KafkaOffsets) -> Result<Vec<WalEntry>> {
+    let mut entries = Vec::new();
+    scan_wal(path, |_, entry| {
+        if !consumed.is_consumed(&entry.offset) {
+            entries.push(entry);
+        }
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+// rewrites the wal with only the entries not covered by `consumed`. the new file is renamed over the old one,
+// so a crash leaves either of them. a broken tail is dropped as well, so the next append starts on a fresh line
+pub fn truncate_wal(path: &Path, consumed: &KafkaOffsets) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let tmp_path = path.with_extension("jsonl.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let (mut kept, mut dropped) = (0, 0);
+    scan_wal(path, |line, entry| {
+        if consumed.is_consumed(&entry.offset) {
+            dropped += 1;
+        } else {
+            writer.write_all(line)?;
+            writer.write_all(b"\n")?;
+            kept += 1;
+        }
+        Ok(())
+    })?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    std::fs::rename(&tmp_path, path)?;
+    log::info!("wal truncated, {} entries dropped, {} kept", dropped, kept);
+    Ok(())
+}
+
+// streams the entries line by line. a broken last line is left by a crash during appending, so it is ignored
+fn scan_wal(path: &Path, mut f: impl FnMut(&[u8], WalEntry) -> Result<()>) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut lines = BufReader::new(File::open(path)?).split(b'\n').enumerate().peekable();
+    let mut logged = KafkaOffsets::default();
+    while let Some((idx, line)) = lines.next() {
+        let line = line?;
+        let entry = match parse_entry(&line) {
+            Ok(entry) => entry,
+            Err(e) if lines.peek().is_none() => {
+                log::warn!("ignore broken wal tail: {}", e);
+                break;
+            }
+            Err(e) => bail!("invalid wal entry at line {}: {}", idx + 1, e),
+        };
+        if logged.is_consumed(&entry.offset) {
+            bail!("wal offsets not increasing at line {}: {:?}", idx + 1, entry.offset);
+        }
+        logged.update(&entry.offset);
+        f(&line, entry)?;
+    }
+    Ok(())
+}
+
+fn parse_entry(line: &[u8]) -> Result<WalEntry> {
+    let v: Value = serde_json::from_slice(line)?;
+    let offset: MsgOffset = serde_json::from_value(v["offset"].clone()).map_err(|e| anyhow!("wrong offset: {}", e))?;
+    let root = v["root"].as_str().ok_or_else(|| anyhow!("missed root"))?.to_string();
+    let msg = parse_msg_value(&v)?.with_offset(offset.clone());
+    Ok(WalEntry { offset, root, msg })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(user_id: u32) -> Value {
+        json!({
+            "timestamp": 1.0,
+            "user_id": user_id,
+            "asset": "ETH",
+            "business": "deposit",
+            "change": "1",
+            "balance": "1",
+            "balance_available": "1",
+            "balance_frozen": "0",
+            "detail": "",
+        })
+    }
+
+    fn write_wal(name: &str, count: i64) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = WalWriter::open(&path, WalSync::Msg).unwrap();
+        for i in 0..count {
+            let offset = MsgOffset::new("unifyevents", 0, i);
+            writer
+                .append(&offset, "DepositMessage", deposit(i as u32), &Fr::from_u32(i as u32))
+                .unwrap();
+        }
+        writer.sync().unwrap();
+        path
+    }
+
+    fn offsets(entries: &[WalEntry]) -> Vec<i64> {
+        entries.iter().map(|entry| entry.offset.offset).collect()
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let path = write_wal("wal_append", 3);
+        let entries = read_wal(&path, &KafkaOffsets::default()).unwrap();
+        assert_eq!(offsets(&entries), vec![0, 1, 2]);
+        assert_eq!(entries[1].root, Fr::from_u32(1).to_hex_string());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_consumed_filter_and_truncate() {
+        let path = write_wal("wal_consumed", 4);
+        let mut consumed = KafkaOffsets::default();
+        consumed.update(&MsgOffset::new("unifyevents", 0, 1));
+        assert_eq!(offsets(&read_wal(&path, &consumed).unwrap()), vec![2, 3]);
+
+        let mut writer = WalWriter::open(&path, WalSync::Block).unwrap();
+        writer.truncate(&consumed).unwrap();
+        writer
+            .append(&MsgOffset::new("unifyevents", 0, 4), "DepositMessage", deposit(4), &Fr::from_u32(0))
+            .unwrap();
+        writer.sync().unwrap();
+        assert_eq!(offsets(&read_wal(&path, &KafkaOffsets::default()).unwrap()), vec![2, 3, 4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_last_line() {
+        let path = write_wal("wal_torn", 2);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"offset": {"topic": "unifyevents", "partition": 0, "off"#)
+            .unwrap();
+        drop(file);
+        assert_eq!(offsets(&read_wal(&path, &KafkaOffsets::default()).unwrap()), vec![0, 1]);
+
+        // the broken tail is dropped by truncating, so appending goes on from a fresh line
+        let mut writer = WalWriter::open(&path, WalSync::Block).unwrap();
+        writer.truncate(&KafkaOffsets::default()).unwrap();
+        writer
+            .append(&MsgOffset::new("unifyevents", 0, 2), "DepositMessage", deposit(2), &Fr::from_u32(0))
+            .unwrap();
+        writer.sync().unwrap();
+        assert_eq!(offsets(&read_wal(&path, &KafkaOffsets::default()).unwrap()), vec![0, 1, 2]);
+
+        // a broken line in the middle is not left by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\n").unwrap();
+        drop(file);
+        let mut writer = WalWriter::open(&path, WalSync::Block).unwrap();
+        writer
+            .append(&MsgOffset::new("unifyevents", 0, 3), "DepositMessage", deposit(3), &Fr::from_u32(0))
+            .unwrap();
+        writer.sync().unwrap();
+        assert!(read_wal(&path, &KafkaOffsets::default()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
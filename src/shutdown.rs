@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// shared by the threads of the pipeline. once triggered, the loader stops consuming and every stage
+// downstream exits after draining its input, so nothing already consumed is dropped on the way.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn trigger(&self) {
+        // never fails, since `self` holds a receiver
+        self.sender.send(true).ok();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    // resolves once triggered, works on any runtime
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // triggers on the first SIGINT or SIGTERM, a second SIGINT exits right away
+    pub fn trigger_on_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let signal = wait_for_signal().await;
+            log::info!("{} received, shutting down. press Ctrl-C again to exit immediately", signal);
+            shutdown.trigger();
+            tokio::signal::ctrl_c().await.ok();
+            log::warn!("Ctrl-C received again, exit without flushing");
+            std::process::exit(1);
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    tokio::signal::ctrl_c().await.ok();
+    "Ctrl-C"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        let waiter = {
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+                rt.block_on(shutdown.wait());
+            })
+        };
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        waiter.join().unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
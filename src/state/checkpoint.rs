@@ -0,0 +1,214 @@
+use super::circuit_params::CircuitParams;
+use super::global::{GlobalStateError, StateSnapshot};
+use crate::r#const::sled_db::*;
+use crate::types::matchengine::offsets::KafkaOffsets;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+// a frozen copy of the state, together with the offsets it corresponds to
+pub struct Checkpoint {
+    pub block_offset: usize,
+    pub kafka_offsets: KafkaOffsets,
+    pub circuit_params: CircuitParams,
+    pub state: StateSnapshot,
+}
+
+impl Checkpoint {
+    // the dump is written into `{id}.db.tmp` first and renamed when completed,
+    // so a crash during dumping never leaves a half-written `{id}.db` behind
+    pub fn write(&mut self, persist_dir: &Path) -> Result<(), GlobalStateError> {
+        log::info!("start to dump #{}", self.block_offset);
+        let start = Instant::now();
+        let db_path = persist_dir.join(format!("{}.db", self.block_offset));
+        let tmp_path = persist_dir.join(format!("{}.db.tmp", self.block_offset));
+        if tmp_path.exists() {
+            std::fs::remove_dir_all(&tmp_path)?;
+        }
+        {
+            let db = sled::open(&tmp_path)?;
+            db.insert(BLOCK_OFFSET_KEY, bincode::serialize(&self.block_offset)?)?;
+            if self.kafka_offsets.is_empty() {
+                log::warn!("kafka offset not exist, is this block belongs to a test_case?");
+            }
+            db.insert(KAFKA_OFFSETS_KEY, bincode::serialize(&self.kafka_offsets)?)?;
+            db.insert(CIRCUIT_PARAMS_KEY, bincode::serialize(&self.circuit_params)?)?;
+            self.state.persist(&db)?;
+            db.flush()?;
+        }
+        std::fs::rename(&tmp_path, &db_path)?;
+        log::info!(
+            "dump #{} completed, duration: {:.3}s",
+            self.block_offset,
+            start.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    // checkpoints written before multiple partitions were supported only have a single offset
+    pub fn read_kafka_offsets(db: &sled::Db) -> Result<KafkaOffsets, GlobalStateError> {
+        if let Some(v) = db.get(KAFKA_OFFSETS_KEY)? {
+            return Ok(bincode::deserialize(&v)?);
+        }
+        match db.get(KAFKA_OFFSET_KEY)? {
+            Some(v) => Ok(KafkaOffsets::from_legacy(bincode::deserialize(&v)?)),
+            None => Ok(KafkaOffsets::default()),
+        }
+    }
+
+    // `None` for the checkpoints written before the params were recorded
+    pub fn read_circuit_params(db: &sled::Db) -> Result<Option<CircuitParams>, GlobalStateError> {
+        Ok(db.get(CIRCUIT_PARAMS_KEY)?.map(|v| bincode::deserialize(&v)).transpose()?)
+    }
+}
+
+// the checkpoint handed over but not picked up by the worker yet
+#[derive(Default)]
+struct Pending {
+    checkpoint: Option<Checkpoint>,
+    closed: bool,
+}
+
+// writes checkpoints on a background thread, with at most one being written and one waiting.
+// handing over a checkpoint never blocks the processing: a newer one replaces the waiting one,
+// so a slow disk makes the checkpoints sparser instead of stalling the txs.
+pub struct Checkpointer {
+    pending: Arc<(Mutex<Pending>, Condvar)>,
+    worker: Option<JoinHandle<()>>,
+    // the kafka offsets of the latest written checkpoint, not taken yet
+    written: Arc<Mutex<Option<KafkaOffsets>>>,
+}
+
+impl Checkpointer {
+    pub fn new(persist_dir: PathBuf) -> Self {
+        let pending = Arc::new((Mutex::new(Pending::default()), Condvar::new()));
+        let written = Arc::new(Mutex::new(None));
+        let worker = {
+            let pending = Arc::clone(&pending);
+            let written = Arc::clone(&written);
+            std::thread::spawn(move || loop {
+                let mut checkpoint = {
+                    let (lock, cvar) = &*pending;
+                    let mut guard = lock.lock().unwrap();
+                    loop {
+                        if let Some(checkpoint) = guard.checkpoint.take() {
+                            break checkpoint;
+                        }
+                        if guard.closed {
+                            return;
+                        }
+                        guard = cvar.wait(guard).unwrap();
+                    }
+                };
+                match checkpoint.write(&persist_dir) {
+                    Ok(()) => *written.lock().unwrap() = Some(checkpoint.kafka_offsets),
+                    Err(e) => log::error!("dump #{} failed: {}", checkpoint.block_offset, e),
+                }
+            })
+        };
+        Self {
+            pending,
+            worker: Some(worker),
+            written,
+        }
+    }
+
+    pub fn take_written_offsets(&self) -> Option<KafkaOffsets> {
+        self.written.lock().unwrap().take()
+    }
+
+    pub fn submit(&self, checkpoint: Checkpoint) {
+        let (lock, cvar) = &*self.pending;
+        let skipped = lock.lock().unwrap().checkpoint.replace(checkpoint);
+        cvar.notify_one();
+        if let Some(skipped) = skipped {
+            log::warn!("dump #{} skipped, the previous dump is still being written", skipped.block_offset);
+        }
+    }
+
+    // blocks until every submitted checkpoint is written
+    pub fn join(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // the worker stops once the waiting checkpoint is written
+        let (lock, cvar) = &*self.pending;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_one();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::error!("checkpoint worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::state::GlobalState;
+    use crate::types::matchengine::offsets::MsgOffset;
+    use fluidex_common::types::FrExt;
+    use fluidex_common::Fr;
+
+    // the checkpoint with the root of its state
+    fn checkpoint(block_offset: usize) -> (Checkpoint, Fr) {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        let account_id = state.create_new_account(1).unwrap();
+        state.set_token_balance(account_id, 1, Fr::from_u32(block_offset as u32));
+        let mut kafka_offsets = KafkaOffsets::default();
+        kafka_offsets.update(&MsgOffset::new("unifyevents", 0, block_offset as i64));
+        let checkpoint = Checkpoint {
+            block_offset,
+            kafka_offsets,
+            circuit_params: CircuitParams::from(&Settings::new()),
+            state: state.snapshot(),
+        };
+        (checkpoint, state.root())
+    }
+
+    #[test]
+    fn test_checkpointer() {
+        let dir = std::env::temp_dir().join(format!("checkpointer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // left behind by a crash during dumping
+        std::fs::create_dir_all(dir.join("2.db.tmp")).unwrap();
+
+        let checkpointer = Checkpointer::new(dir.clone());
+        checkpointer.submit(checkpoint(1).0);
+        // replaces the first one, unless the worker has picked it up already
+        checkpointer.submit(checkpoint(2).0);
+        let written = Arc::clone(&checkpointer.written);
+        checkpointer.join();
+        assert_eq!(written.lock().unwrap().take(), Some(checkpoint(2).0.kafka_offsets));
+
+        let names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(names.contains(&"2.db".to_string()));
+        assert!(names.iter().all(|name| name.ends_with(".db")));
+        for name in names {
+            let block_offset: usize = name.strip_suffix(".db").unwrap().parse().unwrap();
+            let (expected, root) = checkpoint(block_offset);
+            let db = sled::open(dir.join(&name)).unwrap();
+            let stored: usize = bincode::deserialize(&db.get(BLOCK_OFFSET_KEY).unwrap().unwrap()).unwrap();
+            assert_eq!(stored, block_offset);
+            assert_eq!(Checkpoint::read_kafka_offsets(&db).unwrap(), expected.kafka_offsets);
+            assert_eq!(Checkpoint::read_circuit_params(&db).unwrap(), Some(expected.circuit_params));
+            let mut state = GlobalState::new(2, 2, 2, false);
+            state.load_persist(&db).unwrap();
+            assert_eq!(state.root(), root);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use super::OrderSlotStrategy;
+use crate::config::Settings;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// the params the state trees and the blocks are built with, only a circuit of the same params can prove them.
+// they are recorded in the checkpoints and in the db, restarting with other params would give garbage roots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitParams {
+    pub ntxs: usize,
+    pub balance_levels: usize,
+    pub order_levels: usize,
+    pub account_levels: usize,
+    // unknown if not configured, and then not compared
+    pub circuit_version: Option<String>,
+    // every block size with a circuit deployed, `ntxs` included, in ascending order.
+    // empty if recorded before it was added, and then not compared
+    #[serde(default)]
+    pub block_sizes: Vec<usize>,
+    // not a param of the circuit, but it decides which leaves the orders take, so the order trees of
+    // a checkpoint only go on the same way with the same strategy. none if recorded before it was added
+    #[serde(default)]
+    pub order_slot_strategy: Option<OrderSlotStrategy>,
+    // the account receiving the trading fees, the data built for one receiver must not be carried on for another.
+    // none if not configured or recorded before it was added, and then not compared
+    #[serde(default)]
+    pub fee_account: Option<u64>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("circuit params mismatch: {}", .0.join(", "))]
+pub struct CircuitParamsMismatch(pub Vec<String>);
+
+impl CircuitParams {
+    // `self` is the configured params, `recorded` the ones the existing data is built with
+    pub fn check_compatible(&self, recorded: &CircuitParams) -> Result<(), CircuitParamsMismatch> {
+        let mut mismatches = Vec::new();
+        for (name, configured, recorded) in [
+            ("ntxs", self.ntxs, recorded.ntxs),
+            ("balance_levels", self.balance_levels, recorded.balance_levels),
+            ("order_levels", self.order_levels, recorded.order_levels),
+            ("account_levels", self.account_levels, recorded.account_levels),
+        ] {
+            if configured != recorded {
+                mismatches.push(format!("{} is {} but recorded {}", name, configured, recorded));
+            }
+        }
+        if let (Some(configured), Some(recorded)) = (&self.circuit_version, &recorded.circuit_version) {
+            if configured != recorded {
+                mismatches.push(format!("circuit_version is {} but recorded {}", configured, recorded));
+            }
+        }
+        if !recorded.block_sizes.is_empty() && self.block_sizes != recorded.block_sizes {
+            mismatches.push(format!(
+                "block_sizes is {:?} but recorded {:?}",
+                self.block_sizes, recorded.block_sizes
+            ));
+        }
+        if let (Some(configured), Some(recorded)) = (self.order_slot_strategy, recorded.order_slot_strategy) {
+            if configured != recorded {
+                mismatches.push(format!("order_slot_strategy is {:?} but recorded {:?}", configured, recorded));
+            }
+        }
+        if let (Some(configured), Some(recorded)) = (self.fee_account, recorded.fee_account) {
+            if configured != recorded {
+                mismatches.push(format!("fee_account is {} but recorded {}", configured, recorded));
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(CircuitParamsMismatch(mismatches))
+        }
+    }
+}
+
+impl From<&Settings> for CircuitParams {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            ntxs: settings.ntxs,
+            balance_levels: settings.tree_levels.balance,
+            order_levels: settings.tree_levels.order,
+            account_levels: settings.tree_levels.account,
+            circuit_version: settings.circuit_version.clone(),
+            block_sizes: block_sizes(settings.ntxs, &settings.block_sizes),
+            order_slot_strategy: Some(settings.order_slot_strategy),
+            fee_account: settings.fee_account,
+        }
+    }
+}
+
+// the smaller block sizes are configured besides `ntxs`
+pub fn block_sizes(ntxs: usize, smaller: &[usize]) -> Vec<usize> {
+    let mut block_sizes = smaller.to_vec();
+    block_sizes.push(ntxs);
+    block_sizes.sort_unstable();
+    block_sizes.dedup();
+    block_sizes
+}
+
+impl fmt::Display for CircuitParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Block({}, {}, {}, {}) of circuit version {}, block sizes {:?}",
+            self.ntxs,
+            self.balance_levels,
+            self.order_levels,
+            self.account_levels,
+            self.circuit_version.as_deref().unwrap_or("unknown"),
+            self.block_sizes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_compatible() {
+        let configured = CircuitParams::from(&Settings::new());
+        let mut recorded = configured.clone();
+        recorded.circuit_version = Some("197".to_string());
+        assert_eq!(configured.check_compatible(&recorded), Ok(()));
+
+        recorded.ntxs += 1;
+        recorded.order_levels += 1;
+        assert_eq!(
+            configured.check_compatible(&recorded),
+            Err(CircuitParamsMismatch(vec![
+                format!("ntxs is {} but recorded {}", configured.ntxs, recorded.ntxs),
+                format!("order_levels is {} but recorded {}", configured.order_levels, recorded.order_levels),
+            ]))
+        );
+
+        let mut recorded = configured.clone();
+        recorded.block_sizes = vec![1, configured.ntxs];
+        assert_eq!(
+            configured.check_compatible(&recorded),
+            Err(CircuitParamsMismatch(vec![format!(
+                "block_sizes is {:?} but recorded {:?}",
+                configured.block_sizes, recorded.block_sizes
+            )]))
+        );
+        // recorded before the block sizes were added
+        recorded.block_sizes.clear();
+        assert_eq!(configured.check_compatible(&recorded), Ok(()));
+
+        recorded.order_slot_strategy = Some(OrderSlotStrategy::FreeList);
+        assert_eq!(
+            configured.check_compatible(&recorded),
+            Err(CircuitParamsMismatch(vec![
+                "order_slot_strategy is LinearProbe but recorded FreeList".to_string()
+            ]))
+        );
+
+        let mut recorded = configured.clone();
+        recorded.fee_account = Some(1);
+        assert_eq!(configured.check_compatible(&recorded), Ok(()));
+        let mut configured = configured;
+        configured.fee_account = Some(0);
+        assert_eq!(
+            configured.check_compatible(&recorded),
+            Err(CircuitParamsMismatch(vec!["fee_account is 0 but recorded 1".to_string()]))
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use super::GlobalState;
+use crate::types::l2::{FrStr, Order, OrderSide};
+use anyhow::{bail, ensure, Result};
+use fluidex_common::ff::Field;
+use fluidex_common::l2::account::Signature;
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+// a portable snapshot of the accounts, used to seed a rollup without replaying messages.
+// all field elements are in decimal str repr.
+#[derive(Serialize, Deserialize)]
+pub struct Genesis {
+    // the expected state root in hex str repr, checked after loading if present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    // account ids must be exactly 0..accounts.len(), in order
+    pub accounts: Vec<GenesisAccount>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub account_id: u64,
+    pub nonce: FrStr,
+    // the l2 key, both zero if the account has not registered one
+    pub sign: FrStr,
+    pub ay: FrStr,
+    // token_id -> balance
+    #[serde(default)]
+    pub balances: BTreeMap<u32, FrStr>,
+    #[serde(default)]
+    pub orders: Vec<GenesisOrder>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GenesisOrder {
+    // the leaf of the order tree, positions are not reused in order so they must be kept as is
+    pub order_pos: u32,
+    pub order_id: u32,
+    pub side: OrderSide,
+    pub token_buy: u32,
+    pub token_sell: u32,
+    pub total_buy: FrStr,
+    pub total_sell: FrStr,
+    pub filled_buy: FrStr,
+    pub filled_sell: FrStr,
+    pub sig: Signature,
+    pub is_active: bool,
+}
+
+impl Genesis {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn to_file(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    // collects every account of the state, the root is declared as the current one
+    pub fn export(state: &GlobalState) -> Self {
+        let accounts = state
+            .account_ids()
+            .into_iter()
+            .map(|account_id| {
+                let account = state.get_account(account_id);
+                GenesisAccount {
+                    account_id,
+                    nonce: account.nonce.into(),
+                    sign: account.sign.into(),
+                    ay: account.ay.into(),
+                    balances: state
+                        .get_token_balances(account_id)
+                        .into_iter()
+                        .map(|(token_id, balance)| (token_id, balance.into()))
+                        .collect(),
+                    orders: state
+                        .get_account_orders(account_id)
+                        .into_iter()
+                        .map(|(order_pos, order)| GenesisOrder::new(order_pos, &order))
+                        .collect(),
+                }
+            })
+            .collect();
+        Self {
+            root: Some(state.root().to_hex_string()),
+            accounts,
+        }
+    }
+
+    // applies the snapshot on an empty state
+    pub fn apply(&self, state: &mut GlobalState) -> Result<()> {
+        ensure!(state.account_ids().is_empty(), "genesis can only be applied on an empty state");
+        for account in &self.accounts {
+            let account_id = state.create_new_account(1)?;
+            if account_id != account.account_id {
+                bail!(
+                    "genesis account ids must be continuous, expect {} got {}",
+                    account_id,
+                    account.account_id
+                );
+            }
+            if !account.sign.0.is_zero() || !account.ay.0.is_zero() {
+                state.set_account_l2_addr(account_id, account.sign.0, account.ay.0);
+            }
+            state.set_account_nonce(account_id, account.nonce.0);
+            let balances: Vec<(u32, Fr)> = account.balances.iter().map(|(token_id, balance)| (*token_id, balance.0)).collect();
+            state.set_token_balances(account_id, &balances);
+            let mut positions = BTreeSet::new();
+            for order in &account.orders {
+                ensure!(
+                    u64::from(order.order_pos) < 1 << state.order_levels(),
+                    "order_pos {} of account {} overflows for order levels {}",
+                    order.order_pos,
+                    account_id,
+                    state.order_levels()
+                );
+                ensure!(
+                    positions.insert(order.order_pos),
+                    "duplicated order_pos {} of account {}",
+                    order.order_pos,
+                    account_id
+                );
+            }
+            // orders still carry u32 account ids
+            let orders = account
+                .orders
+                .iter()
+                .map(|order| Ok((order.order_pos, order.to_order(u32::try_from(account_id)?))))
+                .collect::<Result<_>>()?;
+            state.set_account_orders(account_id, orders);
+        }
+
+        let computed = state.root().to_hex_string();
+        match &self.root {
+            Some(declared) if *declared != computed => {
+                bail!("genesis root mismatch, declared {} computed {}", declared, computed)
+            }
+            _ => log::info!("genesis loaded with {} accounts, root {}", self.accounts.len(), computed),
+        }
+        Ok(())
+    }
+}
+
+impl GenesisOrder {
+    fn new(order_pos: u32, order: &Order) -> Self {
+        Self {
+            order_pos,
+            order_id: order.order_id,
+            side: order.side,
+            token_buy: order.token_buy.to_u32(),
+            token_sell: order.token_sell.to_u32(),
+            total_buy: order.total_buy.into(),
+            total_sell: order.total_sell.into(),
+            filled_buy: order.filled_buy.into(),
+            filled_sell: order.filled_sell.into(),
+            sig: order.sig,
+            is_active: order.is_active,
+        }
+    }
+
+    fn to_order(&self, account_id: u32) -> Order {
+        Order {
+            account_id,
+            order_id: self.order_id,
+            side: self.side,
+            token_buy: Fr::from_u32(self.token_buy),
+            token_sell: Fr::from_u32(self.token_sell),
+            total_buy: self.total_buy.0,
+            total_sell: self.total_sell.0,
+            filled_buy: self.filled_buy.0,
+            filled_sell: self.filled_sell.0,
+            sig: self.sig,
+            is_active: self.is_active,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genesis_roundtrip() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        let account_id = state.create_new_account(1).unwrap();
+        state.set_account_l2_addr(account_id, Fr::one(), Fr::from_u32(12345));
+        state.set_token_balance(account_id, 1, Fr::from_u32(1000));
+        state.set_account_nonce(account_id, Fr::from_u32(3));
+        let account_id = state.create_new_account(1).unwrap();
+        state.set_token_balance(account_id, 2, Fr::from_u32(42));
+        // sparse positions, as left by closed orders being replaced
+        for (order_pos, order_id) in [(1, 7), (3, 9)] {
+            let order = Order {
+                account_id: account_id as u32,
+                order_id,
+                token_buy: Fr::from_u32(1),
+                token_sell: Fr::from_u32(2),
+                total_buy: Fr::from_u32(10),
+                total_sell: Fr::from_u32(20),
+                filled_buy: Fr::from_u32(order_id),
+                ..Default::default()
+            };
+            state.set_account_order(account_id, order_pos, order);
+        }
+
+        let json = serde_json::to_string(&Genesis::export(&state)).unwrap();
+        let genesis: Genesis = serde_json::from_str(&json).unwrap();
+        let mut loaded = GlobalState::new(2, 2, 2, false);
+        genesis.apply(&mut loaded).unwrap();
+        assert_eq!(loaded.root(), state.root());
+        assert_eq!(loaded.get_token_balance(0, 1), Fr::from_u32(1000));
+        assert_eq!(loaded.get_order_pos_by_id(1, 7), Some(1));
+        assert_eq!(loaded.get_order_pos_by_id(1, 9), Some(3));
+        assert_eq!(loaded.order_proof(1, 3).root, state.order_proof(1, 3).root);
+
+        let mut genesis: Genesis = serde_json::from_str(&json).unwrap();
+        genesis.root = Some(Fr::zero().to_hex_string());
+        genesis
+            .apply(&mut GlobalState::new(2, 2, 2, false))
+            .expect_err("root should mismatch");
+    }
+}
@@ -1,11 +1,13 @@
 #![allow(clippy::field_reassign_with_default)]
 #![allow(clippy::vec_init_then_push)]
 
+use super::order_slots::{FreeSlots, OrderSlotStrategy, OrderTreeFull, OrderTreeOccupancy};
 use super::AccountState;
 #[cfg(feature = "persist_sled")]
 use crate::r#const::sled_db::*;
 use crate::types::l2::Order;
-use crate::types::merkle_tree::{MerkleProof, Tree};
+use crate::types::merkle_tree::{MerkleProof, StateTree, Tree, TreeStats};
+use crate::types::node_store::StateStore;
 use anyhow::bail;
 use fluidex_common::ff::Field;
 use fluidex_common::fnv::FnvHashMap;
@@ -20,7 +22,7 @@ use sled::transaction::ConflictableTransactionError;
 #[cfg(feature = "persist_sled")]
 use sled::transaction::{TransactionError, Transactional, TransactionalTree};
 use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 pub struct BalanceProof {
     pub leaf: Fr,
@@ -39,9 +41,22 @@ pub struct OrderProof {
     pub account_path: Vec<[Fr; 1]>,
     pub root: Fr,
 }
+// the stored nodes of all the trees of the state
+#[derive(Debug, Clone, Default)]
+pub struct StateTreeStats {
+    pub account_tree: TreeStats,
+    // added up over all the accounts
+    pub balance_trees: TreeStats,
+    pub order_trees: TreeStats,
+}
+impl StateTreeStats {
+    pub fn bytes(&self) -> usize {
+        self.account_tree.bytes + self.balance_trees.bytes + self.order_trees.bytes
+    }
+}
 #[derive(Clone, Default)]
 pub struct AccountUpdates {
-    pub account_id: u32,
+    pub account_id: u64,
     pub balance_updates: Vec<(u32, Fr)>,
     pub order_updates: Vec<(u32, Fr)>,
     pub new_nonce: Option<Fr>,
@@ -55,8 +70,12 @@ pub enum GlobalStateError {
     #[error(transparent)]
     #[cfg(feature = "persist_sled")]
     Bincode(#[from] bincode::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error("requested content not found in db")]
     NotFound,
+    #[error("the {tree} trees in db have {found} levels, while {expected} levels are configured")]
+    LevelsMismatch { tree: &'static str, found: usize, expected: usize },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -96,25 +115,48 @@ impl From<TransactionError<GlobalStateError>> for GlobalStateError {
 
 type Result<T, E = GlobalStateError> = std::result::Result<T, E>;
 
+// the leaves of `Tree` are indexed by u64, while token ids and order positions stay u32
+fn widen_leaf_indices(updates: &[(u32, Fr)]) -> Vec<(u64, Fr)> {
+    updates.iter().map(|(idx, value)| ((*idx).into(), *value)).collect()
+}
+
+/// The key of an account in the per-account sled trees, also the prefix of its stored [`AccountState`].
+/// Ids that fit in u32 keep the u32 encoding, so the dbs written before account ids were widened can still be loaded.
+#[cfg(feature = "persist_sled")]
+pub fn account_db_key(account_id: u64) -> Vec<u8> {
+    match u32::try_from(account_id) {
+        Ok(id) => bincode::serialize(&id),
+        Err(_) => bincode::serialize(&account_id),
+    }
+    .expect("encode account id")
+}
+
+/// Decodes the `(account_id, state)` stored under the hash of the account leaf of `account_id`.
+#[cfg(feature = "persist_sled")]
+pub fn decode_account_state(account_id: u64, bytes: &[u8]) -> bincode::Result<AccountState> {
+    let id_len = account_db_key(account_id).len();
+    bincode::deserialize(bytes.get(id_len..).unwrap_or_default())
+}
+
 // TODO: too many unwrap here
-// TODO: do we really need Arc/Mutex?
+// the trees and orders of an account are behind `Arc`s, so a snapshot shares them until they are written again
 pub struct GlobalState {
     balance_levels: usize,
     order_levels: usize,
     account_levels: usize,
 
     // account_id -> acount_state_hash
-    account_tree: Arc<Mutex<Tree>>,
+    account_tree: StateTree,
     // account_id -> acount_state
-    account_states: FnvHashMap<u32, AccountState>,
+    account_states: FnvHashMap<u64, AccountState>,
     // account_id -> token_id -> balance
-    balance_trees: FnvHashMap<u32, Arc<Mutex<Tree>>>,
+    balance_trees: FnvHashMap<u64, Arc<StateTree>>,
     // account_id -> order_pos -> order_hash
-    order_trees: FnvHashMap<u32, Arc<Mutex<Tree>>>,
+    order_trees: FnvHashMap<u64, Arc<StateTree>>,
     // account_id -> order_pos -> order
-    order_states: FnvHashMap<u32, BTreeMap<u32, Order>>,
+    order_states: FnvHashMap<u64, Arc<BTreeMap<u32, Order>>>,
     // (account_id, order_id) -> order_pos
-    order_id_to_pos: FnvHashMap<(u32, u32), u32>,
+    order_id_to_pos: FnvHashMap<(u64, u32), u32>,
 
     default_balance_root: Fr,
     default_order_leaf: Fr,
@@ -122,8 +164,14 @@ pub struct GlobalState {
     default_account_leaf: Fr,
     // TODO: id or pos?
     default_next_order_id: u32,
-    next_order_positions: FnvHashMap<u32, u32>,
+    next_order_positions: FnvHashMap<u64, u32>,
     max_order_num_per_user: u32,
+    order_slot_strategy: OrderSlotStrategy,
+    // account_id -> positions of the filled or cancelled orders
+    free_order_slots: FnvHashMap<u64, FreeSlots>,
+
+    // an empty store, the stores of the new trees are created on its backend
+    node_store: StateStore,
 
     // some precalculated items
     empty_order_tree: Tree,
@@ -136,7 +184,7 @@ pub struct GlobalState {
 
 impl GlobalState {
     pub fn print_config() {
-        Tree::print_config();
+        StateTree::print_config();
     }
 
     pub fn balance_bits(&self) -> usize {
@@ -166,8 +214,8 @@ impl GlobalState {
 
         // default_account_leaf depends on default_order_root and default_balance_root
         let default_account_leaf = AccountState::empty(default_balance_root, default_order_root).hash();
-        let max_order_num_per_user = empty_order_tree.max_leaf_num();
-        let account_tree = Arc::new(Mutex::new(Tree::new(account_levels, default_account_leaf)));
+        let max_order_num_per_user = empty_order_tree.max_leaf_num() as u32;
+        let account_tree = StateTree::new(account_levels, default_account_leaf);
         Self {
             balance_levels,
             order_levels,
@@ -185,6 +233,9 @@ impl GlobalState {
             account_states: FnvHashMap::default(),
             next_order_positions: FnvHashMap::default(),
             max_order_num_per_user,
+            order_slot_strategy: OrderSlotStrategy::default(),
+            free_order_slots: FnvHashMap::default(),
+            node_store: StateStore::default(),
             empty_balance_tree,
             empty_order_tree,
             trivial_order_path_elements,
@@ -192,58 +243,112 @@ impl GlobalState {
             allow_overwrite_order_leaf: true,
         }
     }
+    pub fn set_order_slot_strategy(&mut self, strategy: OrderSlotStrategy) {
+        self.order_slot_strategy = strategy;
+    }
+    pub fn order_slot_strategy(&self) -> OrderSlotStrategy {
+        self.order_slot_strategy
+    }
+    // moves the trees into `store`, which should be empty. the new trees are created on its backend too
+    pub fn set_node_store(&mut self, store: StateStore) {
+        self.account_tree = self.account_tree.copy_into(store.new_empty());
+        for tree in self.balance_trees.values_mut().chain(self.order_trees.values_mut()) {
+            *tree = Arc::new(tree.copy_into(store.new_empty()));
+        }
+        self.node_store = store;
+    }
     pub fn root(&self) -> Fr {
-        self.account_tree.lock().unwrap().get_root()
+        self.account_tree.get_root()
     }
-    fn recalculate_account_state_hash(&mut self, account_id: u32) -> Fr {
+    // a frozen view of the state to be checkpointed on another thread. the trees and orders of the accounts are
+    // shared with `self`, which copies the ones of an account on its next write, so taking it only copies
+    // the small per-account values. the account tree is rebuilt by `StateSnapshot::persist` instead
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot(Self {
+            balance_levels: self.balance_levels,
+            order_levels: self.order_levels,
+            account_levels: self.account_levels,
+            account_tree: StateTree::new(self.account_levels, self.default_account_leaf),
+            account_states: self.account_states.clone(),
+            balance_trees: self.balance_trees.clone(),
+            order_trees: self.order_trees.clone(),
+            order_states: self.order_states.clone(),
+            // not persisted, it is rebuilt from `order_states` on loading
+            order_id_to_pos: FnvHashMap::default(),
+            default_balance_root: self.default_balance_root,
+            default_order_leaf: self.default_order_leaf,
+            default_order_root: self.default_order_root,
+            default_account_leaf: self.default_account_leaf,
+            default_next_order_id: self.default_next_order_id,
+            next_order_positions: self.next_order_positions.clone(),
+            max_order_num_per_user: self.max_order_num_per_user,
+            order_slot_strategy: self.order_slot_strategy,
+            free_order_slots: self.free_order_slots.clone(),
+            node_store: StateStore::default(),
+            empty_order_tree: self.empty_order_tree.clone(),
+            empty_balance_tree: self.empty_balance_tree.clone(),
+            trivial_order_path_elements: self.trivial_order_path_elements.clone(),
+            verbose: self.verbose,
+            allow_overwrite_order_leaf: self.allow_overwrite_order_leaf,
+        })
+    }
+    fn recalculate_account_state_hash(&mut self, account_id: u64) -> Fr {
         let mut acc = self.account_states.get_mut(&account_id).unwrap();
         // TODO: for balance_root/order_root, we maintain two 'truth' here
         // not a good idea
-        acc.balance_root = self.balance_trees.get(&account_id).unwrap().lock().unwrap().get_root();
-        acc.order_root = self.order_trees.get(&account_id).unwrap().lock().unwrap().get_root();
+        acc.balance_root = self.balance_trees.get(&account_id).unwrap().get_root();
+        acc.order_root = self.order_trees.get(&account_id).unwrap().get_root();
         acc.hash()
     }
-    pub fn flush_account_state(&mut self, account_id: u32) {
+    pub fn flush_account_state(&mut self, account_id: u64) {
         let hash = self.recalculate_account_state_hash(account_id);
-        let tree = self.account_tree.clone();
-        tree.lock().unwrap().set_value(account_id, hash);
+        self.account_tree.set_value(account_id, hash);
     }
-    pub fn set_account_l2_addr(&mut self, account_id: u32, sign: Fr, ay: Fr) {
+    pub fn set_account_l2_addr(&mut self, account_id: u64, sign: Fr, ay: Fr) {
         let account = self.account_states.get_mut(&account_id).unwrap();
         account.update_l2_addr(sign, ay);
-        self.account_tree.lock().unwrap().set_value(account_id, account.hash());
+        self.account_tree.set_value(account_id, account.hash());
     }
-    pub fn get_account_nonce(&self, account_id: u32) -> Fr {
+    pub fn get_account_nonce(&self, account_id: u64) -> Fr {
         self.get_account(account_id).nonce
     }
-    pub fn set_account_nonce(&mut self, account_id: u32, nonce: Fr) {
+    pub fn set_account_nonce(&mut self, account_id: u64, nonce: Fr) {
         self.account_states.get_mut(&account_id).unwrap().update_nonce(nonce);
         self.flush_account_state(account_id);
     }
     // this function should only be used in tests for convenience
-    pub fn set_account_order_root(&mut self, account_id: u32, order_root: Fr) {
+    pub fn set_account_order_root(&mut self, account_id: u64, order_root: Fr) {
         self.account_states.get_mut(&account_id).unwrap().update_order_root(order_root);
         self.flush_account_state(account_id);
     }
-    pub fn increase_nonce(&mut self, account_id: u32) {
+    pub fn increase_nonce(&mut self, account_id: u64) {
         let mut nonce = self.account_states.get(&account_id).unwrap().nonce;
         nonce.add_assign(&Fr::one());
         //println!("oldNonce", oldNonce);
         self.set_account_nonce(account_id, nonce);
     }
-    pub fn get_account(&self, account_id: u32) -> AccountState {
+    pub fn get_account(&self, account_id: u64) -> AccountState {
         self.account_states
             .get(&account_id)
             .cloned()
             .unwrap_or_else(|| AccountState::empty(self.default_balance_root, self.default_order_root))
     }
-    pub fn has_account(&self, account_id: u32) -> bool {
+    pub fn order_levels(&self) -> usize {
+        self.order_levels
+    }
+    // ids of all existing accounts, in ascending order
+    pub fn account_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.account_states.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+    pub fn has_account(&self, account_id: u64) -> bool {
         !self.get_account(account_id).ay.is_zero()
     }
 
     // find a position range 0..2**n where the slot is either empty or occupied by a close order
     // so we can place the new order here
-    fn get_next_order_pos_for_user(&mut self, account_id: u32, order_id: u32) -> u32 {
+    fn find_order_slot(&self, account_id: u64, order_id: u32) -> Result<u32, OrderTreeFull> {
         let order_state_tree = self.order_states.get(&account_id).unwrap();
         let order_num = order_state_tree.len();
         debug_assert!(order_num <= self.max_order_num_per_user as usize);
@@ -252,110 +357,175 @@ impl GlobalState {
                 debug_assert!(order_state_tree.is_empty() || *order_state_tree.iter().rev().next().unwrap().0 == order_num as u32 - 1);
             }
             // return the last leaf location
-            return order_num as u32;
+            return Ok(order_num as u32);
         }
         // now the tree is full
         // we have to find a vicvim order to replace
-        if self.allow_overwrite_order_leaf {
-            let start_pos = *self.next_order_positions.get(&account_id).unwrap();
-            for i in 0..2u32.pow(self.order_levels as u32) {
-                let candidate_pos = (start_pos + i) % 2u32.pow(self.order_levels as u32);
-                let order = self.get_account_order_by_pos(account_id, candidate_pos);
-                debug_assert!(!order.is_default());
-                if order.is_filled() || !order.is_active {
-                    assert_ne!(order_id, order.order_id, "order already in tree, why search location for it?");
-                    if order.order_id < order_id {
-                        self.next_order_positions.insert(account_id, candidate_pos + 1);
-                        log::debug!(
-                            "replace order uid {} old order {} new order {} at {}. reason: {}",
-                            account_id,
-                            order.order_id,
-                            order_id,
-                            candidate_pos,
-                            if order.is_filled() { "filled" } else { "cancelled" }
-                        );
-                        return candidate_pos;
-                    }
+        let full = OrderTreeFull { account_id, order_id };
+        if !self.allow_overwrite_order_leaf {
+            return Err(full);
+        }
+        if self.order_slot_strategy != OrderSlotStrategy::LinearProbe {
+            return self
+                .free_order_slots
+                .get(&account_id)
+                .and_then(|slots| slots.pick(self.order_slot_strategy, order_id))
+                .ok_or(full);
+        }
+        let start_pos = *self.next_order_positions.get(&account_id).unwrap();
+        for i in 0..2u32.pow(self.order_levels as u32) {
+            let candidate_pos = (start_pos + i) % 2u32.pow(self.order_levels as u32);
+            let order = self.get_account_order_by_pos(account_id, candidate_pos);
+            debug_assert!(!order.is_default());
+            if order.is_filled() || !order.is_active {
+                assert_ne!(order_id, order.order_id, "order already in tree, why search location for it?");
+                if order.order_id < order_id {
+                    return Ok(candidate_pos);
                 }
             }
         }
-        panic!(
-            "Cannot find order pos, please use larger order tree height. account_id {} order_id {}",
-            account_id, order_id
-        );
+        Err(full)
+    }
+    fn get_next_order_pos_for_user(&mut self, account_id: u64, order_id: u32) -> Result<u32, OrderTreeFull> {
+        let pos = self.find_order_slot(account_id, order_id)?;
+        let order = self.get_account_order_by_pos(account_id, pos);
+        if !order.is_default() {
+            self.next_order_positions.insert(account_id, pos + 1);
+            log::debug!(
+                "replace order uid {} old order {} new order {} at {}. reason: {}",
+                account_id,
+                order.order_id,
+                order_id,
+                pos,
+                if order.is_filled() { "filled" } else { "cancelled" }
+            );
+        }
+        if let Some(slots) = self.free_order_slots.get_mut(&account_id) {
+            slots.take(pos);
+        }
+        Ok(pos)
+    }
+    // checks a new order can be placed without touching the state, so a trade can be rejected before applied
+    pub fn check_order_slot(&self, account_id: u64, order_id: u32) -> Result<(), OrderTreeFull> {
+        if self.has_order(account_id, order_id) {
+            return Ok(());
+        }
+        self.find_order_slot(account_id, order_id).map(|_| ())
+    }
+    // keeps `free_order_slots` in sync after the order at `order_pos` changes
+    fn refresh_free_slot(&mut self, account_id: u64, order_pos: u32) {
+        let order = self.get_account_order_by_pos(account_id, order_pos);
+        let slots = self.free_order_slots.entry(account_id).or_default();
+        if !order.is_default() && (order.is_filled() || !order.is_active) {
+            slots.release(order_pos, order.order_id);
+        } else {
+            slots.take(order_pos);
+        }
+    }
+    pub fn order_tree_occupancy(&self, account_id: u64) -> OrderTreeOccupancy {
+        let used = self.order_states.get(&account_id).map_or(0, |orders| orders.len());
+        let closed = self.free_order_slots.get(&account_id).map_or(0, |slots| slots.len());
+        OrderTreeOccupancy {
+            open: used - closed,
+            closed,
+            capacity: self.max_order_num_per_user,
+        }
     }
-    pub fn get_next_account_id(&self) -> anyhow::Result<u32> {
-        let account_id = self.balance_trees.len() as u32;
-        if account_id >= 2u32.pow(self.account_levels as u32) {
+    // the accounts with the most open orders, busiest first
+    pub fn busiest_order_trees(&self, n: usize) -> Vec<(u64, OrderTreeOccupancy)> {
+        let mut occupancies: Vec<(u64, OrderTreeOccupancy)> = self
+            .order_states
+            .keys()
+            .map(|account_id| (*account_id, self.order_tree_occupancy(*account_id)))
+            .filter(|(_, occupancy)| occupancy.used() > 0)
+            .collect();
+        occupancies.sort_by(|(id1, o1), (id2, o2)| o2.open.cmp(&o1.open).then(id1.cmp(id2)));
+        occupancies.truncate(n);
+        occupancies
+    }
+    pub fn get_next_account_id(&self) -> anyhow::Result<u64> {
+        let account_id = self.balance_trees.len() as u64;
+        if account_id >= 2u64.pow(self.account_levels as u32) {
             bail!("account_id {} overflows for account_levels {}", account_id, self.account_levels);
         }
         Ok(account_id)
     }
-    fn init_account(&mut self, account_id: u32, next_order_id: u32) -> anyhow::Result<u32> {
+    fn init_account(&mut self, account_id: u64, next_order_id: u32) -> anyhow::Result<u64> {
         if self.account_states.contains_key(&account_id) {
             return Ok(account_id);
         }
-        if account_id >= 2u32.pow(self.account_levels as u32) {
+        if account_id >= 2u64.pow(self.account_levels as u32) {
             bail!("account_id {} overflows for account_levels {}", account_id, self.account_levels);
         }
         let account_state = AccountState::empty(self.default_balance_root, self.default_order_root);
         self.account_states.insert(account_id, account_state);
-        self.balance_trees
-            .insert(account_id, Arc::new(Mutex::new(Tree::new(self.balance_levels, Fr::zero()))));
-        self.order_trees.insert(
-            account_id,
-            Arc::new(Mutex::new(Tree::new(self.order_levels, self.default_order_leaf))),
-        );
-        self.order_states.insert(account_id, BTreeMap::<u32, Order>::default());
-        self.account_tree.lock().unwrap().set_value(account_id, self.default_account_leaf);
+        let balance_tree = StateTree::with_store(self.balance_levels, Fr::zero(), self.node_store.new_empty());
+        self.balance_trees.insert(account_id, Arc::new(balance_tree));
+        let order_tree = StateTree::with_store(self.order_levels, self.default_order_leaf, self.node_store.new_empty());
+        self.order_trees.insert(account_id, Arc::new(order_tree));
+        self.order_states.insert(account_id, Arc::default());
+        self.free_order_slots.insert(account_id, FreeSlots::default());
+        self.account_tree.set_value(account_id, self.default_account_leaf);
         self.next_order_positions.insert(account_id, next_order_id);
         Ok(account_id)
     }
-    pub fn create_new_account(&mut self, next_order_id: u32) -> anyhow::Result<u32> {
+    pub fn create_new_account(&mut self, next_order_id: u32) -> anyhow::Result<u64> {
         let account_id = self.get_next_account_id()?;
         self.init_account(account_id, next_order_id)
     }
-    pub fn get_order_pos_by_id(&self, account_id: u32, order_id: u32) -> Option<u32> {
+    pub fn get_order_pos_by_id(&self, account_id: u64, order_id: u32) -> Option<u32> {
         self.order_id_to_pos.get(&(account_id, order_id)).cloned()
     }
-    pub fn get_order_id_by_pos(&self, account_id: u32, order_pos: u32) -> Option<u32> {
+    pub fn get_order_id_by_pos(&self, account_id: u64, order_pos: u32) -> Option<u32> {
         self.order_states.get(&account_id).unwrap().get(&order_pos).map(|o| o.account_id)
     }
 
-    pub fn set_account_order(&mut self, account_id: u32, order_pos: u32, order: Order) {
+    pub fn set_account_order(&mut self, account_id: u64, order_pos: u32, order: Order) {
         assert!(self.order_trees.contains_key(&account_id), "set_account_order");
         if order_pos >= 2u32.pow(self.order_levels as u32) {
             panic!("order_pos {} invalid for order_levels {}", order_pos, self.order_levels);
         }
-        self.order_trees
-            .get_mut(&account_id)
-            .unwrap()
-            .lock()
-            .unwrap()
-            .set_value(order_pos, order.hash());
-        self.order_states.get_mut(&account_id).unwrap().insert(order_pos, order);
+        Arc::make_mut(self.order_trees.get_mut(&account_id).unwrap()).set_value(order_pos.into(), order.hash());
+        Arc::make_mut(self.order_states.get_mut(&account_id).unwrap()).insert(order_pos, order);
+        self.refresh_free_slot(account_id, order_pos);
         let order_id: u32 = order.order_id;
         self.order_id_to_pos.insert((account_id, order_id), order_pos);
         self.flush_account_state(account_id);
     }
+    // same as `set_account_order` for each of them, but the order tree and the account leaf are recalculated once
+    pub fn set_account_orders(&mut self, account_id: u64, orders: Vec<(u32, Order)>) {
+        assert!(self.order_trees.contains_key(&account_id), "set_account_orders");
+        let leaves: Vec<(u64, Fr)> = orders
+            .iter()
+            .map(|(order_pos, order)| ((*order_pos).into(), order.hash()))
+            .collect();
+        Arc::make_mut(self.order_trees.get_mut(&account_id).unwrap()).set_values(&leaves);
+        for (order_pos, order) in orders {
+            Arc::make_mut(self.order_states.get_mut(&account_id).unwrap()).insert(order_pos, order);
+            self.refresh_free_slot(account_id, order_pos);
+            self.order_id_to_pos.insert((account_id, order.order_id), order_pos);
+        }
+        self.flush_account_state(account_id);
+    }
 
-    pub fn update_order_state(&mut self, account_id: u32, order_pos: u32, order: Order) {
-        self.order_states.get_mut(&account_id).unwrap().insert(order_pos, order);
+    pub fn update_order_state(&mut self, account_id: u64, order_pos: u32, order: Order) {
+        Arc::make_mut(self.order_states.get_mut(&account_id).unwrap()).insert(order_pos, order);
+        self.refresh_free_slot(account_id, order_pos);
     }
-    pub fn find_or_insert_order(&mut self, account_id: u32, order: &Order) -> (u32, Order) {
+    pub fn find_or_insert_order(&mut self, account_id: u64, order: &Order) -> Result<(u32, Order), OrderTreeFull> {
         let order_id = order.order_id;
         match self.get_order_pos_by_id(account_id, order_id) {
-            Some(pos) => (pos, self.get_account_order_by_pos(account_id, pos)),
+            Some(pos) => Ok((pos, self.get_account_order_by_pos(account_id, pos))),
             None => {
-                let pos = self.get_next_order_pos_for_user(account_id, order_id);
+                let pos = self.get_next_order_pos_for_user(account_id, order_id)?;
                 // old_order may be empty
                 let old_order = self.get_account_order_by_pos(account_id, pos);
                 self.set_order_pos_for_id(account_id, pos, order_id);
-                (pos, old_order)
+                Ok((pos, old_order))
             }
         }
     }
-    fn set_order_pos_for_id(&mut self, account_id: u32, order_pos: u32, order_id: u32) {
+    fn set_order_pos_for_id(&mut self, account_id: u64, order_pos: u32, order_id: u32) {
         assert!(self.order_trees.contains_key(&account_id), "link_order_pos_and_id");
 
         if order_pos >= 2u32.pow(self.order_levels as u32) {
@@ -364,57 +534,81 @@ impl GlobalState {
 
         self.order_id_to_pos.insert((account_id, order_id), order_pos);
     }
-    pub fn set_order_leaf_hash(&mut self, account_id: u32, order_pos: u32, order_hash: Fr) {
+    pub fn set_order_leaf_hash(&mut self, account_id: u64, order_pos: u32, order_hash: Fr) {
         self.set_order_leaf_hash_raw(account_id, order_pos, order_hash);
         self.flush_account_state(account_id);
     }
-    pub fn set_order_leaf_hash_raw(&mut self, account_id: u32, order_pos: u32, order_hash: Fr) {
+    pub fn set_order_leaf_hash_raw(&mut self, account_id: u64, order_pos: u32, order_hash: Fr) {
         assert!(self.order_trees.contains_key(&account_id), "set_order_leaf_hash_raw");
-        let tree = self.order_trees.get_mut(&account_id).unwrap().clone();
-        tree.lock().unwrap().set_value(order_pos, order_hash);
+        Arc::make_mut(self.order_trees.get_mut(&account_id).unwrap()).set_value(order_pos.into(), order_hash);
     }
 
-    pub fn get_token_balance(&self, account_id: u32, token_id: u32) -> Fr {
+    pub fn get_token_balance(&self, account_id: u64, token_id: u32) -> Fr {
         if !self.has_account(account_id) {
             return Fr::zero();
         }
-        self.balance_trees.get(&account_id).unwrap().lock().unwrap().get_leaf(token_id)
+        self.balance_trees.get(&account_id).unwrap().get_leaf(token_id.into())
+    }
+    // non-zero balances of the account, in ascending order of token id
+    pub fn get_token_balances(&self, account_id: u64) -> Vec<(u32, Fr)> {
+        let tree = match self.balance_trees.get(&account_id) {
+            Some(tree) => tree,
+            None => return Vec::new(),
+        };
+        let mut balances: Vec<(u32, Fr)> = tree
+            .iter()
+            .filter(|(_, balance)| !balance.is_zero())
+            .map(|(token_id, balance)| (token_id as u32, balance))
+            .collect();
+        balances.sort_unstable_by_key(|(token_id, _)| *token_id);
+        balances
     }
-    pub fn set_token_balance(&mut self, account_id: u32, token_id: u32, balance: Fr) {
+    pub fn set_token_balance(&mut self, account_id: u64, token_id: u32, balance: Fr) {
         if !self.account_states.contains_key(&account_id) {
             self.init_account(account_id, self.default_next_order_id).unwrap();
         }
         self.set_token_balance_raw(account_id, token_id, balance);
         self.flush_account_state(account_id)
     }
+    pub fn set_token_balances(&mut self, account_id: u64, balances: &[(u32, Fr)]) {
+        if !self.account_states.contains_key(&account_id) {
+            self.init_account(account_id, self.default_next_order_id).unwrap();
+        }
+        let leaves: Vec<(u64, Fr)> = balances.iter().map(|(token_id, balance)| ((*token_id).into(), *balance)).collect();
+        Arc::make_mut(self.balance_trees.get_mut(&account_id).unwrap()).set_values(&leaves);
+        self.flush_account_state(account_id)
+    }
     pub fn batch_update(&mut self, updates: Vec<AccountUpdates>, parallel: bool) {
         if parallel {
             let balance_parallel = 2;
             let order_parallel = 1;
             let account_parallel = 2;
 
-            updates
-                .clone()
-                .into_iter()
-                .map(|update| {
-                    let account_id = update.account_id;
-                    assert!(self.balance_trees.contains_key(&account_id), "set_token_balance");
-                    let balance_tree = self.balance_trees.get_mut(&account_id).unwrap().clone();
-                    let balance_updates = update.balance_updates;
-
-                    assert!(self.order_trees.contains_key(&account_id), "set_order_leaf_hash_raw");
-                    let order_tree = self.order_trees.get_mut(&account_id).unwrap().clone();
-                    let order_updates = update.order_updates;
-
-                    (
-                        (balance_tree, balance_updates, balance_parallel),
-                        (order_tree, order_updates, order_parallel),
-                    )
-                })
-                .flat_map(|(balance, order)| std::array::IntoIter::new([balance, order]))
-                .par_bridge()
+            // the trees shared with a snapshot are copied here, before being updated in parallel
+            let mut balance_updates: FnvHashMap<u64, Vec<(u64, Fr)>> = FnvHashMap::default();
+            let mut order_updates: FnvHashMap<u64, Vec<(u64, Fr)>> = FnvHashMap::default();
+            for update in &updates {
+                assert!(self.balance_trees.contains_key(&update.account_id), "set_token_balance");
+                assert!(self.order_trees.contains_key(&update.account_id), "set_order_leaf_hash_raw");
+                balance_updates.insert(update.account_id, widen_leaf_indices(&update.balance_updates));
+                order_updates.insert(update.account_id, widen_leaf_indices(&update.order_updates));
+            }
+            let balance_jobs = self.balance_trees.iter_mut().filter_map(|(id, tree)| {
+                balance_updates
+                    .remove(id)
+                    .map(|updates| (Arc::make_mut(tree), updates, balance_parallel))
+            });
+            let order_jobs = self.order_trees.iter_mut().filter_map(|(id, tree)| {
+                order_updates
+                    .remove(id)
+                    .map(|updates| (Arc::make_mut(tree), updates, order_parallel))
+            });
+            balance_jobs
+                .chain(order_jobs)
+                .collect::<Vec<_>>()
+                .into_par_iter()
                 .for_each(|(tree, updates, parallel)| {
-                    tree.lock().unwrap().set_value_parallel(updates.as_slice(), parallel);
+                    tree.set_value_parallel(updates.as_slice(), parallel);
                 });
 
             let mut account_updates = vec![];
@@ -425,10 +619,7 @@ impl GlobalState {
                 let account_hash = self.recalculate_account_state_hash(update.account_id);
                 account_updates.push((update.account_id, account_hash));
             }
-            self.account_tree
-                .lock()
-                .unwrap()
-                .set_value_parallel(&account_updates, account_parallel);
+            self.account_tree.set_value_parallel(&account_updates, account_parallel);
         } else {
             for update in updates {
                 let account_id = update.account_id;
@@ -445,16 +636,15 @@ impl GlobalState {
             }
         }
     }
-    pub fn set_token_balance_raw(&mut self, account_id: u32, token_id: u32, balance: Fr) {
+    pub fn set_token_balance_raw(&mut self, account_id: u64, token_id: u32, balance: Fr) {
         assert!(self.balance_trees.contains_key(&account_id), "set_token_balance");
-        let tree = self.balance_trees.get_mut(&account_id).unwrap().clone();
-        tree.lock().unwrap().set_value(token_id, balance);
+        Arc::make_mut(self.balance_trees.get_mut(&account_id).unwrap()).set_value(token_id.into(), balance);
     }
-    pub fn has_order(&self, account_id: u32, order_id: u32) -> bool {
+    pub fn has_order(&self, account_id: u64, order_id: u32) -> bool {
         self.order_id_to_pos.contains_key(&(account_id, order_id))
         //self.order_map.contains_key(&account_id) && self.order_map.get(&account_id).unwrap().contains_key(&order_id)
     }
-    pub fn cancel_order(&mut self, account_id: u32, order_id: u32) {
+    pub fn cancel_order(&mut self, account_id: u64, order_id: u32) {
         let order_pos = self.get_order_pos_by_id(account_id, order_id).unwrap();
         log::debug!(
             "cancel order account_id {} order_id {} order_pos {}",
@@ -462,14 +652,13 @@ impl GlobalState {
             order_id,
             order_pos
         );
-        self.order_states
-            .get_mut(&account_id)
-            .unwrap()
+        Arc::make_mut(self.order_states.get_mut(&account_id).unwrap())
             .get_mut(&order_pos)
             .unwrap()
             .is_active = false;
+        self.refresh_free_slot(account_id, order_pos);
     }
-    fn get_account_order_by_pos(&self, account_id: u32, order_pos: u32) -> Order {
+    fn get_account_order_by_pos(&self, account_id: u64, order_pos: u32) -> Order {
         *self
             .order_states
             .get(&account_id)
@@ -477,7 +666,14 @@ impl GlobalState {
             .get(&order_pos)
             .unwrap_or(&Order::default())
     }
-    pub fn get_account_order_by_id(&self, account_id: u32, order_id: u32) -> Order {
+    // order_pos -> order of all orders stored for the account
+    pub fn get_account_orders(&self, account_id: u64) -> Vec<(u32, Order)> {
+        self.order_states
+            .get(&account_id)
+            .map(|orders| orders.iter().map(|(pos, order)| (*pos, *order)).collect())
+            .unwrap_or_default()
+    }
+    pub fn get_account_order_by_id(&self, account_id: u64, order_id: u32) -> Order {
         assert!(self.has_order(account_id, order_id));
         let order_pos = self.get_order_pos_by_id(account_id, order_id).unwrap();
         self.get_account_order_by_pos(account_id, order_pos)
@@ -486,22 +682,76 @@ impl GlobalState {
     pub fn trivial_order_path_elements(&self) -> Vec<[Fr; 1]> {
         self.trivial_order_path_elements.clone()
     }
-    pub fn order_proof(&self, account_id: u32, order_pos: u32) -> MerkleProof {
-        self.order_trees.get(&account_id).unwrap().lock().unwrap().get_proof(order_pos)
+    pub fn order_proof(&self, account_id: u64, order_pos: u32) -> MerkleProof {
+        self.order_trees.get(&account_id).unwrap().get_proof(order_pos.into())
     }
-    pub fn balance_proof(&self, account_id: u32, token_id: u32) -> MerkleProof {
+    pub fn balance_proof(&self, account_id: u64, token_id: u32) -> MerkleProof {
         if self.balance_trees.contains_key(&account_id) {
-            self.balance_trees.get(&account_id).unwrap().lock().unwrap().get_proof(token_id)
+            self.balance_trees.get(&account_id).unwrap().get_proof(token_id.into())
         } else {
-            self.empty_balance_tree.get_proof(token_id)
+            self.empty_balance_tree.get_proof(token_id.into())
         }
     }
     // get proof if `value` is in the tree without really updating
-    //pub fn balance_proof_with(self, account_id: u32, token_id: u32, value: Fr) -> MerkleProof
-    pub fn account_proof(&self, account_id: u32) -> MerkleProof {
-        self.account_tree.lock().unwrap().get_proof(account_id)
+    //pub fn balance_proof_with(self, account_id: u64, token_id: u32, value: Fr) -> MerkleProof
+    pub fn account_proof(&self, account_id: u64) -> MerkleProof {
+        self.account_tree.get_proof(account_id)
+    }
+    // proves the account slot is still unused, none if it is taken
+    pub fn empty_account_proof(&self, account_id: u64) -> Option<MerkleProof> {
+        self.account_tree.get_default_leaf_proof(account_id)
+    }
+    // the accounts whose leaves differ between the two states, in ascending order
+    pub fn changed_accounts(&self, other: &GlobalState) -> Vec<u64> {
+        self.account_tree
+            .diff(&other.account_tree)
+            .into_iter()
+            .map(|diff| diff.idx)
+            .collect()
+    }
+    pub fn tree_stats(&self) -> StateTreeStats {
+        let mut stats = StateTreeStats {
+            account_tree: self.account_tree.stats(),
+            ..Default::default()
+        };
+        for tree in self.balance_trees.values() {
+            stats.balance_trees.merge(&tree.stats());
+        }
+        for tree in self.order_trees.values() {
+            stats.order_trees.merge(&tree.stats());
+        }
+        stats
     }
-    pub fn balance_full_proof(&self, account_id: u32, token_id: u32) -> BalanceProof {
+    // the balance tree and the order tree of the account added up
+    pub fn account_tree_stats(&self, account_id: u64) -> TreeStats {
+        let mut stats = TreeStats::default();
+        for trees in [&self.balance_trees, &self.order_trees] {
+            if let Some(tree) = trees.get(&account_id) {
+                stats.merge(&tree.stats());
+            }
+        }
+        stats
+    }
+    // the accounts whose trees take the most bytes, largest first
+    pub fn largest_accounts(&self, n: usize) -> Vec<(u64, TreeStats)> {
+        let mut accounts: Vec<(u64, TreeStats)> = self
+            .balance_trees
+            .keys()
+            .map(|account_id| (*account_id, self.account_tree_stats(*account_id)))
+            .collect();
+        accounts.sort_by(|(id1, s1), (id2, s2)| s2.bytes.cmp(&s1.bytes).then(id1.cmp(id2)));
+        accounts.truncate(n);
+        accounts
+    }
+    // drops the default nodes left in the trees, the roots do not change. returns how many are removed
+    pub fn compact_trees(&mut self) -> usize {
+        let mut removed = self.account_tree.compact();
+        for tree in self.balance_trees.values_mut().chain(self.order_trees.values_mut()) {
+            removed += Arc::make_mut(tree).compact();
+        }
+        removed
+    }
+    pub fn balance_full_proof(&self, account_id: u64, token_id: u32) -> BalanceProof {
         let account_proof = self.account_proof(account_id);
         let balance_proof = self.balance_proof(account_id, token_id);
         BalanceProof {
@@ -525,22 +775,30 @@ impl GlobalState {
         let order_trees = db.open_tree(ORDERTREES_KEY)?;
         let order_states = db.open_tree(ORDERSTATES_KEY)?;
         let next_order_positions = db.open_tree(NEXT_ORDER_POSITIONS_KEY)?;
-        let (account_tree, account_states, balance_trees, order_trees, order_states, next_order_positions) = (
+        let free_order_slots = db.open_tree(FREE_ORDER_SLOTS_KEY)?;
+        let legacy = free_order_slots.is_empty();
+        let (account_tree, account_states, balance_trees, order_trees, order_states, next_order_positions, free_order_slots) = (
             &**db,
             &account_states,
             &balance_trees,
             &order_trees,
             &order_states,
             &next_order_positions,
+            &free_order_slots,
         )
             .transaction(
-                |(db, account_states, balance_trees, order_trees, order_states, next_order_positions)| {
+                |(db, account_states, balance_trees, order_trees, order_states, next_order_positions, free_order_slots)| {
                     let account_tree = Self::load_account_tree(db)?;
                     let account_states = Self::load_account_state(&account_tree, account_states)?;
-                    let balance_trees = Self::load_trees::<Arc<Mutex<Tree>>>(&account_states, balance_trees)?;
-                    let order_trees = Self::load_trees::<Arc<Mutex<Tree>>>(&account_states, order_trees)?;
-                    let order_states = Self::load_trees::<BTreeMap<u32, Order>>(&account_states, order_states)?;
+                    let balance_trees = Self::load_trees::<Tree>(&account_states, balance_trees)?;
+                    let order_trees = Self::load_trees::<Tree>(&account_states, order_trees)?;
+                    let order_states = Self::load_trees::<Arc<BTreeMap<u32, Order>>>(&account_states, order_states)?;
                     let next_order_positions = Self::load_trees::<u32>(&account_states, next_order_positions)?;
+                    let free_order_slots = if legacy {
+                        None
+                    } else {
+                        Some(Self::load_trees::<FreeSlots>(&account_states, free_order_slots)?)
+                    };
                     Ok((
                         account_tree,
                         account_states,
@@ -548,13 +806,32 @@ impl GlobalState {
                         order_trees,
                         order_states,
                         next_order_positions,
+                        free_order_slots,
                     ))
                 },
             )?;
-        self.account_tree = Arc::new(Mutex::new(account_tree));
+        // trees of other heights would still load, but give roots the circuit can not prove
+        let heights = [
+            ("account", Some(account_tree.height()), self.account_levels),
+            ("balance", balance_trees.values().next().map(Tree::height), self.balance_levels),
+            ("order", order_trees.values().next().map(Tree::height), self.order_levels),
+        ];
+        for (tree, found, expected) in heights {
+            if let Some(found) = found.filter(|found| *found != expected) {
+                return Err(GlobalStateError::LevelsMismatch { tree, found, expected });
+            }
+        }
+        // the checkpoint is decoded into memory, then the trees are moved onto the configured store one by one
+        let move_trees = |trees: FnvHashMap<u64, Tree>| -> FnvHashMap<u64, Arc<StateTree>> {
+            trees
+                .into_iter()
+                .map(|(id, tree)| (id, Arc::new(tree.copy_into(self.node_store.new_empty()))))
+                .collect()
+        };
+        self.balance_trees = move_trees(balance_trees);
+        self.order_trees = move_trees(order_trees);
+        self.account_tree = account_tree.copy_into(self.node_store.new_empty());
         self.account_states = account_states;
-        self.balance_trees = balance_trees;
-        self.order_trees = order_trees;
         self.order_states = order_states;
         // order_id_to_pos[account_id][order_id] === order_pos and order_states[account_id][order_pos].order_id === order_id
         self.order_id_to_pos = self
@@ -568,9 +845,30 @@ impl GlobalState {
             .flatten()
             .collect();
         self.next_order_positions = next_order_positions;
+        match free_order_slots {
+            Some(free_order_slots) => self.free_order_slots = free_order_slots,
+            None => self.rebuild_free_order_slots(),
+        }
         Ok(())
     }
 
+    // the release order is lost in the old checkpoints, the slots are released again in the order of positions
+    #[cfg(feature = "persist_sled")]
+    fn rebuild_free_order_slots(&mut self) {
+        if self.order_slot_strategy == OrderSlotStrategy::FreeList {
+            log::warn!("free order slots not found in the checkpoint, their order is rebuilt by positions");
+        }
+        self.free_order_slots = FnvHashMap::default();
+        let positions: Vec<(u64, u32)> = self
+            .order_states
+            .iter()
+            .flat_map(|(account_id, orders)| orders.keys().map(move |order_pos| (*account_id, *order_pos)))
+            .collect();
+        for (account_id, order_pos) in positions {
+            self.refresh_free_slot(account_id, order_pos);
+        }
+    }
+
     #[cfg(feature = "persist_sled")]
     fn load_account_tree(db: &TransactionalTree) -> Result<Tree, GlobalStateInternalError> {
         Ok(bincode::deserialize(
@@ -579,40 +877,39 @@ impl GlobalState {
     }
 
     #[cfg(feature = "persist_sled")]
-    fn load_account_state(account_tree: &Tree, db: &TransactionalTree) -> Result<FnvHashMap<u32, AccountState>, GlobalStateInternalError> {
+    fn load_account_state(account_tree: &Tree, db: &TransactionalTree) -> Result<FnvHashMap<u64, AccountState>, GlobalStateInternalError> {
         #[derive(Serialize)]
         struct FrWrapper(#[serde(with = "FrBytes")] Fr);
 
         account_tree
             .iter()
-            .map(|(_id, hash)| match bincode::serialize(&FrWrapper(*hash)) {
+            .map(|(id, hash)| match bincode::serialize(&FrWrapper(hash)) {
                 Ok(key) => db
                     .get(key)
                     .map_err(GlobalStateInternalError::from)
                     .and_then(|v| v.ok_or(GlobalStateInternalError::NotFound))
-                    .and_then(|v| bincode::deserialize::<(u32, AccountState)>(v.as_ref()).map_err(GlobalStateInternalError::from)),
+                    .and_then(|v| decode_account_state(id, v.as_ref()).map_err(GlobalStateInternalError::from))
+                    .map(|state| (id, state)),
                 Err(e) => Err(e.into()),
             })
-            .collect::<Result<FnvHashMap<u32, AccountState>, GlobalStateInternalError>>()
+            .collect::<Result<FnvHashMap<u64, AccountState>, GlobalStateInternalError>>()
     }
 
     #[cfg(feature = "persist_sled")]
     fn load_trees<T: serde::de::DeserializeOwned>(
-        account_states: &FnvHashMap<u32, AccountState>,
+        account_states: &FnvHashMap<u64, AccountState>,
         db: &TransactionalTree,
-    ) -> Result<FnvHashMap<u32, T>, GlobalStateInternalError> {
+    ) -> Result<FnvHashMap<u64, T>, GlobalStateInternalError> {
         account_states
             .iter()
-            .map(|(id, _state)| match bincode::serialize(id) {
-                Ok(key) => db
-                    .get(key)
+            .map(|(id, _state)| {
+                db.get(account_db_key(*id))
                     .map_err(GlobalStateInternalError::from)
                     .and_then(|v| v.ok_or(GlobalStateInternalError::NotFound))
                     .and_then(|v| bincode::deserialize::<T>(v.as_ref()).map_err(GlobalStateInternalError::from))
-                    .map(|tree| (*id, tree)),
-                Err(e) => Err(e.into()),
+                    .map(|tree| (*id, tree))
             })
-            .collect::<Result<FnvHashMap<u32, T>, GlobalStateInternalError>>()
+            .collect::<Result<FnvHashMap<u64, T>, GlobalStateInternalError>>()
     }
 
     #[cfg(feature = "persist_sled")]
@@ -622,6 +919,7 @@ impl GlobalState {
         let order_trees = db.open_tree(ORDERTREES_KEY)?;
         let order_states = db.open_tree(ORDERSTATES_KEY)?;
         let next_order_positions = db.open_tree(NEXT_ORDER_POSITIONS_KEY)?;
+        let free_order_slots = db.open_tree(FREE_ORDER_SLOTS_KEY)?;
         (
             &**db,
             &account_states,
@@ -629,15 +927,17 @@ impl GlobalState {
             &order_trees,
             &order_states,
             &next_order_positions,
+            &free_order_slots,
         )
             .transaction(
-                |(db, account_states, balance_trees, order_trees, order_states, next_order_positions)| {
+                |(db, account_states, balance_trees, order_trees, order_states, next_order_positions, free_order_slots)| {
                     self.save_account_tree(db)?;
                     self.save_account_state(account_states)?;
                     self.save_balance_trees(balance_trees)?;
                     self.save_order_trees(order_trees)?;
                     self.save_order_states(order_states)?;
                     self.save_next_order_positions(next_order_positions)?;
+                    Self::save_serializable_map(free_order_slots, &self.free_order_slots)?;
                     Ok(())
                 },
             )?;
@@ -650,20 +950,20 @@ impl GlobalState {
         struct FrWrapper(#[serde(with = "FrBytes")] Fr);
 
         self.account_states.iter().try_for_each(|(id, state)| {
-            db.insert(bincode::serialize(&FrWrapper(state.hash()))?, bincode::serialize(&(id, state))?)
+            let value = [account_db_key(*id), bincode::serialize(state)?].concat();
+            db.insert(bincode::serialize(&FrWrapper(state.hash()))?, value)
                 .map(|_| ())
                 .map_err(GlobalStateInternalError::from)
         })
     }
 
     #[cfg(feature = "persist_sled")]
-    fn save_serializable_map<K, V>(db: &TransactionalTree, map: &FnvHashMap<K, V>) -> Result<(), GlobalStateInternalError>
+    fn save_serializable_map<V>(db: &TransactionalTree, map: &FnvHashMap<u64, V>) -> Result<(), GlobalStateInternalError>
     where
-        K: Serialize,
         V: Serialize + Clone,
     {
         map.iter().try_for_each(|(id, value)| {
-            db.insert(bincode::serialize(id)?, bincode::serialize(&value.clone())?)
+            db.insert(account_db_key(*id), bincode::serialize(&value.clone())?)
                 .map(|_| ())
                 .map_err(GlobalStateInternalError::from)
         })
@@ -686,8 +986,7 @@ impl GlobalState {
 
     #[cfg(feature = "persist_sled")]
     fn save_account_tree(&self, db: &TransactionalTree) -> Result<(), GlobalStateInternalError> {
-        db.insert(ACCOUNTTREE_KEY, bincode::serialize(&*self.account_tree.clone())?)
-            .map(|_| ())?;
+        db.insert(ACCOUNTTREE_KEY, bincode::serialize(&self.account_tree)?).map(|_| ())?;
         Ok(())
     }
 
@@ -696,3 +995,194 @@ impl GlobalState {
         Self::save_serializable_map(db, &self.next_order_positions)
     }
 }
+
+/// A frozen view of a [`GlobalState`], taken by [`GlobalState::snapshot`]
+pub struct StateSnapshot(GlobalState);
+
+impl StateSnapshot {
+    // the account leaves are recalculated from the account states, which hold the roots of their trees
+    fn rebuild_account_tree(&mut self) {
+        let leaves: Vec<(u64, Fr)> = self.0.account_states.iter().map(|(id, state)| (*id, state.hash())).collect();
+        self.0.account_tree.set_values(&leaves);
+    }
+
+    pub fn into_state(mut self) -> GlobalState {
+        self.rebuild_account_tree();
+        self.0
+    }
+
+    #[cfg(feature = "persist_sled")]
+    pub fn persist(&mut self, db: &sled::Db) -> Result<()> {
+        self.rebuild_account_tree();
+        self.0.persist(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::types::FrExt;
+
+    fn place_order(state: &mut GlobalState, account_id: u64, order_id: u32) -> Result<u32, OrderTreeFull> {
+        let order = Order {
+            account_id: account_id as u32,
+            order_id,
+            total_sell: Fr::from_u32(10),
+            total_buy: Fr::from_u32(10),
+            ..Default::default()
+        };
+        let (pos, _) = state.find_or_insert_order(account_id, &order)?;
+        state.update_order_state(account_id, pos, order);
+        Ok(pos)
+    }
+
+    #[test]
+    fn test_order_tree_full() {
+        let mut state = GlobalState::new(2, 1, 2, false);
+        state.set_order_slot_strategy(OrderSlotStrategy::LruByOrderId);
+        let account_id = state.create_new_account(1).unwrap();
+        assert_eq!(place_order(&mut state, account_id, 1), Ok(0));
+        assert_eq!(place_order(&mut state, account_id, 2), Ok(1));
+        assert!(state.order_tree_occupancy(account_id).is_full());
+        assert_eq!(
+            state.check_order_slot(account_id, 3),
+            Err(OrderTreeFull { account_id, order_id: 3 })
+        );
+
+        state.cancel_order(account_id, 2);
+        state.cancel_order(account_id, 1);
+        assert_eq!(
+            state.order_tree_occupancy(account_id),
+            OrderTreeOccupancy {
+                open: 0,
+                closed: 2,
+                capacity: 2
+            }
+        );
+        // the closed order with the smallest order_id goes first
+        assert_eq!(place_order(&mut state, account_id, 3), Ok(0));
+        assert_eq!(state.order_tree_occupancy(account_id).closed, 1);
+        assert_eq!(
+            state.busiest_order_trees(5),
+            vec![(account_id, state.order_tree_occupancy(account_id))]
+        );
+    }
+
+    #[cfg(feature = "persist_sled")]
+    #[test]
+    fn test_persist_free_order_slots() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        state.set_order_slot_strategy(OrderSlotStrategy::FreeList);
+        let account_id = state.create_new_account(1).unwrap();
+        for order_id in 1..=4 {
+            place_order(&mut state, account_id, order_id).unwrap();
+        }
+        // released in another order than the positions
+        for order_id in [3, 1, 4] {
+            state.cancel_order(account_id, order_id);
+        }
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        state.persist(&db).unwrap();
+
+        let mut loaded = GlobalState::new(2, 2, 2, false);
+        loaded.set_order_slot_strategy(OrderSlotStrategy::FreeList);
+        loaded.load_persist(&db).unwrap();
+        assert_eq!(loaded.free_order_slots, state.free_order_slots);
+        for order_id in 5..=7 {
+            let expected = place_order(&mut state, account_id, order_id);
+            assert_eq!(place_order(&mut loaded, account_id, order_id), expected);
+        }
+        assert_eq!(loaded.root(), state.root());
+    }
+
+    #[cfg(feature = "persist_sled")]
+    #[test]
+    fn test_node_stores() {
+        use crate::types::node_store::SledStore;
+
+        fn add_accounts(state: &mut GlobalState, n: u32) {
+            for i in 0..n {
+                let account_id = state.create_new_account(1).unwrap();
+                state.set_token_balance(account_id, 1, Fr::from_u32(i + 5));
+                state.set_token_balance(account_id, 3, Fr::from_u32(i + 7));
+                place_order(state, account_id, 1).unwrap();
+                place_order(state, account_id, 2).unwrap();
+                state.cancel_order(account_id, 1);
+            }
+        }
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut mem_state = GlobalState::new(2, 2, 3, false);
+        let mut sled_state = GlobalState::new(2, 2, 3, false);
+        add_accounts(&mut mem_state, 2);
+        add_accounts(&mut sled_state, 2);
+        // the existing trees are moved onto the store, the later ones are created there
+        sled_state.set_node_store(StateStore::Sled(SledStore::new(db.open_tree("nodes").unwrap(), 4)));
+        add_accounts(&mut mem_state, 2);
+        add_accounts(&mut sled_state, 2);
+
+        assert_eq!(sled_state.root(), mem_state.root());
+        for account_id in 0..4 {
+            let (proof, expected) = (sled_state.account_proof(account_id), mem_state.account_proof(account_id));
+            assert_eq!(proof.leaf, expected.leaf);
+            assert_eq!(proof.path_elements, expected.path_elements);
+            for idx in 0..4 {
+                assert_eq!(
+                    sled_state.balance_proof(account_id, idx).path_elements,
+                    mem_state.balance_proof(account_id, idx).path_elements
+                );
+                assert_eq!(
+                    sled_state.order_proof(account_id, idx).path_elements,
+                    mem_state.order_proof(account_id, idx).path_elements
+                );
+            }
+        }
+
+        // the snapshot is not changed along with the state, whose written trees are copied
+        let snapshot = sled_state.snapshot();
+        sled_state.set_token_balance(0, 1, Fr::zero());
+        place_order(&mut sled_state, 1, 3).unwrap();
+        let snapshot = snapshot.into_state();
+        assert_eq!(snapshot.root(), mem_state.root());
+        assert_eq!(snapshot.get_account_orders(1), mem_state.get_account_orders(1));
+        assert_ne!(sled_state.root(), snapshot.root());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        let account_id = state.create_new_account(1).unwrap();
+        state.set_token_balance(account_id, 1, Fr::from_u32(5));
+        place_order(&mut state, account_id, 1).unwrap();
+        let root = state.root();
+        let snapshot = state.snapshot();
+        state.set_token_balance(account_id, 1, Fr::from_u32(6));
+        state.cancel_order(account_id, 1);
+
+        let frozen = snapshot.into_state();
+        assert_eq!(frozen.root(), root);
+        assert_eq!(frozen.balance_proof(account_id, 1).leaf, Fr::from_u32(5));
+        assert!(frozen.get_account_orders(account_id)[0].1.is_active);
+        assert_ne!(state.root(), root);
+    }
+
+    #[test]
+    fn test_tree_stats() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        let account1 = state.create_new_account(1).unwrap();
+        let account2 = state.create_new_account(1).unwrap();
+        state.set_token_balance(account1, 1, Fr::from_u32(5));
+        state.set_token_balance(account2, 1, Fr::from_u32(5));
+        state.set_token_balance(account2, 2, Fr::from_u32(5));
+        assert!(state.account_tree_stats(account2).nodes() > state.account_tree_stats(account1).nodes());
+        assert_eq!(state.largest_accounts(1)[0].0, account2);
+
+        // the emptied balance drops its nodes, instead of storing the default ones
+        let root = state.root();
+        state.set_token_balance(account2, 2, Fr::zero());
+        assert_eq!(state.compact_trees(), 0);
+        let stats = state.tree_stats();
+        assert_eq!(stats.balance_trees.nodes(), 2 * state.account_tree_stats(account1).nodes());
+        assert_ne!(state.root(), root);
+    }
+}
@@ -1,26 +1,30 @@
 #![allow(clippy::field_reassign_with_default)]
 #![allow(clippy::vec_init_then_push)]
 
+use super::circuit_params::{self, CircuitParams};
 use super::global::{AccountUpdates, GlobalState};
+use super::order_slots::{OrderTreeFull, OrderTreeOccupancy};
+use super::pubkey_cache::PubkeyCache;
+use super::seal::SealPolicy;
+use crate::config::Settings;
 use crate::types::l2::{
     tx_detail_idx,
     tx_encode::{self, EncodeForScheme},
-    AmountType, DepositTx, FullSpotTradeTx, L2Block, L2BlockDetail, Order, RawTx, TransferTx, TxDataEncoder, TxType, UpdateKeyTx,
-    WithdrawTx, TX_LENGTH,
+    AmountType, BatchTransferTx, DepositTx, FullSpotTradeTx, L2Block, L2BlockDetail, Order, RawTx, TransferTx, TxDataEncoder, TxType,
+    UpdateKeyTx, WithdrawTx, TX_LENGTH,
 };
+use crate::types::matchengine::offsets::{KafkaOffsets, MsgOffset};
 use crate::types::merkle_tree::Tree;
 use anyhow::{anyhow, bail};
-use fluidex_common::babyjubjub_rs::{self, Point};
 use fluidex_common::ff::Field;
 use fluidex_common::l2::account::{L2Account, SignatureBJJ};
 use fluidex_common::{num_bigint::BigInt, num_traits::ToPrimitive};
 use fluidex_common::{types::FrExt, Fr};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "persist_sled")] {
-        use crate::config::Settings;
-        use crate::r#const::sled_db::*;
+        use super::checkpoint::{Checkpoint, Checkpointer};
         use std::time::Instant;
     }
 }
@@ -28,14 +32,36 @@ cfg_if::cfg_if! {
 // TODO: too many unwrap here
 pub struct ManagerWrapper {
     state: Arc<RwLock<GlobalState>>,
+    // the largest block size
     n_tx: usize,
-    // 0 <= len(buffered_txs) < n_tx
+    // supported block sizes in ascending order, the last one is n_tx
+    block_sizes: Vec<usize>,
+    // sealed blocks followed by the pending txs, 0 <= pending txs < n_tx
     buffered_txs: Vec<RawTx>,
+    // sizes of the sealed blocks at the front of buffered_txs
+    sealed_blocks: Vec<usize>,
     block_generate_num: usize,
     //buffered_blocks: Vec<L2Block>,
     tx_data_encoder: TxDataEncoder,
     verbose: bool,
     verify_sig: bool,
+    // check_sig only takes &self
+    pubkeys: Mutex<PubkeyCache>,
+    // offsets of the messages included in the blocks generated so far
+    kafka_offsets: KafkaOffsets,
+    seal_policy: SealPolicy,
+    // timestamp of the latest applied message, in ms
+    msg_time_ms: i64,
+    // msg_time_ms when the first pending tx was seen
+    block_start_ms: Option<i64>,
+    #[cfg(feature = "persist_sled")]
+    checkpointer: Option<Checkpointer>,
+    // offline tools replaying messages must not write checkpoints into the persist dir
+    #[cfg(feature = "persist_sled")]
+    persist_enabled: bool,
+    // the block offset of the latest checkpoint, a checkpoint db can not be written twice
+    #[cfg(feature = "persist_sled")]
+    last_checkpoint: Option<usize>,
 }
 
 fn encode_amount_to_compressed_fr(amount: &AmountType) -> anyhow::Result<Fr> {
@@ -122,6 +148,12 @@ fn encode_rawtx_to_pubdata(tx: &RawTx, encoder: &mut TxDataEncoder) -> anyhow::R
             tx_encode::ForCommonTx(tx).encode(encoder)
         }
 
+        TxType::BatchTransfer => {
+            assert_eq!(payload[tx_detail_idx::DST_IS_NEW], Fr::zero());
+            encoder.encode_heading(3)?; //110
+            tx_encode::ForCommonTx(tx).encode(encoder)
+        }
+
         TxType::Withdraw => {
             encoder.encode_heading(4)?; //001
             tx_encode::ForCommonTx(tx).encode(encoder)
@@ -169,54 +201,143 @@ impl ManagerWrapper {
         Self {
             state,
             n_tx,
+            block_sizes: vec![n_tx],
             buffered_txs: Vec::new(),
+            sealed_blocks: Vec::new(),
             block_generate_num: block_offset.unwrap_or(0),
             //buffered_blocks: Vec::new(),
             tx_data_encoder,
             verbose,
             verify_sig: true,
+            pubkeys: Mutex::new(PubkeyCache::default()),
+            kafka_offsets: KafkaOffsets::default(),
+            seal_policy: SealPolicy::default(),
+            msg_time_ms: 0,
+            block_start_ms: None,
+            #[cfg(feature = "persist_sled")]
+            checkpointer: None,
+            #[cfg(feature = "persist_sled")]
+            persist_enabled: true,
+            #[cfg(feature = "persist_sled")]
+            last_checkpoint: block_offset,
         }
     }
 
+    // restores the offsets recorded in the checkpoint the state is loaded from
+    pub fn set_kafka_offsets(&mut self, kafka_offsets: KafkaOffsets) {
+        self.kafka_offsets = kafka_offsets;
+    }
+
+    pub fn set_seal_policy(&mut self, seal_policy: SealPolicy) {
+        self.seal_policy = seal_policy;
+    }
+
+    // called with the timestamp of every message after it is applied, rejected messages are not counted.
+    // seals the pending txs if they have waited too long
+    pub fn advance_time(&mut self, timestamp_ms: i64) {
+        self.msg_time_ms = self.msg_time_ms.max(timestamp_ms);
+        let pending = self.pending_txs();
+        if pending == 0 {
+            self.block_start_ms = None;
+            return;
+        }
+        let block_start_ms = *self.block_start_ms.get_or_insert(self.msg_time_ms);
+        if self
+            .seal_policy
+            .latency_exceeded(pending, self.n_tx, block_start_ms, self.msg_time_ms)
+        {
+            log::debug!("seal block with {} txs for latency", pending);
+            self.flush_with_nop();
+            self.block_start_ms = None;
+        }
+    }
+
+    // sizes smaller than n_tx are used for sealing partially filled blocks,
+    // n_tx is always supported
+    pub fn set_block_sizes(&mut self, block_sizes: &[usize]) -> anyhow::Result<()> {
+        if let Some(size) = block_sizes.iter().find(|size| **size == 0 || **size > self.n_tx) {
+            bail!("invalid block size {}, should be in 1..={}", size, self.n_tx);
+        }
+        self.block_sizes = circuit_params::block_sizes(self.n_tx, block_sizes);
+        Ok(())
+    }
+
+    // the params of the blocks generated, the circuit version and the fee account are taken from the config
+    pub fn circuit_params(&self) -> CircuitParams {
+        let state = self.state();
+        CircuitParams {
+            ntxs: self.n_tx,
+            balance_levels: state.balance_bits(),
+            order_levels: state.order_bits(),
+            account_levels: state.account_bits(),
+            circuit_version: Settings::try_get().and_then(|settings| settings.circuit_version.clone()),
+            block_sizes: self.block_sizes.clone(),
+            order_slot_strategy: Some(state.order_slot_strategy()),
+            fee_account: Settings::try_get().and_then(|settings| settings.fee_account),
+        }
+    }
+
+    // txs not sealed into a block yet
+    fn pending_txs(&self) -> usize {
+        self.buffered_txs.len() - self.sealed_blocks.iter().sum::<usize>()
+    }
+
+    #[cfg(feature = "persist_sled")]
+    pub fn set_persist_enabled(&mut self, enabled: bool) {
+        self.persist_enabled = enabled;
+    }
+
     /////////////////// forward method call to self.state //////////////////////////////////
     pub fn root(&self) -> Fr {
         self.state().root()
     }
     pub fn has_order(&self, account_id: u32, order_id: u32) -> bool {
-        self.state().has_order(account_id, order_id)
+        self.state().has_order(account_id.into(), order_id)
     }
     pub fn has_account(&self, account_id: u32) -> bool {
-        self.state().has_account(account_id)
+        self.state().has_account(account_id.into())
     }
     pub fn cancel_order(&mut self, account_id: u32, order_id: u32) {
-        self.mut_state().cancel_order(account_id, order_id)
+        self.mut_state().cancel_order(account_id.into(), order_id)
     }
     pub fn get_token_balance(&self, account_id: u32, token_id: u32) -> Fr {
-        self.state().get_token_balance(account_id, token_id)
+        self.state().get_token_balance(account_id.into(), token_id)
     }
     //pub fn update_order_state(&mut self, account_id: u32, order: Order) {
     //    self.state.update_order_state(account_id, order)
     //}
+    // the txs carry u32 account ids, the ids above can only be reached through `GlobalState`
     pub fn create_new_account(&mut self, next_order_id: u32) -> anyhow::Result<u32> {
-        self.mut_state().create_new_account(next_order_id)
+        let account_id = self.mut_state().create_new_account(next_order_id)?;
+        Ok(u32::try_from(account_id)?)
+    }
+    pub fn check_order_slot(&self, account_id: u32, order_id: u32) -> Result<(), OrderTreeFull> {
+        self.state().check_order_slot(account_id.into(), order_id)
+    }
+    pub fn order_tree_occupancy(&self, account_id: u32) -> OrderTreeOccupancy {
+        self.state().order_tree_occupancy(account_id.into())
+    }
+    pub fn busiest_order_trees(&self, n: usize) -> Vec<(u64, OrderTreeOccupancy)> {
+        self.state().busiest_order_trees(n)
     }
     pub fn get_account_order_by_id(&self, account_id: u32, order_id: u32) -> Order {
-        self.state().get_account_order_by_id(account_id, order_id)
+        self.state().get_account_order_by_id(account_id.into(), order_id)
     }
     pub fn set_account_l2_addr(&mut self, account_id: u32, sign: Fr, ay: Fr) {
-        self.mut_state().set_account_l2_addr(account_id, sign, ay);
+        self.mut_state().set_account_l2_addr(account_id.into(), sign, ay);
+        self.pubkeys.lock().unwrap().invalidate(account_id);
     }
     pub fn set_account_nonce(&mut self, account_id: u32, nonce: Fr) {
-        self.mut_state().set_account_nonce(account_id, nonce);
+        self.mut_state().set_account_nonce(account_id.into(), nonce);
     }
     pub fn get_account_nonce(&self, account_id: u32) -> Fr {
-        self.state().get_account_nonce(account_id)
+        self.state().get_account_nonce(account_id.into())
     }
     pub fn set_account_order(&mut self, account_id: u32, order_pos: u32, order: Order) {
-        self.mut_state().set_account_order(account_id, order_pos, order);
+        self.mut_state().set_account_order(account_id.into(), order_pos, order);
     }
     pub fn set_token_balance(&mut self, account_id: u32, token_id: u32, balance: Fr) {
-        self.mut_state().set_token_balance(account_id, token_id, balance);
+        self.mut_state().set_token_balance(account_id.into(), token_id, balance);
     }
 
     pub fn forge_with_txs(block_id: usize, buffered_txs: &[RawTx], encoder: &mut TxDataEncoder) -> L2Block {
@@ -262,6 +383,7 @@ impl ManagerWrapper {
         };
         L2Block {
             block_id,
+            block_size: buffered_txs.len(),
             detail,
             public_data,
         }
@@ -271,19 +393,26 @@ impl ManagerWrapper {
     }
     pub fn add_raw_tx(&mut self, raw_tx: RawTx) {
         self.buffered_txs.push(raw_tx);
+        if self.pending_txs()
+            >= self
+                .seal_policy
+                .capacity(self.n_tx, self.tx_data_encoder.pubdata_len_bits() as usize)
+        {
+            self.flush_with_nop();
+        }
     }
     pub fn get_block_generate_num(&self) -> usize {
         self.block_generate_num
     }
-    pub fn key_update(&mut self, tx: UpdateKeyTx, offset: Option<i64>) -> anyhow::Result<()> {
+    pub fn key_update(&mut self, tx: UpdateKeyTx, offset: Option<MsgOffset>) -> anyhow::Result<()> {
         let mut state = self.mut_state();
-        if state.has_account(tx.account_id) {
+        if state.has_account(tx.account_id.into()) {
             bail!("current update key can only set key for un-inited account");
         }
         let fake_token_id = 0;
-        let proof = state.balance_full_proof(tx.account_id, fake_token_id);
-        let acc = state.get_account(tx.account_id);
-        let old_balance = state.get_token_balance(tx.account_id, fake_token_id);
+        let proof = state.balance_full_proof(tx.account_id.into(), fake_token_id);
+        let acc = state.get_account(tx.account_id.into());
+        let old_balance = state.get_token_balance(tx.account_id.into(), fake_token_id);
         let nonce = acc.nonce;
 
         let mut encoded_tx = [Fr::zero(); TX_LENGTH];
@@ -323,8 +452,9 @@ impl ManagerWrapper {
             offset,
         };
 
-        state.set_token_balance(tx.account_id, fake_token_id, old_balance);
-        state.set_account_l2_addr(tx.account_id, tx.l2key.sign, tx.l2key.ay);
+        state.set_token_balance(tx.account_id.into(), fake_token_id, old_balance);
+        state.set_account_l2_addr(tx.account_id.into(), tx.l2key.sign, tx.l2key.ay);
+        self.pubkeys.lock().unwrap().invalidate(tx.account_id);
         let new_root = state.root();
         drop(state);
         log::debug!("finish update key tx {:?} new root {}", tx, new_root);
@@ -332,20 +462,20 @@ impl ManagerWrapper {
         self.add_raw_tx(raw_tx);
         Ok(())
     }
-    pub fn deposit(&mut self, tx: DepositTx, offset: Option<i64>) -> anyhow::Result<()> {
+    pub fn deposit(&mut self, tx: DepositTx, offset: Option<MsgOffset>) -> anyhow::Result<()> {
         let mut state = self.mut_state();
         //TODO: deposit to new has been deprecated after key_update is induced
         let deposit_to_new = tx.l2key.is_some();
-        if deposit_to_new && state.has_account(tx.account_id) {
+        if deposit_to_new && state.has_account(tx.account_id.into()) {
             bail!("deposit to new, but account already existed");
         }
-        if !deposit_to_new && !state.has_account(tx.account_id) {
+        if !deposit_to_new && !state.has_account(tx.account_id.into()) {
             bail!("deposit to old, but account not existed");
         }
         // assert!(state.accounts.get(tx.account_id).eth_addr != 0n, "deposit_to_old");
-        let proof = state.balance_full_proof(tx.account_id, tx.token_id);
-        let acc = state.get_account(tx.account_id);
-        let old_balance = state.get_token_balance(tx.account_id, tx.token_id);
+        let proof = state.balance_full_proof(tx.account_id.into(), tx.token_id);
+        let acc = state.get_account(tx.account_id.into());
+        let old_balance = state.get_token_balance(tx.account_id.into(), tx.token_id);
         let nonce = acc.nonce;
 
         let mut encoded_tx = [Fr::zero(); TX_LENGTH];
@@ -395,10 +525,11 @@ impl ManagerWrapper {
 
         let mut balance = old_balance;
         balance.add_assign(&Fr::from_bigint(BigInt::from(tx.amount)));
-        state.set_token_balance(tx.account_id, tx.token_id, balance);
+        state.set_token_balance(tx.account_id.into(), tx.token_id, balance);
         if deposit_to_new {
             let l2key = tx.l2key.clone().unwrap();
-            state.set_account_l2_addr(tx.account_id, l2key.sign, l2key.ay);
+            state.set_account_l2_addr(tx.account_id.into(), l2key.sign, l2key.ay);
+            self.pubkeys.lock().unwrap().invalidate(tx.account_id);
         }
 
         let new_root = state.root();
@@ -411,23 +542,63 @@ impl ManagerWrapper {
     }
     pub fn fill_withdraw_tx(&self, tx: &mut WithdrawTx) {
         let state = self.state();
-        tx.nonce = state.get_account(tx.account_id).nonce;
-        tx.old_balance = state.get_token_balance(tx.account_id, tx.token_id);
+        tx.nonce = state.get_account(tx.account_id.into()).nonce;
+        tx.old_balance = state.get_token_balance(tx.account_id.into(), tx.token_id);
+    }
+    pub fn transfer(&mut self, tx: TransferTx, offset: Option<MsgOffset>) {
+        self.transfer_leg(tx, TxType::Transfer, true, offset);
+    }
+    // the whole batch is checked before any leg is applied, so it either succeeds or leaves the state untouched
+    pub fn batch_transfer(&mut self, tx: BatchTransferTx, offset: Option<MsgOffset>) -> anyhow::Result<()> {
+        {
+            let state = self.state();
+            if !state.has_account(tx.from.into()) {
+                bail!("invalid account {}", tx.from);
+            }
+            if tx.legs.is_empty() {
+                bail!("empty batch transfer");
+            }
+            for (to, _) in &tx.legs {
+                if *to == tx.from || !state.has_account((*to).into()) {
+                    bail!("invalid batch transfer target {}", to);
+                }
+            }
+            let total = tx.total_amount().ok_or_else(|| anyhow!("batch transfer amount overflows"))?;
+            let balance = state.get_token_balance(tx.from.into(), tx.token_id);
+            if balance < Fr::from_bigint(BigInt::from(total)) {
+                bail!("batch transfer balance not enough {} < {}", balance, total);
+            }
+        }
+        let last = tx.legs.len() - 1;
+        for (i, (to, amount)) in tx.legs.iter().enumerate() {
+            let leg = TransferTx {
+                from: tx.from,
+                to: *to,
+                token_id: tx.token_id,
+                amount: *amount,
+                from_nonce: tx.from_nonce,
+                sig: tx.sig,
+                l2key: None,
+            };
+            self.transfer_leg(leg, TxType::BatchTransfer, i == last, offset.clone());
+        }
+        Ok(())
     }
-    pub fn transfer(&mut self, tx: TransferTx, offset: Option<i64>) {
+    // every leg of a batch checks the batch signature against the signed nonce, so only the last leg bumps the nonce
+    fn transfer_leg(&mut self, tx: TransferTx, tx_type: TxType, bump_nonce: bool, offset: Option<MsgOffset>) {
         let mut state = self.mut_state();
-        if !state.has_account(tx.from) {
+        if !state.has_account(tx.from.into()) {
             panic!("invalid account {:?}", tx);
         }
 
         let transfer_to_new = tx.l2key.is_some();
-        let proof_from = state.balance_full_proof(tx.from, tx.token_id);
-        let from_account = state.get_account(tx.from);
+        let proof_from = state.balance_full_proof(tx.from.into(), tx.token_id);
+        let from_account = state.get_account(tx.from.into());
         // when transfer_to_new, `to_account` will be an empty account
-        let to_account = state.get_account(tx.to);
+        let to_account = state.get_account(tx.to.into());
 
-        let from_old_balance = state.get_token_balance(tx.from, tx.token_id);
-        let to_old_balance = state.get_token_balance(tx.to, tx.token_id);
+        let from_old_balance = state.get_token_balance(tx.from.into(), tx.token_id);
+        let to_old_balance = state.get_token_balance(tx.to.into(), tx.token_id);
         assert!(
             from_old_balance >= Fr::from_bigint(BigInt::from(tx.amount)),
             "Transfer balance not enough {} < {}",
@@ -470,33 +641,38 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::DST_IS_NEW] = if transfer_to_new { Fr::one() } else { Fr::zero() };
 
         /*
-                state.set_token_balance(tx.from, tx.token_id, from_new_balance);
-                state.increase_nonce(tx.from);
-                state.set_token_balance(tx.to, tx.token_id, to_new_balance);
+                state.set_token_balance(tx.from.into(), tx.token_id, from_new_balance);
+                state.increase_nonce(tx.from.into());
+                state.set_token_balance(tx.to.into(), tx.token_id, to_new_balance);
         */
         let acc1_updates = AccountUpdates {
-            account_id: tx.from,
+            account_id: tx.from.into(),
             balance_updates: vec![(tx.token_id, from_new_balance)],
-            new_nonce: Some(state.get_account_nonce(tx.from).add(&Fr::one())),
+            new_nonce: if bump_nonce {
+                Some(state.get_account_nonce(tx.from.into()).add(&Fr::one()))
+            } else {
+                None
+            },
             ..Default::default()
         };
         let acc2_updates = AccountUpdates {
-            account_id: tx.to,
+            account_id: tx.to.into(),
             balance_updates: vec![(tx.token_id, to_new_balance)],
             ..Default::default()
         };
         state.batch_update(vec![acc1_updates, acc2_updates], true);
 
-        let proof_to = state.balance_full_proof(tx.to, tx.token_id);
+        let proof_to = state.balance_full_proof(tx.to.into(), tx.token_id);
 
         if transfer_to_new {
             // transfer_to_new is rarely used
             let l2key = tx.l2key.unwrap();
-            state.set_account_l2_addr(tx.to, l2key.sign, l2key.ay);
+            state.set_account_l2_addr(tx.to.into(), l2key.sign, l2key.ay);
+            self.pubkeys.lock().unwrap().invalidate(tx.to);
         }
 
         let raw_tx = RawTx {
-            tx_type: TxType::Transfer,
+            tx_type,
             payload: encoded_tx.to_vec(),
             balance_path0: proof_from.balance_path.clone(),
             balance_path1: proof_to.balance_path.clone(),
@@ -516,15 +692,15 @@ impl ManagerWrapper {
         drop(state);
         self.add_raw_tx(raw_tx);
     }
-    pub fn withdraw(&mut self, tx: WithdrawTx, offset: Option<i64>) {
+    pub fn withdraw(&mut self, tx: WithdrawTx, offset: Option<MsgOffset>) {
         // assert(this.accounts.get(tx.accountID).ethAddr != 0n, 'Withdraw');
         let account_id = tx.account_id;
         let token_id = tx.token_id;
         let mut state = self.mut_state();
-        let proof = state.balance_full_proof(account_id, token_id);
+        let proof = state.balance_full_proof(account_id.into(), token_id);
 
-        let acc = state.get_account(account_id);
-        let old_balance = state.get_token_balance(account_id, token_id);
+        let acc = state.get_account(account_id.into());
+        let old_balance = state.get_token_balance(account_id.into(), token_id);
         let new_balance = old_balance.sub(&Fr::from_bigint(BigInt::from(tx.amount)));
         let nonce = acc.nonce;
         // assert(oldBalance > tx.amount, 'Withdraw balance');
@@ -575,8 +751,8 @@ impl ManagerWrapper {
             offset,
         };
 
-        state.set_token_balance(account_id, token_id, new_balance);
-        state.increase_nonce(account_id);
+        state.set_token_balance(account_id.into(), token_id, new_balance);
+        state.increase_nonce(account_id.into());
         raw_tx.root_after = state.root();
         drop(state);
 
@@ -588,7 +764,7 @@ impl ManagerWrapper {
     // case3: old order has same order id, we will modify it
     // tx.xxx_order is_none: xxx_order should be already put into the GlobalState tree
     // tx.xxx_order is_some: xxx_order should be new for the GlobalState
-    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx, offset: Option<i64>) {
+    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx, offset: Option<MsgOffset>) {
         // Step1: basic tx check
         // check account ids exist
         let trade = full_tx.trade;
@@ -598,49 +774,58 @@ impl ManagerWrapper {
             panic!("self trade no allowed");
         }
         let mut state = self.mut_state();
-        assert!(state.has_account(acc_id1));
-        assert!(state.has_account(acc_id2));
+        assert!(state.has_account(acc_id1.into()));
+        assert!(state.has_account(acc_id2.into()));
 
         // Step2: retrive old state first for later use
 
         let old_root = state.root();
-        let proof_order1_seller = state.balance_full_proof(acc_id1, trade.token_id_1to2);
-        let proof_order2_seller = state.balance_full_proof(acc_id2, trade.token_id_2to1);
+        let proof_order1_seller = state.balance_full_proof(acc_id1.into(), trade.token_id_1to2);
+        let proof_order2_seller = state.balance_full_proof(acc_id2.into(), trade.token_id_2to1);
 
-        let account1 = state.get_account(acc_id1);
+        let account1 = state.get_account(acc_id1.into());
         let order_root0 = account1.order_root;
-        let account2 = state.get_account(acc_id2);
+        let account2 = state.get_account(acc_id2.into());
 
         // Step3: handle new order
         let mut order1 = if let Some(maker_order) = full_tx.maker_order {
             // new order
-            assert!(!state.has_order(maker_order.account_id, maker_order.order_id));
+            assert!(!state.has_order(maker_order.account_id.into(), maker_order.order_id));
             assert_eq!(maker_order.filled_buy, Fr::zero());
             assert_eq!(maker_order.filled_sell, Fr::zero());
             // state.update_order_state(maker_order.account_id, maker_order);
             maker_order
         } else {
             // order1 means maker, order2 means taker
-            assert!(state.has_order(acc_id1, trade.order1_id), "unknown order1 {}", trade.order1_id);
-            state.get_account_order_by_id(acc_id1, trade.order1_id)
+            assert!(
+                state.has_order(acc_id1.into(), trade.order1_id),
+                "unknown order1 {}",
+                trade.order1_id
+            );
+            state.get_account_order_by_id(acc_id1.into(), trade.order1_id)
         };
 
         let mut order2 = if let Some(taker_order) = full_tx.taker_order {
             // new order
-            assert!(!state.has_order(taker_order.account_id, taker_order.order_id));
+            assert!(!state.has_order(taker_order.account_id.into(), taker_order.order_id));
             assert_eq!(taker_order.filled_buy, Fr::zero());
             assert_eq!(taker_order.filled_sell, Fr::zero());
             // state.update_order_state(taker_order.account_id, taker_order);
             taker_order
         } else {
-            assert!(state.has_order(acc_id2, trade.order2_id), "unknown order2 {}", trade.order2_id);
-            state.get_account_order_by_id(acc_id2, trade.order2_id)
+            assert!(
+                state.has_order(acc_id2.into(), trade.order2_id),
+                "unknown order2 {}",
+                trade.order2_id
+            );
+            state.get_account_order_by_id(acc_id2.into(), trade.order2_id)
         };
 
         // old_order1 is same as old_order1_in_tree when case3
         // not same when case1 and case2
-        let (order1_pos, old_order1_in_tree) = state.find_or_insert_order(acc_id1, &order1);
-        let (order2_pos, old_order2_in_tree) = state.find_or_insert_order(acc_id2, &order2);
+        // the processor rejects the trades whose orders do not fit with `check_order_slot` beforehand
+        let (order1_pos, old_order1_in_tree) = state.find_or_insert_order(acc_id1.into(), &order1).unwrap();
+        let (order2_pos, old_order2_in_tree) = state.find_or_insert_order(acc_id2.into(), &order2).unwrap();
 
         // first, generate the tx
 
@@ -684,16 +869,16 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::ORDER1_POS] = Fr::from_u32(order1_pos);
         encoded_tx[tx_detail_idx::ORDER2_POS] = Fr::from_u32(order2_pos);
 
-        let acc1_balance_sell = state.get_token_balance(acc_id1, trade.token_id_1to2);
+        let acc1_balance_sell = state.get_token_balance(acc_id1.into(), trade.token_id_1to2);
         assert!(acc1_balance_sell > trade.amount_1to2, "balance_1to2");
         let acc1_balance_sell_new = acc1_balance_sell.sub(&trade.amount_1to2);
-        let acc1_balance_buy = state.get_token_balance(acc_id1, trade.token_id_2to1);
+        let acc1_balance_buy = state.get_token_balance(acc_id1.into(), trade.token_id_2to1);
         let acc1_balance_buy_new = acc1_balance_buy.add(&trade.amount_2to1);
 
-        let acc2_balance_sell = state.get_token_balance(acc_id2, trade.token_id_2to1);
+        let acc2_balance_sell = state.get_token_balance(acc_id2.into(), trade.token_id_2to1);
         assert!(acc2_balance_sell > trade.amount_2to1, "balance_2to1");
         let acc2_balance_sell_new = acc2_balance_sell.sub(&trade.amount_2to1);
-        let acc2_balance_buy = state.get_token_balance(acc_id2, trade.token_id_1to2);
+        let acc2_balance_buy = state.get_token_balance(acc_id2.into(), trade.token_id_1to2);
         let acc2_balance_buy_new = acc2_balance_buy.add(&trade.amount_1to2);
 
         encoded_tx[tx_detail_idx::BALANCE1] = acc1_balance_sell;
@@ -713,8 +898,8 @@ impl ManagerWrapper {
             balance_path1: Default::default(),
             balance_path2: proof_order2_seller.balance_path,
             balance_path3: Default::default(),
-            order_path0: state.order_proof(acc_id1, order1_pos).path_elements,
-            order_path1: state.order_proof(acc_id2, order2_pos).path_elements,
+            order_path0: state.order_proof(acc_id1.into(), order1_pos).path_elements,
+            order_path1: state.order_proof(acc_id2.into(), order2_pos).path_elements,
             order_root0,
             order_root1: Default::default(),
             account_path0: proof_order1_seller.account_path,
@@ -725,12 +910,12 @@ impl ManagerWrapper {
         };
 
         order1.trade_with(&trade.amount_1to2, &trade.amount_2to1);
-        state.update_order_state(acc_id1, order1_pos, order1);
+        state.update_order_state(acc_id1.into(), order1_pos, order1);
         order2.trade_with(&trade.amount_2to1, &trade.amount_1to2);
-        state.update_order_state(acc_id2, order2_pos, order2);
+        state.update_order_state(acc_id2.into(), order2_pos, order2);
 
         let acc1_updates = AccountUpdates {
-            account_id: acc_id1,
+            account_id: acc_id1.into(),
             balance_updates: vec![
                 (trade.token_id_1to2, acc1_balance_sell_new),
                 (trade.token_id_2to1, acc1_balance_buy_new),
@@ -739,7 +924,7 @@ impl ManagerWrapper {
             ..Default::default()
         };
         let acc2_updates = AccountUpdates {
-            account_id: acc_id2,
+            account_id: acc_id2.into(),
             balance_updates: vec![
                 (trade.token_id_1to2, acc2_balance_buy_new),
                 (trade.token_id_2to1, acc2_balance_sell_new),
@@ -749,10 +934,10 @@ impl ManagerWrapper {
         };
         state.batch_update(vec![acc1_updates, acc2_updates], true);
 
-        raw_tx.balance_path3 = state.balance_proof(acc_id1, trade.token_id_2to1).path_elements;
-        raw_tx.balance_path1 = state.balance_proof(acc_id2, trade.token_id_1to2).path_elements;
-        raw_tx.account_path1 = state.account_proof(acc_id2).path_elements;
-        raw_tx.order_root1 = state.get_account(acc_id2).order_root;
+        raw_tx.balance_path3 = state.balance_proof(acc_id1.into(), trade.token_id_2to1).path_elements;
+        raw_tx.balance_path1 = state.balance_proof(acc_id2.into(), trade.token_id_1to2).path_elements;
+        raw_tx.account_path1 = state.account_proof(acc_id2.into()).path_elements;
+        raw_tx.order_root1 = state.get_account(acc_id2.into()).order_root;
 
         encoded_tx[tx_detail_idx::NEW_ORDER1_ID] = Fr::from_u32(order1.order_id);
         encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_SELL] = order1.token_sell;
@@ -781,11 +966,16 @@ impl ManagerWrapper {
     }
 
     pub fn nop(&mut self) {
+        let raw_tx = self.nop_raw_tx();
+        self.add_raw_tx(raw_tx);
+    }
+
+    fn nop_raw_tx(&self) -> RawTx {
         // assume we already have initialized the account tree and the balance tree
         let state = self.state();
         let trivial_proof = state.trivial_state_proof();
         let encoded_tx = [Fr::zero(); TX_LENGTH];
-        let raw_tx = RawTx {
+        RawTx {
             tx_type: TxType::Nop,
             payload: encoded_tx.to_vec(),
             balance_path0: trivial_proof.balance_path.clone(),
@@ -801,86 +991,125 @@ impl ManagerWrapper {
             root_before: state.root(),
             root_after: state.root(),
             offset: None,
-        };
-        drop(state);
-        self.add_raw_tx(raw_tx);
+        }
     }
 
+    // seals the pending txs into the smallest block size fitting them
     pub fn flush_with_nop(&mut self) {
-        let mut cnt = 0;
-        while self.buffered_txs.len() % self.n_tx != 0 {
-            self.nop();
-            cnt += 1;
+        let pending = self.pending_txs();
+        if pending == 0 {
+            return;
         }
-        log::debug!("flush with {} nop", cnt);
+        let size = *self.block_sizes.iter().find(|size| **size >= pending).unwrap();
+        let cnt = size - pending;
+        for _ in 0..cnt {
+            let raw_tx = self.nop_raw_tx();
+            self.buffered_txs.push(raw_tx);
+        }
+        self.sealed_blocks.push(size);
+        log::debug!("flush into block size {} with {} nop", size, cnt);
     }
 
     pub fn check_sig(&self, account_id: u32, msg: &Fr, sig: &SignatureBJJ) -> anyhow::Result<()> {
         let state = self.state();
-        if !state.has_account(account_id) {
+        if !state.has_account(account_id.into()) {
             bail!("account not found");
         }
-        let acc = state.get_account(account_id);
-        // TODO: it is stupid to recover point every time...
-        let pk = babyjubjub_rs::recover_point(acc.ay.to_bigint(), acc.sign != Fr::zero());
-        let pub_key: Point = pk.map_err(|e| anyhow!(e))?;
+        let acc = state.get_account(account_id.into());
+        let pub_key = self.pubkeys.lock().unwrap().get(account_id, acc.sign, acc.ay)?;
         if !L2Account::verify_raw_using_pubkey(*msg, sig.clone(), pub_key) {
             bail!("verify sig failed");
         }
         Ok(())
     }
 
+    pub fn has_l2_key(&self, account_id: u32, sign: &Fr, ay: &Fr) -> bool {
+        let state = self.state();
+        if !state.has_account(account_id.into()) {
+            return false;
+        }
+        let acc = state.get_account(account_id.into());
+        acc.sign == *sign && acc.ay == *ay
+    }
+
     pub fn pop_all_blocks(&mut self) -> Vec<L2Block> {
         let mut blocks = vec![];
         let mut i = 0;
-        let len = self.buffered_txs.len();
-        while i + self.n_tx <= len {
-            let block = Self::forge_with_txs(
-                self.block_generate_num,
-                &self.buffered_txs[i..i + self.n_tx],
-                &mut self.tx_data_encoder,
-            );
+        for size in std::mem::take(&mut self.sealed_blocks) {
+            let block = Self::forge_with_txs(self.block_generate_num, &self.buffered_txs[i..i + size], &mut self.tx_data_encoder);
             blocks.push(block);
 
+            for offset in self.buffered_txs[i..i + size].iter().filter_map(|tx| tx.offset.as_ref()) {
+                self.kafka_offsets.update(offset);
+            }
             self.block_generate_num += 1;
 
             #[cfg(feature = "persist_sled")]
             // TODO: fix unwrap
-            if self.block_generate_num % Settings::persist_every_n_block() == 0 {
-                self.persist(i)
+            if self.persist_enabled && self.block_generate_num % Settings::persist_every_n_block() == 0 {
+                if log::log_enabled!(log::Level::Debug) {
+                    let offsets: Vec<Option<&MsgOffset>> = self.buffered_txs[i..i + size].iter().map(|tx| tx.offset.as_ref()).collect();
+                    log::debug!("block #{}, offsets: {:?}", self.block_generate_num, offsets);
+                }
+                self.persist()
             }
 
-            i += self.n_tx;
+            i += size;
         }
         self.buffered_txs.drain(0..i);
         blocks
     }
 
+    // takes a snapshot of the state and hands it over to the background checkpointer. the snapshot shares the trees
+    // with the state, so the processing thread only pays for copying the trees it writes before the dump completes
     #[cfg(feature = "persist_sled")]
-    fn persist(&mut self, i: usize) {
-        log::info!("start to dump #{}", self.block_generate_num);
+    fn persist(&mut self) {
         let start = Instant::now();
-        let last_offset = self.buffered_txs[i..i + self.n_tx].iter().rev().filter_map(|tx| tx.offset).next();
-        if log::log_enabled!(log::Level::Debug) {
-            let offsets: Vec<Option<i64>> = self.buffered_txs[i..i + self.n_tx].iter().map(|tx| tx.offset).collect();
-            log::debug!("block #{}, offsets: {:?}", self.block_generate_num, offsets);
-        }
-        if last_offset.is_none() {
-            log::warn!("kafka offset not exist, is this block belongs to a test_case?")
-        }
-        let db_path = Settings::persist_dir().join(format!("{}.db", self.block_generate_num));
-        let db = sled::open(db_path).unwrap();
-        db.insert(BLOCK_OFFSET_KEY, bincode::serialize(&self.block_generate_num).unwrap())
-            .unwrap();
-        db.insert(KAFKA_OFFSET_KEY, bincode::serialize(&last_offset.unwrap()).unwrap())
-            .unwrap();
-        self.dump_to_sled(&db).unwrap();
-        let elapsed = Instant::now() - start;
+        let checkpoint = Checkpoint {
+            block_offset: self.block_generate_num,
+            kafka_offsets: self.kafka_offsets.clone(),
+            circuit_params: self.circuit_params(),
+            state: self.state().snapshot(),
+        };
         log::info!(
-            "dump #{} completed, duration: {:.3}s",
+            "snapshot #{} taken, duration: {:.3}s",
             self.block_generate_num,
-            elapsed.as_secs_f32()
-        )
+            start.elapsed().as_secs_f32()
+        );
+        self.checkpointer
+            .get_or_insert_with(|| Checkpointer::new(Settings::persist_dir().to_path_buf()))
+            .submit(checkpoint);
+        self.last_checkpoint = Some(self.block_generate_num);
+    }
+
+    // takes a checkpoint at the current block unless there is one already, used on shutdown.
+    // the state includes the txs not popped as blocks yet while the kafka offsets do not, so there must be none
+    #[cfg(feature = "persist_sled")]
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        if self.has_raw_tx() {
+            bail!("{} txs are not popped as blocks yet", self.buffered_txs.len());
+        }
+        if self.persist_enabled && self.last_checkpoint != Some(self.block_generate_num) {
+            self.persist();
+        }
+        Ok(())
+    }
+
+    // blocks until all the pending checkpoints are written
+    #[cfg(feature = "persist_sled")]
+    pub fn wait_checkpoints(&mut self) {
+        if let Some(checkpointer) = self.checkpointer.take() {
+            checkpointer.join();
+        }
+    }
+
+    // the kafka offsets of the latest checkpoint written since the last call, the wal before them can be dropped
+    pub fn take_checkpointed_offsets(&mut self) -> Option<KafkaOffsets> {
+        #[cfg(feature = "persist_sled")]
+        if let Some(checkpointer) = &self.checkpointer {
+            return checkpointer.take_written_offsets();
+        }
+        None
     }
 
     #[cfg(feature = "persist_sled")]
@@ -1004,4 +1233,67 @@ mod test {
         assert_eq!(blks[1].detail.txdata_hash.low_u128(), 229380481089431957009116204147712640854u128);
         assert_eq!(blks[2].detail.txdata_hash.low_u128(), 16562419241364283837688117385709745071u128);
     }
+
+    #[test]
+    fn test_block_sizes() {
+        let gs = GlobalState::new(3, 4, 4, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 4, None, false);
+        #[cfg(feature = "persist_sled")]
+        wrapper.set_persist_enabled(false);
+        wrapper.set_block_sizes(&[0]).expect_err("zero block size");
+        wrapper.set_block_sizes(&[8]).expect_err("larger than n_tx");
+        wrapper.set_block_sizes(&[2, 1]).unwrap();
+
+        wrapper.nop();
+        wrapper.flush_with_nop();
+        wrapper.nop();
+        wrapper.nop();
+        wrapper.nop();
+        wrapper.flush_with_nop();
+        wrapper.nop();
+        wrapper.nop();
+        wrapper.nop();
+        wrapper.nop();
+        let blks = wrapper.pop_all_blocks();
+        assert_eq!(blks.iter().map(|blk| blk.block_size).collect::<Vec<_>>(), vec![1, 4, 4]);
+        assert_eq!(blks.iter().map(|blk| blk.block_id).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(!wrapper.has_raw_tx());
+    }
+
+    #[test]
+    fn test_batch_transfer() {
+        let mut gs = GlobalState::new(3, 4, 4, false);
+        for i in 0..3 {
+            let account_id = gs.create_new_account(1).unwrap();
+            gs.set_account_l2_addr(account_id, Fr::one(), Fr::from_u32(i + 1));
+        }
+        gs.set_token_balance(0, 1, Fr::from_u32(100));
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 4, None, false);
+        #[cfg(feature = "persist_sled")]
+        wrapper.set_persist_enabled(false);
+        let root = wrapper.root();
+
+        wrapper
+            .batch_transfer(BatchTransferTx::new(0, 1, vec![(1, 60), (2, 41)]), None)
+            .expect_err("balance not enough for the whole batch");
+        wrapper
+            .batch_transfer(BatchTransferTx::new(0, 1, vec![(1, 60), (3, 1)]), None)
+            .expect_err("unknown account");
+        assert_eq!(wrapper.root(), root);
+        assert!(!wrapper.has_raw_tx());
+
+        wrapper
+            .batch_transfer(BatchTransferTx::new(0, 1, vec![(1, 60), (2, 40)]), None)
+            .unwrap();
+        assert_eq!(wrapper.get_token_balance(0, 1), Fr::zero());
+        assert_eq!(wrapper.get_token_balance(2, 1), Fr::from_u32(40));
+        assert_eq!(wrapper.get_account_nonce(0), Fr::one());
+        assert_eq!(wrapper.pending_txs(), 2);
+        // every leg is checked against the signed nonce, which is bumped once by the last leg
+        for tx in &wrapper.buffered_txs {
+            assert_eq!(tx.tx_type, TxType::BatchTransfer);
+            assert_eq!(tx.payload[tx_detail_idx::ENABLE_SIG_CHECK1], Fr::one());
+            assert_eq!(tx.payload[tx_detail_idx::NONCE1], Fr::zero());
+        }
+    }
 }
@@ -1,7 +1,19 @@
 pub mod account;
+#[cfg(feature = "persist_sled")]
+pub mod checkpoint;
+pub mod circuit_params;
+pub mod genesis;
 pub mod global;
 pub mod manager_wrapper;
+pub mod order_slots;
+pub mod pubkey_cache;
+pub mod seal;
 
 pub use account::AccountState;
-pub use global::GlobalState;
+pub use circuit_params::CircuitParams;
+pub use genesis::Genesis;
+pub use global::{GlobalState, StateSnapshot};
 pub use manager_wrapper::ManagerWrapper;
+pub use order_slots::{OrderSlotStrategy, OrderTreeFull, OrderTreeOccupancy};
+pub use pubkey_cache::PubkeyCache;
+pub use seal::SealPolicy;
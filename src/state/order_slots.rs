@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// how a slot is picked for a new order once every leaf of the order tree is taken.
+// only filled or cancelled orders can be replaced, and only by an order with a larger order_id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSlotStrategy {
+    // probe the leaves one by one, starting after the last replaced one
+    LinearProbe,
+    // reuse the slots in the order they were filled or cancelled
+    FreeList,
+    // replace the closed order with the smallest order_id
+    LruByOrderId,
+}
+
+impl Default for OrderSlotStrategy {
+    fn default() -> Self {
+        Self::LinearProbe
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("order tree of account {account_id} is full, no closed order can be replaced by order {order_id}")]
+pub struct OrderTreeFull {
+    pub account_id: u64,
+    pub order_id: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OrderTreeOccupancy {
+    // leaves holding an open order
+    pub open: usize,
+    // leaves holding a filled or cancelled order, which can be replaced
+    pub closed: usize,
+    pub capacity: u32,
+}
+
+impl OrderTreeOccupancy {
+    pub fn used(&self) -> usize {
+        self.open + self.closed
+    }
+    pub fn is_full(&self) -> bool {
+        self.open >= self.capacity as usize
+    }
+}
+
+// the replaceable slots of an order tree, indexed for every strategy.
+// the release seqs can not be told from the orders, so it is persisted in the checkpoints as is
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FreeSlots {
+    // release seq -> order_pos
+    released: BTreeMap<u64, u32>,
+    // order_pos -> (release seq, order_id)
+    slots: BTreeMap<u32, (u64, u32)>,
+    // (order_id, order_pos)
+    by_order_id: BTreeSet<(u32, u32)>,
+    next_seq: u64,
+}
+
+impl FreeSlots {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+    // a slot released twice keeps its first position in the free list
+    pub fn release(&mut self, order_pos: u32, order_id: u32) {
+        if let Some((_, old_order_id)) = self.slots.get(&order_pos) {
+            if *old_order_id == order_id {
+                return;
+            }
+            self.take(order_pos);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.released.insert(seq, order_pos);
+        self.slots.insert(order_pos, (seq, order_id));
+        self.by_order_id.insert((order_id, order_pos));
+    }
+    pub fn take(&mut self, order_pos: u32) {
+        if let Some((seq, order_id)) = self.slots.remove(&order_pos) {
+            self.released.remove(&seq);
+            self.by_order_id.remove(&(order_id, order_pos));
+        }
+    }
+    // `LinearProbe` does not use the index, it is handled by the caller
+    pub fn pick(&self, strategy: OrderSlotStrategy, order_id: u32) -> Option<u32> {
+        match strategy {
+            OrderSlotStrategy::LinearProbe => None,
+            OrderSlotStrategy::FreeList => self.released.values().find(|pos| self.slots[pos].1 < order_id).cloned(),
+            OrderSlotStrategy::LruByOrderId => self
+                .by_order_id
+                .iter()
+                .next()
+                .filter(|(old_order_id, _)| *old_order_id < order_id)
+                .map(|(_, pos)| *pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_free_slot() {
+        let mut slots = FreeSlots::default();
+        slots.release(3, 10);
+        slots.release(1, 7);
+        slots.release(2, 12);
+        assert_eq!(slots.pick(OrderSlotStrategy::FreeList, 20), Some(3));
+        assert_eq!(slots.pick(OrderSlotStrategy::LruByOrderId, 20), Some(1));
+        // only older orders can be replaced
+        assert_eq!(slots.pick(OrderSlotStrategy::FreeList, 9), Some(1));
+        assert_eq!(slots.pick(OrderSlotStrategy::LruByOrderId, 7), None);
+
+        slots.take(3);
+        slots.release(1, 7);
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots.pick(OrderSlotStrategy::FreeList, 20), Some(1));
+        slots.take(1);
+        slots.take(2);
+        assert!(slots.is_empty());
+        assert_eq!(slots.pick(OrderSlotStrategy::FreeList, 20), None);
+    }
+}
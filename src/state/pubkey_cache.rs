@@ -0,0 +1,43 @@
+use anyhow::{anyhow, Result};
+use fluidex_common::babyjubjub_rs::{self, Point};
+use fluidex_common::ff::Field;
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use std::collections::HashMap;
+
+// decompressed l2 pubkeys by account, recovering the point is much slower than verifying a sig with it.
+// an entry is only used while the account still has the key it was recovered from
+#[derive(Default)]
+pub struct PubkeyCache {
+    keys: HashMap<u32, CachedKey>,
+}
+
+struct CachedKey {
+    sign: Fr,
+    ay: Fr,
+    point: Point,
+}
+
+impl PubkeyCache {
+    pub fn get(&mut self, account_id: u32, sign: Fr, ay: Fr) -> Result<Point> {
+        if let Some(key) = self.keys.get(&account_id) {
+            if key.sign == sign && key.ay == ay {
+                return Ok(key.point.clone());
+            }
+        }
+        let point = babyjubjub_rs::recover_point(ay.to_bigint(), sign != Fr::zero()).map_err(|e| anyhow!(e))?;
+        self.keys.insert(
+            account_id,
+            CachedKey {
+                sign,
+                ay,
+                point: point.clone(),
+            },
+        );
+        Ok(point)
+    }
+
+    pub fn invalidate(&mut self, account_id: u32) {
+        self.keys.remove(&account_id);
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Deserialize;
+
+// decides when the pending txs are sealed into a block, the rest of the block is padded with nops.
+// the decisions only depend on the messages, times are taken from the message timestamps instead of
+// the wall clock, so replaying the same messages always produces the same blocks.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SealPolicy {
+    // seal once this many txs are pending, the block size is always the upper bound
+    pub max_txs: Option<usize>,
+    // seal once the first pending tx is older than this, compared with the timestamp of the latest applied message
+    pub max_latency_ms: Option<u64>,
+    // every tx takes the same number of pubdata bits, so this is also an upper bound of the pending txs
+    pub max_pubdata_bits: Option<usize>,
+    // sealing because of the latency only happens if at least this fraction of the block is filled
+    pub min_fill_ratio: f64,
+    // also seal after receiving nothing for this long, so the pending txs are not held back once the traffic stops.
+    // it depends on the wall clock, so the blocks generated by a replay may differ from the ones generated online.
+    // off unless set explicitly, the messages alone decide the blocks by default
+    pub idle_timeout_secs: Option<u64>,
+    // seal the pending txs into a padded block on shutdown, so they are saved in the db and the final checkpoint.
+    // otherwise they are applied again from the wal after restarting
+    pub on_shutdown: bool,
+}
+
+impl Default for SealPolicy {
+    fn default() -> Self {
+        Self {
+            max_txs: None,
+            max_latency_ms: None,
+            max_pubdata_bits: None,
+            min_fill_ratio: 0.0,
+            idle_timeout_secs: None,
+            on_shutdown: false,
+        }
+    }
+}
+
+impl SealPolicy {
+    // the number of pending txs which triggers sealing
+    pub fn capacity(&self, n_tx: usize, tx_bits: usize) -> usize {
+        let by_txs = self.max_txs.unwrap_or(n_tx);
+        let by_bits = self.max_pubdata_bits.map_or(n_tx, |bits| bits / tx_bits.max(1));
+        n_tx.min(by_txs).min(by_bits).max(1)
+    }
+
+    pub fn latency_exceeded(&self, pending: usize, n_tx: usize, block_start_ms: i64, now_ms: i64) -> bool {
+        match self.max_latency_ms {
+            Some(max_latency) if pending > 0 => {
+                now_ms - block_start_ms >= max_latency as i64 && pending as f64 >= self.min_fill_ratio * n_tx as f64
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_policy() {
+        let mut policy = SealPolicy::default();
+        assert_eq!(policy.idle_timeout_secs, None);
+        assert_eq!(policy.capacity(8, 100), 8);
+        assert!(!policy.latency_exceeded(1, 8, 0, i64::MAX));
+
+        policy.max_txs = Some(16);
+        policy.max_pubdata_bits = Some(550);
+        assert_eq!(policy.capacity(8, 100), 5);
+
+        policy.max_latency_ms = Some(1000);
+        policy.min_fill_ratio = 0.5;
+        assert!(!policy.latency_exceeded(0, 8, 0, 1000));
+        assert!(!policy.latency_exceeded(4, 8, 0, 999));
+        assert!(!policy.latency_exceeded(3, 8, 0, 1000));
+        assert!(policy.latency_exceeded(4, 8, 0, 1000));
+    }
+}
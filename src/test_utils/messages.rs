@@ -1,52 +1,95 @@
+use crate::msg::envelope::Envelope;
 use crate::types::matchengine::messages::{
-    DepositMessage, Message, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage,
+    BatchTransferMessage, DepositMessage, Message, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage,
 };
+use crate::types::matchengine::offsets::MsgOffset;
 use anyhow::{anyhow, Result};
 use serde_json::Value;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum WrappedMessage {
     DEPOSIT(Message<DepositMessage>),
     ORDER(Message<OrderMessage>),
     TRADE(Message<TradeMessage>),
     TRANSFER(Message<TransferMessage>),
+    #[allow(non_camel_case_types)]
+    BATCH_TRANSFER(Message<BatchTransferMessage>),
     USER(Message<UserMessage>),
     WITHDRAW(Message<WithdrawMessage>),
 }
 
-pub fn parse_msg(line: String) -> Result<WrappedMessage> {
-    let v: Value = serde_json::from_str(&line)?;
-    if let Value::String(typestr) = &v["type"] {
-        let val = v["value"].clone();
-
-        match typestr.as_str() {
-            "DepositMessage" => {
-                let data: DepositMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong deposit: {}", e))?;
-                Ok(WrappedMessage::DEPOSIT(data.into()))
-            }
-            "OrderMessage" => {
-                let data: OrderMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong order: {}", e))?;
-                Ok(WrappedMessage::ORDER(data.into()))
-            }
-            "TradeMessage" => {
-                let data: TradeMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong trade: {}", e))?;
-                Ok(WrappedMessage::TRADE(data.into()))
-            }
-            "TransferMessage" => {
-                let data: TransferMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong transfer: {}", e))?;
-                Ok(WrappedMessage::TRANSFER(data.into()))
-            }
-            "UserMessage" => {
-                let data: UserMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong user: {}", e))?;
-                Ok(WrappedMessage::USER(data.into()))
-            }
-            "WithdrawMessage" => {
-                let data: WithdrawMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong withdraw: {}", e))?;
-                Ok(WrappedMessage::WITHDRAW(data.into()))
-            }
-            other => Err(anyhow!("unrecognized type field {}", other)),
+impl WrappedMessage {
+    pub fn offset(&self) -> Option<&MsgOffset> {
+        match self {
+            WrappedMessage::DEPOSIT(msg) => msg.offset(),
+            WrappedMessage::ORDER(msg) => msg.offset(),
+            WrappedMessage::TRADE(msg) => msg.offset(),
+            WrappedMessage::TRANSFER(msg) => msg.offset(),
+            WrappedMessage::BATCH_TRANSFER(msg) => msg.offset(),
+            WrappedMessage::USER(msg) => msg.offset(),
+            WrappedMessage::WITHDRAW(msg) => msg.offset(),
+        }
+    }
+
+    // unix epoch timestamp in seconds, user messages carry none
+    pub fn timestamp(&self) -> Option<f64> {
+        match self {
+            WrappedMessage::DEPOSIT(msg) => Some(msg.timestamp),
+            WrappedMessage::ORDER(msg) => Some(msg.order.update_time),
+            WrappedMessage::TRADE(msg) => Some(msg.timestamp),
+            WrappedMessage::TRANSFER(msg) => Some(msg.time),
+            WrappedMessage::BATCH_TRANSFER(msg) => Some(msg.time),
+            WrappedMessage::USER(_) => None,
+            WrappedMessage::WITHDRAW(msg) => Some(msg.timestamp),
         }
-    } else {
-        Err(anyhow!("missed or unexpected type field: {}", line))
     }
+
+    // the `type` field used by `parse_msg`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            WrappedMessage::DEPOSIT(_) => "DepositMessage",
+            WrappedMessage::ORDER(_) => "OrderMessage",
+            WrappedMessage::TRADE(_) => "TradeMessage",
+            WrappedMessage::TRANSFER(_) => "TransferMessage",
+            WrappedMessage::BATCH_TRANSFER(_) => "BatchTransferMessage",
+            WrappedMessage::USER(_) => "UserMessage",
+            WrappedMessage::WITHDRAW(_) => "WithdrawMessage",
+        }
+    }
+
+    // the `value` field used by `parse_msg`, the offset is not included
+    pub fn to_value(&self) -> Result<Value> {
+        let value = match self {
+            WrappedMessage::DEPOSIT(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::ORDER(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::TRADE(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::TRANSFER(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::BATCH_TRANSFER(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::USER(msg) => serde_json::to_value(&**msg)?,
+            WrappedMessage::WITHDRAW(msg) => serde_json::to_value(&**msg)?,
+        };
+        Ok(value)
+    }
+
+    pub fn with_offset(self, offset: MsgOffset) -> Self {
+        match self {
+            WrappedMessage::DEPOSIT(msg) => WrappedMessage::DEPOSIT((msg.into_parts().0, offset).into()),
+            WrappedMessage::ORDER(msg) => WrappedMessage::ORDER((msg.into_parts().0, offset).into()),
+            WrappedMessage::TRADE(msg) => WrappedMessage::TRADE((msg.into_parts().0, offset).into()),
+            WrappedMessage::TRANSFER(msg) => WrappedMessage::TRANSFER((msg.into_parts().0, offset).into()),
+            WrappedMessage::BATCH_TRANSFER(msg) => WrappedMessage::BATCH_TRANSFER((msg.into_parts().0, offset).into()),
+            WrappedMessage::USER(msg) => WrappedMessage::USER((msg.into_parts().0, offset).into()),
+            WrappedMessage::WITHDRAW(msg) => WrappedMessage::WITHDRAW((msg.into_parts().0, offset).into()),
+        }
+    }
+}
+
+pub fn parse_msg(line: String) -> Result<WrappedMessage> {
+    let v: Value = serde_json::from_str(&line)?;
+    parse_msg_value(&v).map_err(|e| anyhow!("{}: {}", e, line))
+}
+
+// both the `{"type": .., "value": ..}` lines and the versioned envelopes are accepted
+pub fn parse_msg_value(v: &Value) -> Result<WrappedMessage> {
+    Ok(Envelope::from_value(v)?.decode()?)
 }
@@ -1,5 +1,6 @@
 #![allow(clippy::let_and_return)]
 use crate::account::random_mnemonic_with_rng;
+use crate::config::{Settings, Token, DEFAULT_TOKENS};
 use ethers::core::rand::SeedableRng;
 use ethers::prelude::coins_bip39::{English, Mnemonic};
 use std::str::FromStr;
@@ -9,27 +10,32 @@ use ethers::utils::{secret_key_to_address, to_checksum};
 use serde::Serialize;
 // TODO: Moves other test types to here.
 
-// shoule be consistent with dingir-exchange/migrations/20210223072038_markets_preset.sql
-// TODO: enum & impl
+// the tokens of the loaded config, or the built-in ones if no config is loaded, e.g. in unit tests
+fn tokens() -> &'static [Token] {
+    Settings::try_get().map_or(DEFAULT_TOKENS.as_slice(), |settings| settings.tokens.as_slice())
+}
+
 pub fn get_token_id_by_name(token_name: &str) -> u32 {
-    match token_name {
-        "ETH" => 0,
-        "USDT" => 1,
-        "UNI" => 2,
-        "LINK" => 3,
-        "YFI" => 4,
-        "MATIC" => 5,
-        _ => unreachable!(),
-    }
+    try_get_token_id_by_name(token_name).unwrap_or_else(|| unreachable!("unknown token {}", token_name))
+}
+
+pub fn try_get_token_id_by_name(token_name: &str) -> Option<u32> {
+    tokens().iter().find(|token| token.symbol == token_name).map(|token| token.id)
+}
+
+pub fn get_token_name_by_id(token_id: u32) -> Option<&'static str> {
+    tokens()
+        .iter()
+        .find(|token| token.id == token_id)
+        .map(|token| token.symbol.as_str())
 }
 
-// TODO: enum & impl
 pub fn prec_token_id(token_id: u32) -> u32 {
-    match token_id {
-        0 | 2 | 3 | 4 | 5 => 4,
-        1 => 4 + 2, // only USDT can be quote, quote prec = price prec + amount prec
-        _ => unreachable!(),
-    }
+    tokens()
+        .iter()
+        .find(|token| token.id == token_id)
+        .map(|token| token.prec)
+        .unwrap_or_else(|| unreachable!("unknown token {}", token_id))
 }
 
 pub fn get_mnemonic_by_account_id(account_id: u32) -> Mnemonic<English> {
@@ -22,6 +22,8 @@ pub struct L2BlockDetail {
 #[derive(Clone)]
 pub struct L2Block {
     pub block_id: usize,
+    // number of txs including the padding nops, each size is proved by its own circuit
+    pub block_size: usize,
     pub detail: L2BlockDetail,
     pub public_data: Vec<u8>,
 }
@@ -1,5 +1,5 @@
 pub use crate::types::l2;
-pub use crate::types::merkle_tree::MerklePath;
+pub use crate::types::merkle_tree::{CompactProof, MerklePath};
 use fluidex_common::num_bigint::BigInt;
 use fluidex_common::types::FrExt;
 use fluidex_common::Fr;
@@ -98,6 +98,12 @@ impl From<&[Fr; 1]> for MerkleLeafStr {
     }
 }
 
+impl From<MerkleLeafStr> for [Fr; 1] {
+    fn from(origin: MerkleLeafStr) -> Self {
+        [origin.0 .0]
+    }
+}
+
 impl Serialize for l2::TxType {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.serialize_i32(match self {
@@ -107,6 +113,7 @@ impl Serialize for l2::TxType {
             l2::TxType::Withdraw => 3,
             l2::TxType::PlaceOrder => 4,
             l2::TxType::SpotTrade => 5,
+            l2::TxType::BatchTransfer => 6,
         })
     }
 }
@@ -157,6 +164,7 @@ impl<'de> Deserialize<'de> for l2::TxType {
                     3 => l2::TxType::Withdraw,
                     4 => l2::TxType::PlaceOrder,
                     5 => l2::TxType::SpotTrade,
+                    6 => l2::TxType::BatchTransfer,
                     _ => return Err(de::Error::invalid_type(de::Unexpected::Signed(v), &self)),
                 };
                 Ok(tx_type)
@@ -194,6 +202,7 @@ impl<'de> Deserialize<'de> for l2::TxType {
                     3 => l2::TxType::Withdraw,
                     4 => l2::TxType::PlaceOrder,
                     5 => l2::TxType::SpotTrade,
+                    6 => l2::TxType::BatchTransfer,
                     _ => return Err(de::Error::invalid_type(de::Unexpected::Unsigned(v), &self)),
                 };
                 Ok(tx_type)
@@ -234,6 +243,43 @@ pub struct L2BlockSerde {
     pub new_account_roots: Vec<FrStr>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CompactProofSerde {
+    pub root: FrStr,
+    pub leaf: FrStr,
+    pub height: usize,
+    // the bitmap in decimal str repr, a js number can not hold all the 64 bits
+    #[serde(rename = "defaultLevels")]
+    pub default_levels: String,
+    pub siblings: MerklePathStr,
+}
+
+impl From<&CompactProof> for CompactProofSerde {
+    fn from(origin: &CompactProof) -> Self {
+        CompactProofSerde {
+            root: origin.root.into(),
+            leaf: origin.leaf.into(),
+            height: origin.height,
+            default_levels: origin.default_levels.to_string(),
+            siblings: origin.siblings.iter().map(From::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<CompactProofSerde> for CompactProof {
+    type Error = std::num::ParseIntError;
+
+    fn try_from(origin: CompactProofSerde) -> Result<Self, Self::Error> {
+        Ok(CompactProof {
+            root: origin.root.0,
+            leaf: origin.leaf.0,
+            height: origin.height,
+            default_levels: origin.default_levels.parse()?,
+            siblings: origin.siblings.into_iter().map(From::from).collect(),
+        })
+    }
+}
+
 //array::map is not stable
 fn array_map<U, T: Clone + Into<U>, const N: usize>(origin: [T; N]) -> [U; N] {
     let mut collector: Vec<U> = Vec::new();
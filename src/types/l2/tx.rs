@@ -1,4 +1,5 @@
 use super::order;
+use crate::types::matchengine::offsets::MsgOffset;
 use crate::types::merkle_tree::MerklePath;
 use anyhow::{anyhow, Result};
 use ethers::core::types::U256;
@@ -19,6 +20,7 @@ pub enum TxType {
     Withdraw,
     PlaceOrder,
     SpotTrade,
+    BatchTransfer,
 }
 
 pub struct RawTx {
@@ -36,7 +38,7 @@ pub struct RawTx {
     pub account_path1: MerklePath,
     pub root_before: Fr,
     pub root_after: Fr,
-    pub offset: Option<i64>,
+    pub offset: Option<MsgOffset>,
     // debug info
     // extra: any;
 }
@@ -146,6 +148,50 @@ impl TransferTx {
     }
 }
 
+// moves one token from one account to several accounts under a single signature and nonce.
+// every leg becomes a RawTx of its own checking the signature with the signed nonce, only the last one bumps the nonce.
+#[derive(Debug)]
+pub struct BatchTransferTx {
+    pub from: u32,
+    pub token_id: u32,
+    // (to, amount)
+    pub legs: Vec<(u32, u128)>,
+    pub from_nonce: Fr,
+    pub sig: Signature,
+}
+
+impl BatchTransferTx {
+    pub fn new(from: u32, token_id: u32, legs: Vec<(u32, u128)>) -> Self {
+        Self {
+            from,
+            token_id,
+            legs,
+            from_nonce: Fr::zero(),
+            sig: Signature::default(),
+        }
+    }
+
+    // none if it overflows
+    pub fn total_amount(&self) -> Option<u128> {
+        self.legs.iter().try_fold(0u128, |acc, (_, amount)| acc.checked_add(*amount))
+    }
+
+    pub fn hash(&self) -> Fr {
+        // adhoc ... FIXME
+        // same precision workaround as TransferTx
+        let legs = self.legs.iter().fold(Fr::zero(), |acc, (to, amount)| {
+            Fr::hash(&[acc, Fr::from_u32(*to), Fr::from_bigint(BigInt::from(amount / 1000000u128))])
+        });
+        Fr::hash(&[
+            Fr::from_u32(TxType::BatchTransfer as u32),
+            Fr::from_u32(self.token_id),
+            Fr::from_u32(self.from),
+            self.from_nonce,
+            legs,
+        ])
+    }
+}
+
 // WithdrawTx can only withdraw to one's own L1 address
 #[derive(Debug)]
 pub struct WithdrawTx {
@@ -163,8 +209,7 @@ impl WithdrawTx {
             account_id,
             token_id,
             amount,
-            nonce: Fr::zero(), //TODO: nonce is also not involved yet ...
-            //later we should also update the scripts in circuits
+            nonce: Fr::zero(),       // set to the signed nonce by the caller
             old_balance: Fr::zero(), // TODO: Maybe we should not involve old_balance into hash
             sig: Signature::default(),
         }
@@ -336,7 +381,8 @@ impl TxDataEncoder {
         self.tx_encode_bits as u32
     }
 
-    pub fn encode_account(&mut self, account_id: u32) -> Result<()> {
+    // fails if the id does not fit in `account_bits`
+    pub fn encode_account(&mut self, account_id: u64) -> Result<()> {
         self.ctx.as_mut().unwrap().encode_primint(account_id, self.account_bits)
     }
 
@@ -422,7 +468,7 @@ impl EncodeForPubData for NopTx {
 impl EncodeForPubData for UpdateKeyTx {
     fn encode_pubdata(&self, encoder: &mut TxDataEncoder) -> Result<()> {
         encoder.encode_heading(1)?;
-        encoder.encode_account(self.account_id)?;
+        encoder.encode_account(self.account_id.into())?;
         encoder.encode_fr(&self.l2key.sign, 1)?;
         encoder.encode_fr(&self.l2key.ay, 254)?;
         encoder.encode_padding();
@@ -434,8 +480,8 @@ impl EncodeForPubData for DepositTx {
     fn encode_pubdata(&self, encoder: &mut TxDataEncoder) -> Result<()> {
         assert!(self.l2key.is_none());
         encoder.encode_heading(0)?;
-        encoder.encode_account(self.account_id)?;
-        encoder.encode_account(self.account_id)?;
+        encoder.encode_account(self.account_id.into())?;
+        encoder.encode_account(self.account_id.into())?;
         encoder.encode_token(self.token_id)?;
         encoder.encode_amount(self.amount)?;
         encoder.encode_padding();
@@ -446,8 +492,8 @@ impl EncodeForPubData for DepositTx {
 impl EncodeForPubData for TransferTx {
     fn encode_pubdata(&self, encoder: &mut TxDataEncoder) -> Result<()> {
         encoder.encode_heading(0)?;
-        encoder.encode_account(self.from)?;
-        encoder.encode_account(self.to)?;
+        encoder.encode_account(self.from.into())?;
+        encoder.encode_account(self.to.into())?;
         encoder.encode_token(self.token_id)?;
         encoder.encode_amount(self.amount)?;
         encoder.encode_padding();
@@ -466,8 +512,8 @@ impl EncodeForPubData for (FullSpotTradeTx, (u32, u32)) {
         h += if order1.is_filled() { 2 } else { 0 };
         h += if order2.is_filled() { 4 } else { 0 };
         encoder.encode_heading(h)?;
-        encoder.encode_account(trade.order1_account_id)?;
-        encoder.encode_account(trade.order2_account_id)?;
+        encoder.encode_account(trade.order1_account_id.into())?;
+        encoder.encode_account(trade.order2_account_id.into())?;
         encoder.encode_token(trade.token_id_1to2)?;
         encoder.encode_token(trade.token_id_2to1)?;
         encoder.encode_fr_compressed(&order1.total_sell)?;
@@ -486,8 +532,8 @@ impl EncodeForPubData for (FullSpotTradeTx, (u32, u32)) {
 impl EncodeForPubData for WithdrawTx {
     fn encode_pubdata(&self, encoder: &mut TxDataEncoder) -> Result<()> {
         encoder.encode_heading(4)?; //001
-        encoder.encode_account(self.account_id)?;
-        encoder.encode_account(self.account_id)?;
+        encoder.encode_account(self.account_id.into())?;
+        encoder.encode_account(self.account_id.into())?;
         encoder.encode_token(self.token_id)?;
         encoder.encode_amount(self.amount)?;
         encoder.encode_padding();
@@ -667,6 +713,17 @@ fn test_tx_pubdata() {
     let hash = tx_encoder.finish();
     assert_eq!(hash.low_u128(), 45067006840171976501491216447014895325u128);
 }
+
+#[cfg(test)]
+#[test]
+fn test_encode_wide_account() {
+    let mut tx_encoder = TxDataEncoder::new(2, 2, 40);
+    assert!(tx_encoder.encode_account(1 << 39).is_ok());
+    assert!(tx_encoder.encode_account(1 << 40).is_err());
+    let mut tx_encoder = TxDataEncoder::new(2, 2, 2);
+    assert!(tx_encoder.encode_account(3).is_ok());
+    assert!(tx_encoder.encode_account(4).is_err());
+}
 /*
 #[cfg(test)]
 #[test]
@@ -5,11 +5,13 @@ use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::serde::HexArray;
 use serde::{Deserialize, Serialize};
 
+use super::offsets::MsgOffset;
+
 // TODO: reuse related types def in dingir-exchange
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message<T> {
     message: T,
-    offset: Option<i64>,
+    offset: Option<MsgOffset>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -161,6 +163,10 @@ pub struct WithdrawMessage {
     pub balance_available: Decimal,
     pub balance_frozen: Decimal,
     pub detail: String,
+    // the nonce of the sender covered by the signature, must equal the one in the state.
+    // it is optional only for the old messages, see `Processor::allow_missing_nonce`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u32>,
     #[serde(with = "HexArray")]
     pub signature: [u8; 64],
 }
@@ -172,6 +178,27 @@ pub struct TransferMessage {
     pub user_to: u32,
     pub asset: String,
     pub amount: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u32>,
+    #[serde(with = "HexArray")]
+    pub signature: [u8; 64],
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchTransferLeg {
+    pub user_to: u32,
+    pub amount: Decimal,
+}
+
+// a single signature covers all the legs, which are applied all or none
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchTransferMessage {
+    pub time: f64,
+    pub user_from: u32,
+    pub asset: String,
+    pub legs: Vec<BatchTransferLeg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u32>,
     #[serde(with = "HexArray")]
     pub signature: [u8; 64],
 }
@@ -182,22 +209,23 @@ impl TxMessage for DepositMessage {}
 impl TxMessage for OrderMessage {}
 impl TxMessage for TradeMessage {}
 impl TxMessage for TransferMessage {}
+impl TxMessage for BatchTransferMessage {}
 impl TxMessage for UserMessage {}
 impl TxMessage for WithdrawMessage {}
 
 impl<T: TxMessage> Message<T> {
-    pub fn new(message: T, offset: i64) -> Self {
+    pub fn new(message: T, offset: MsgOffset) -> Self {
         Self {
             message,
             offset: Some(offset),
         }
     }
 
-    pub fn offset(&self) -> Option<i64> {
-        self.offset
+    pub fn offset(&self) -> Option<&MsgOffset> {
+        self.offset.as_ref()
     }
 
-    pub fn into_parts(self) -> (T, Option<i64>) {
+    pub fn into_parts(self) -> (T, Option<MsgOffset>) {
         (self.message, self.offset)
     }
 }
@@ -208,8 +236,8 @@ impl<T: TxMessage> From<T> for Message<T> {
     }
 }
 
-impl<T: TxMessage> From<(T, i64)> for Message<T> {
-    fn from((message, offset): (T, i64)) -> Self {
+impl<T: TxMessage> From<(T, MsgOffset)> for Message<T> {
+    fn from((message, offset): (T, MsgOffset)) -> Self {
         Self {
             message,
             offset: Some(offset),
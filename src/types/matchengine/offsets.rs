@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// topic of the single stream consumed before multiple partitions were supported,
+// the offsets in old checkpoints belong to partition 0 of it
+pub const LEGACY_TOPIC: &str = "unifyevents";
+
+// position of a message in kafka
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsgOffset {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl MsgOffset {
+    pub fn new(topic: &str, partition: i32, offset: i64) -> Self {
+        Self {
+            topic: topic.to_string(),
+            partition,
+            offset,
+        }
+    }
+}
+
+// topic -> partition -> the last consumed offset
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KafkaOffsets(BTreeMap<String, BTreeMap<i32, i64>>);
+
+impl KafkaOffsets {
+    pub fn from_legacy(offset: i64) -> Self {
+        let mut offsets = Self::default();
+        offsets.update(&MsgOffset::new(LEGACY_TOPIC, 0, offset));
+        offsets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.0.get(topic).and_then(|partitions| partitions.get(&partition)).copied()
+    }
+
+    // offsets never go backwards
+    pub fn update(&mut self, offset: &MsgOffset) {
+        let last = self
+            .0
+            .entry(offset.topic.clone())
+            .or_default()
+            .entry(offset.partition)
+            .or_insert(offset.offset);
+        *last = (*last).max(offset.offset);
+    }
+
+    pub fn is_consumed(&self, offset: &MsgOffset) -> bool {
+        self.get(&offset.topic, offset.partition)
+            .map_or(false, |last| offset.offset <= last)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = MsgOffset> + '_ {
+        self.0.iter().flat_map(|(topic, partitions)| {
+            partitions
+                .iter()
+                .map(move |(partition, offset)| MsgOffset::new(topic, *partition, *offset))
+        })
+    }
+}
@@ -1,17 +1,33 @@
 // https://github1s.com/fluidex/circuits/blob/HEAD/helper.ts/binary_merkle_tree.ts
 use std::iter::Iterator;
+use std::marker::PhantomData;
 
+#[cfg(feature = "persist_sled")]
+use super::node_store::SledStore;
+use super::node_store::{MemoryStore, NodeIndex, NodeStore, StateStore};
 use fluidex_common::serde::FrBytes;
 use fluidex_common::types::MerkleValueMapType;
 use fluidex_common::{types::FrExt, Fr};
 use rayon::prelude::*;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
 
-type LeafIndex = u32;
-type NodeIndex = usize;
+pub type LeafIndex = u64;
 type LeafType = Fr;
 
-type ValueMap = MerkleValueMapType<NodeIndex, LeafType>;
+/// The hash function of the inner nodes, applied to all the children of a node
+pub trait MerkleHasher: Clone + Send + Sync + 'static {
+    fn hash(inputs: &[LeafType]) -> LeafType;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PoseidonHasher;
+
+impl MerkleHasher for PoseidonHasher {
+    #[inline(always)]
+    fn hash(inputs: &[LeafType]) -> LeafType {
+        Fr::hash(inputs)
+    }
+}
 
 pub struct MerkleProofN<const LENGTH: usize> {
     pub root: LeafType,
@@ -19,67 +35,158 @@ pub struct MerkleProofN<const LENGTH: usize> {
     pub path_elements: Vec<[LeafType; LENGTH]>,
 }
 pub type MerkleProof = MerkleProofN<1>;
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafDiff {
+    pub idx: LeafIndex,
+    pub old: LeafType,
+    pub new: LeafType,
+}
+/// [`MerkleProofN`] without the levels whose siblings are all the default node of the level.
+/// Bit `i` of `default_levels` is set when level `i` is left out of `siblings`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactProofN<const LENGTH: usize> {
+    pub root: LeafType,
+    pub leaf: LeafType,
+    pub height: usize,
+    pub default_levels: u64,
+    pub siblings: Vec<[LeafType; LENGTH]>,
+}
+pub type CompactProof = CompactProofN<1>;
 pub type MerklePath = Vec<[LeafType; 1]>;
+
+/// The nodes stored by a tree, see [`TreeN::stats`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    // level -> stored nodes, leaves first
+    pub nodes_per_level: Vec<usize>,
+    // stored nodes holding the default of their level, `compact` removes them
+    pub default_nodes: usize,
+    // size of the stored indices and values, the overhead of the store is not counted
+    pub bytes: usize,
+}
+
+impl TreeStats {
+    pub fn nodes(&self) -> usize {
+        self.nodes_per_level.iter().sum()
+    }
+    // adds up the stats of another tree, the trees may have different heights
+    pub fn merge(&mut self, other: &TreeStats) {
+        if self.nodes_per_level.len() < other.nodes_per_level.len() {
+            self.nodes_per_level.resize(other.nodes_per_level.len(), 0);
+        }
+        for (nodes, other_nodes) in self.nodes_per_level.iter_mut().zip(&other.nodes_per_level) {
+            *nodes += other_nodes;
+        }
+        self.default_nodes += other.default_nodes;
+        self.bytes += other.bytes;
+    }
+}
 #[derive(Debug)]
 struct HashCacheItemN<const LENGTH: usize> {
     inputs: [LeafType; LENGTH],
     result: LeafType,
 }
-type HashCacheItem = HashCacheItemN<2>;
 
 // TODO: use leaf_index/leaf_type as generics
-pub struct Tree {
+// `SIBLINGS` is always `ARITY - 1`, it can not be derived from `ARITY` on stable rust yet, so `new` checks it
+#[derive(Clone)]
+pub struct TreeN<H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> {
     pub height: usize,
     // precalculate mid hashes, so we don't have to store the empty nodes
     default_nodes: Vec<LeafType>,
+    // level -> idx of its first node in `data`
+    level_offsets: Vec<NodeIndex>,
 
     // In `data`, we only store the nodes with non empty values
-    // The idx is generated by level and local idx, for example, when height=3:
+    // The idx is generated by level and local idx, for example, when height=3 and ARITY=2:
     // leaf (level 0) nodes idx are data[0..=7], level 1 nodes idx are data[8..=11], etc.
-    data: ValueMap,
+    data: S,
+    hasher: PhantomData<H>,
 }
 
-/// [`Tree`] iterator
-pub struct TreeLeafIter<'a> {
-    tree: &'a Tree,
+/// The binary Poseidon tree used by the circuits
+pub type Tree = TreeN<PoseidonHasher, MemoryStore, 2, 1>;
+pub type QuaternaryTree = TreeN<PoseidonHasher, MemoryStore, 4, 3>;
+/// [`Tree`] with the nodes kept on disk, it has the same roots and proofs
+#[cfg(feature = "persist_sled")]
+pub type SledTree = TreeN<PoseidonHasher, SledStore, 2, 1>;
+/// [`Tree`] of the global state, on the store picked by the `storage` setting
+pub type StateTree = TreeN<PoseidonHasher, StateStore, 2, 1>;
+
+/// [`TreeN`] iterator
+pub struct TreeLeafIter<'a, H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> {
+    tree: &'a TreeN<H, S, ARITY, SIBLINGS>,
     size: usize,
-    data_iter: Box<dyn Iterator<Item = (NodeIndex, &'a LeafType)> + 'a>,
+    data_iter: Box<dyn Iterator<Item = (NodeIndex, LeafType)> + 'a>,
 }
 
-impl Tree {
+impl<H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> TreeN<H, S, ARITY, SIBLINGS> {
     pub fn print_config() {
-        println!("merkletree valueMap type: {}", std::any::type_name::<ValueMap>())
+        println!("merkletree node store type: {}", std::any::type_name::<S>())
     }
 
-    pub fn new(height: usize, default_leaf_node_value: LeafType) -> Self {
-        // check overflow
-        let _ = 2u32.checked_pow(height as u32).expect("tree depth error, overflow");
-        // 2**height leaves, and the total height of the tree is
+    pub fn new(height: usize, default_leaf_node_value: LeafType) -> Self
+    where
+        S: Default,
+    {
+        Self::with_store(height, default_leaf_node_value, S::default())
+    }
+
+    // `store` may already hold the nodes of a tree with the same height and default leaf
+    pub fn with_store(height: usize, default_leaf_node_value: LeafType, store: S) -> Self {
+        assert!(
+            ARITY >= 2 && SIBLINGS + 1 == ARITY,
+            "invalid tree arity {} with {} siblings",
+            ARITY,
+            SIBLINGS
+        );
+        // check overflow, every node must be addressable by a `NodeIndex`
+        let _ = ARITY.checked_pow(height as u32 + 1).expect("tree depth error, overflow");
+        // ARITY**height leaves, and the total height of the tree is
         //self.height = height;
         let mut default_nodes = vec![default_leaf_node_value];
         for i in 0..height {
-            default_nodes.push(Fr::hash(&[default_nodes[i], default_nodes[i]]));
+            default_nodes.push(H::hash(&[default_nodes[i]; ARITY]));
+        }
+        let mut level_offsets = vec![0];
+        for level in 0..height {
+            level_offsets.push(level_offsets[level] + ARITY.pow((height - level) as u32));
         }
-        let data = ValueMap::default();
         Self {
             height,
             default_nodes,
-            data,
+            level_offsets,
+            data: store,
+            hasher: PhantomData,
+        }
+    }
+
+    // the same tree with its nodes copied into `store`, which should be empty
+    pub fn copy_into<S2: NodeStore>(&self, mut store: S2) -> TreeN<H, S2, ARITY, SIBLINGS> {
+        for (idx, value) in self.data.entries() {
+            store.insert(idx, value);
+        }
+        TreeN {
+            height: self.height,
+            default_nodes: self.default_nodes.clone(),
+            level_offsets: self.level_offsets.clone(),
+            data: store,
+            hasher: PhantomData,
         }
     }
 
-    pub fn iter(&self) -> TreeLeafIter {
+    pub fn iter(&self) -> TreeLeafIter<H, S, ARITY, SIBLINGS> {
         TreeLeafIter::new(self)
     }
 
     #[inline]
-    pub fn get_tree_data(&self) -> &ValueMap {
+    pub fn get_tree_data(&self) -> &S {
         &self.data
     }
 
     #[inline]
-    pub fn max_leaf_num(&self) -> u32 {
-        2u32.checked_pow(self.height as u32).unwrap()
+    pub fn max_leaf_num(&self) -> LeafIndex {
+        (ARITY as LeafIndex).checked_pow(self.height as u32).unwrap()
     }
     /*
     pub fn print(dense = true, empty_label = 'None') {
@@ -106,61 +213,82 @@ impl Tree {
     */
 
     #[inline]
-    pub fn sibling_idx(&self, n: LeafIndex) -> LeafIndex {
-        if n % 2 == 1 {
-            n - 1
-        } else {
-            n + 1
+    pub fn sibling_idxs(&self, n: LeafIndex) -> [LeafIndex; SIBLINGS] {
+        let first = n - n % ARITY as LeafIndex;
+        let mut idxs = [first; SIBLINGS];
+        for (slot, idx) in idxs.iter_mut().zip((first..first + ARITY as LeafIndex).filter(|idx| *idx != n)) {
+            *slot = idx;
         }
+        idxs
     }
 
     #[inline]
     pub fn parent_idx(&self, n: LeafIndex) -> LeafIndex {
-        n >> 1
+        n / ARITY as LeafIndex
     }
 
     #[inline(always)]
     fn level_offset(&self, level: usize) -> usize {
-        (1usize << (self.height + 1)) - (1usize << (self.height - level + 1))
+        self.level_offsets[level]
     }
 
     #[inline]
-    fn get_flattened_idx(&self, level: usize, idx: u32) -> usize {
-        self.level_offset(level) + (idx as usize)
+    fn get_flattened_idx(&self, level: usize, idx: LeafIndex) -> NodeIndex {
+        self.level_offset(level) + (idx as NodeIndex)
     }
 
     #[inline]
-    fn from_flattened_idx(&self, level: usize, flattened: usize) -> u32 {
-        (flattened - self.level_offset(level)) as u32
+    fn from_flattened_idx(&self, level: usize, flattened: NodeIndex) -> LeafIndex {
+        (flattened - self.level_offset(level)) as LeafIndex
     }
 
-    pub fn get_value(&self, level: usize, idx: u32) -> LeafType {
-        *self
-            .data
-            .get(&self.get_flattened_idx(level, idx))
-            .unwrap_or(&self.default_nodes[level])
+    pub fn get_value(&self, level: usize, idx: LeafIndex) -> LeafType {
+        self.data
+            .get(self.get_flattened_idx(level, idx))
+            .unwrap_or(self.default_nodes[level])
     }
 
-    pub fn get_leaf(&self, idx: u32) -> LeafType {
+    pub fn get_leaf(&self, idx: LeafIndex) -> LeafType {
         self.get_value(0, idx)
     }
 
-    fn recalculate_parent(&mut self, level: usize, idx: u32) {
-        let lhs = self.get_value(level - 1, idx * 2);
-        let rhs = self.get_value(level - 1, idx * 2 + 1);
-        let new_hash = Fr::hash(&[lhs, rhs]);
-        self.data.insert(self.get_flattened_idx(level, idx), new_hash);
+    // the children (at `level`) of the node `parent` (at `level + 1`)
+    fn get_children(&self, level: usize, parent: LeafIndex) -> [LeafType; ARITY] {
+        let mut children = [self.default_nodes[level]; ARITY];
+        for (i, child) in children.iter_mut().enumerate() {
+            *child = self.get_value(level, parent * ARITY as LeafIndex + i as LeafIndex);
+        }
+        children
     }
 
-    pub fn set_value(&mut self, idx: u32, value: LeafType) {
-        let mut idx = idx;
-        if self.get_leaf(idx) == value {
-            return;
+    fn recalculate_parent(&mut self, level: usize, idx: LeafIndex) {
+        let new_hash = H::hash(&self.get_children(level - 1, idx));
+        self.put_node(level, self.get_flattened_idx(level, idx), new_hash);
+    }
+
+    // a node holding the default of its level is removed rather than stored
+    fn put_node(&mut self, level: usize, flattened: NodeIndex, value: LeafType) {
+        if value == self.default_nodes[level] {
+            self.data.remove(flattened);
+        } else {
+            self.data.insert(flattened, value);
         }
+    }
+
+    #[inline]
+    fn level_of(&self, flattened: NodeIndex) -> usize {
+        self.level_offsets.partition_point(|offset| *offset <= flattened) - 1
+    }
+
+    pub fn set_value(&mut self, idx: LeafIndex, value: LeafType) {
+        let mut idx = idx;
         if idx >= self.max_leaf_num() {
             panic!("invalid tree idx {}", idx);
         }
-        self.data.insert(idx as usize, value);
+        if self.get_leaf(idx) == value {
+            return;
+        }
+        self.put_node(0, idx as NodeIndex, value);
         for i in 1..=self.height {
             idx = self.parent_idx(idx);
             self.recalculate_parent(i, idx);
@@ -171,7 +299,7 @@ impl Tree {
     // first, it calculates some mid-level nodes as cache in parallel
     // then, it updates the tree sequentially, if a cache item is useful, then use it, if not, ignore the cache item and recalculate
     // in fact if we use some 'unsafe/raw pointer', we can get more precise control and speed up more...
-    pub fn set_value_parallel(&mut self, updates: &[(u32, LeafType)], parallel: usize) {
+    pub fn set_value_parallel(&mut self, updates: &[(LeafIndex, LeafType)], parallel: usize) {
         let mut parallel = parallel;
         if parallel == 0 {
             parallel = 8; // TODO: a better default
@@ -184,58 +312,49 @@ impl Tree {
         }
         // TODO: change updates into something like Into<ParIter> ...
         for chunk in updates.chunks(parallel) {
-            let diffs: Vec<Vec<HashCacheItem>> = chunk
+            let diffs: Vec<Vec<HashCacheItemN<ARITY>>> = chunk
                 .par_iter() // iterating over i32
                 .map(|(idx, value)| self.set_value_prepare_diff(*idx, *value))
                 .collect();
-            let chunk_vec: Vec<(u32, LeafType)> = chunk.to_vec();
+            let chunk_vec: Vec<(LeafIndex, LeafType)> = chunk.to_vec();
             for ((idx, value), cache) in chunk_vec.into_iter().zip(diffs.into_iter()) {
                 self.set_value_apply_diff(idx, value, cache)
             }
         }
     }
 
-    fn set_value_prepare_diff(&self, idx: u32, value: LeafType) -> Vec<HashCacheItem> {
+    fn set_value_prepare_diff(&self, idx: LeafIndex, value: LeafType) -> Vec<HashCacheItemN<ARITY>> {
         // the precalculating can be done parallelly
-        let mut precalculated = Vec::<HashCacheItem>::default();
+        let mut precalculated = Vec::<HashCacheItemN<ARITY>>::default();
         let mut cur_idx = idx;
         let mut cur_value = value;
         for i in 0..self.height {
-            let pair = if cur_idx % 2 == 0 {
-                [cur_value, self.get_value(i, cur_idx + 1)]
-            } else {
-                [self.get_value(i, cur_idx - 1), cur_value]
-            };
-            cur_value = Fr::hash(&pair);
-            cur_idx = self.parent_idx(cur_idx);
-            let cache_item = HashCacheItem {
-                inputs: pair,
-                result: cur_value,
-            };
+            let parent_idx = self.parent_idx(cur_idx);
+            let mut inputs = self.get_children(i, parent_idx);
+            inputs[(cur_idx % ARITY as LeafIndex) as usize] = cur_value;
+            cur_value = H::hash(&inputs);
+            cur_idx = parent_idx;
+            let cache_item = HashCacheItemN { inputs, result: cur_value };
             precalculated.push(cache_item);
         }
         precalculated
     }
 
-    fn set_value_apply_diff(&mut self, idx: u32, value: LeafType, precalculated: Vec<HashCacheItem>) {
+    fn set_value_apply_diff(&mut self, idx: LeafIndex, value: LeafType, precalculated: Vec<HashCacheItemN<ARITY>>) {
         // apply the precalculated
         let mut cache_miss = false;
         let mut cur_idx = idx;
         //cur_value = value;
-        self.data.insert(idx as usize, value);
+        self.put_node(0, idx as NodeIndex, value);
         //let cache_size = precalculated.len();
         //let mut cache_hit_count = 0;
         for i in 0..self.height {
-            let pair = if cur_idx % 2 == 0 {
-                [self.get_value(i, cur_idx), self.get_value(i, cur_idx + 1)]
-            } else {
-                [self.get_value(i, cur_idx - 1), self.get_value(i, cur_idx)]
-            };
             cur_idx = self.parent_idx(cur_idx);
+            let inputs = self.get_children(i, cur_idx);
             if !cache_miss {
                 // TODO: is the `cache_miss` shortcut really needed? comparing bigint is quite cheap compared to hash
                 // `cache_miss` makes codes more difficult to read
-                if precalculated[i].inputs[0] != pair[0] || precalculated[i].inputs[1] != pair[1] {
+                if precalculated[i].inputs != inputs {
                     // Due to self is a merkle tree, future caches will all be missed.
                     // precalculated becomes totally useless now
                     cache_miss = true;
@@ -243,57 +362,243 @@ impl Tree {
                 }
             }
             if cache_miss {
-                self.data.insert(self.get_flattened_idx(i + 1, cur_idx), Fr::hash(&pair));
+                self.put_node(i + 1, self.get_flattened_idx(i + 1, cur_idx), H::hash(&inputs));
             } else {
-                self.data.insert(self.get_flattened_idx(i + 1, cur_idx), precalculated[i].result);
+                self.put_node(i + 1, self.get_flattened_idx(i + 1, cur_idx), precalculated[i].result);
                 //cache_hit_count += 1;
             }
         }
         //println!("cache hit {}/{}", cache_hit_count, cache_size);
     }
 
+    // builds the tree bottom-up, every internal node is hashed exactly once, a level at a time in parallel
     pub fn fill_with_leaves_vec(&mut self, leaves: &[LeafType]) {
         if leaves.len() != self.max_leaf_num() as usize {
             panic!("invalid leaves size {}", leaves.len());
         }
-        // TODO: optimize here
-        for (i, item) in leaves.iter().enumerate() {
-            self.set_value(i as u32, *item);
+        let mut level_nodes = leaves.to_vec();
+        for level in 0..=self.height {
+            for (idx, value) in level_nodes.iter().enumerate() {
+                // on an empty tree, the nodes with the default value are not stored
+                if self.get_value(level, idx as LeafIndex) != *value {
+                    self.put_node(level, self.get_flattened_idx(level, idx as LeafIndex), *value);
+                }
+            }
+            if level < self.height {
+                level_nodes = level_nodes.par_chunks(ARITY).map(H::hash).collect();
+            }
         }
     }
 
     pub fn fill_with_leaves_map(&mut self, leaves: std::collections::HashMap<LeafIndex, LeafType>) {
-        for (k, v) in leaves.iter() {
-            self.set_value(*k, *v);
+        let updates: Vec<(LeafIndex, LeafType)> = leaves.into_iter().collect();
+        self.set_values(&updates);
+    }
+
+    // sets a batch of leaves, each ancestor of them is recalculated once, a level at a time in parallel.
+    // when an idx appears more than once, the last value wins, same as calling `set_value` one by one
+    pub fn set_values(&mut self, updates: &[(LeafIndex, LeafType)]) {
+        let mut dirty = Vec::with_capacity(updates.len());
+        for (idx, value) in updates {
+            if *idx >= self.max_leaf_num() {
+                panic!("invalid tree idx {}", idx);
+            }
+            if self.get_leaf(*idx) != *value {
+                self.put_node(0, *idx as NodeIndex, *value);
+                dirty.push(*idx);
+            }
+        }
+        for level in 1..=self.height {
+            dirty = dirty.into_iter().map(|idx| self.parent_idx(idx)).collect();
+            dirty.sort_unstable();
+            dirty.dedup();
+            let hashes: Vec<LeafType> = dirty.par_iter().map(|idx| H::hash(&self.get_children(level - 1, *idx))).collect();
+            for (idx, hash) in dirty.iter().zip(hashes) {
+                self.put_node(level, self.get_flattened_idx(level, *idx), hash);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            nodes_per_level: vec![0; self.height + 1],
+            ..Default::default()
+        };
+        for (flattened, value) in self.data.entries() {
+            let level = self.level_of(flattened);
+            stats.nodes_per_level[level] += 1;
+            if value == self.default_nodes[level] {
+                stats.default_nodes += 1;
+            }
         }
+        stats.bytes = stats.nodes() * std::mem::size_of::<(NodeIndex, LeafType)>();
+        stats
+    }
+
+    // removes the stored nodes holding the default of their level, which the trees saved by older versions may have.
+    // returns how many are removed
+    pub fn compact(&mut self) -> usize {
+        let defaults: Vec<NodeIndex> = self
+            .data
+            .entries()
+            .filter(|(flattened, value)| *value == self.default_nodes[self.level_of(*flattened)])
+            .map(|(flattened, _)| flattened)
+            .collect();
+        for flattened in &defaults {
+            self.data.remove(*flattened);
+        }
+        defaults.len()
     }
 
     #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn get_root(&self) -> LeafType {
         self.get_value(self.height, 0)
     }
 
-    pub fn get_proof(&self, index: u32) -> MerkleProof {
+    pub fn get_proof(&self, index: LeafIndex) -> MerkleProofN<SIBLINGS> {
         let mut index = index;
         let leaf = self.get_leaf(index);
         let mut path_elements = Vec::new();
         for i in 0..self.height {
-            path_elements.push([self.get_value(i, self.sibling_idx(index))]);
+            let mut siblings = [self.default_nodes[i]; SIBLINGS];
+            for (sibling, idx) in siblings.iter_mut().zip(self.sibling_idxs(index)) {
+                *sibling = self.get_value(i, idx);
+            }
+            path_elements.push(siblings);
             index = self.parent_idx(index);
         }
-        MerkleProof {
+        MerkleProofN {
             root: self.get_root(),
             path_elements,
             leaf,
         }
     }
+
+    // recomputes the root from the leaf and the path, `index` must be the leaf index the proof is generated for
+    pub fn verify_proof(index: LeafIndex, proof: &MerkleProofN<SIBLINGS>) -> bool {
+        let mut index = index;
+        let mut cur_value = proof.leaf;
+        for siblings in &proof.path_elements {
+            let pos = (index % ARITY as LeafIndex) as usize;
+            let mut inputs = [cur_value; ARITY];
+            let mut siblings = siblings.iter();
+            for (i, input) in inputs.iter_mut().enumerate() {
+                if i != pos {
+                    *input = *siblings.next().unwrap();
+                }
+            }
+            cur_value = H::hash(&inputs);
+            index /= ARITY as LeafIndex;
+        }
+        index == 0 && cur_value == proof.root
+    }
+
+    #[inline]
+    pub fn default_leaf(&self) -> LeafType {
+        self.default_nodes[0]
+    }
+
+    // a proof that the slot is still empty, none if it is not
+    pub fn get_default_leaf_proof(&self, index: LeafIndex) -> Option<MerkleProofN<SIBLINGS>> {
+        if self.get_leaf(index) != self.default_leaf() {
+            return None;
+        }
+        Some(self.get_proof(index))
+    }
+
+    // checks the slot is empty in the tree with root `proof.root`, which is not necessarily the current root
+    pub fn verify_default_leaf_proof(&self, index: LeafIndex, proof: &MerkleProofN<SIBLINGS>) -> bool {
+        proof.leaf == self.default_leaf() && proof.path_elements.len() == self.height && Self::verify_proof(index, proof)
+    }
+
+    // a tree can not be higher than 63 levels, see `with_store`, so the levels fit in the bitmap
+    pub fn encode_compact_proof(&self, proof: &MerkleProofN<SIBLINGS>) -> CompactProofN<SIBLINGS> {
+        assert_eq!(proof.path_elements.len(), self.height, "proof of another tree height");
+        let mut default_levels = 0;
+        let mut siblings = Vec::new();
+        for (level, level_siblings) in proof.path_elements.iter().enumerate() {
+            if level_siblings.iter().all(|sibling| *sibling == self.default_nodes[level]) {
+                default_levels |= 1 << level;
+            } else {
+                siblings.push(*level_siblings);
+            }
+        }
+        CompactProofN {
+            root: proof.root,
+            leaf: proof.leaf,
+            height: self.height,
+            default_levels,
+            siblings,
+        }
+    }
+
+    // none if the levels in the bitmap and the siblings do not add up to the tree height
+    pub fn decode_compact_proof(&self, proof: &CompactProofN<SIBLINGS>) -> Option<MerkleProofN<SIBLINGS>> {
+        if proof.height != self.height || proof.default_levels.checked_shr(self.height as u32).unwrap_or(0) != 0 {
+            return None;
+        }
+        let mut siblings = proof.siblings.iter();
+        let mut path_elements = Vec::with_capacity(self.height);
+        for level in 0..self.height {
+            if proof.default_levels & (1 << level) != 0 {
+                path_elements.push([self.default_nodes[level]; SIBLINGS]);
+            } else {
+                path_elements.push(*siblings.next()?);
+            }
+        }
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(MerkleProofN {
+            root: proof.root,
+            leaf: proof.leaf,
+            path_elements,
+        })
+    }
+
+    pub fn get_compact_proof(&self, index: LeafIndex) -> CompactProofN<SIBLINGS> {
+        self.encode_compact_proof(&self.get_proof(index))
+    }
+
+    pub fn verify_compact_proof(&self, index: LeafIndex, proof: &CompactProofN<SIBLINGS>) -> bool {
+        match self.decode_compact_proof(proof) {
+            Some(proof) => Self::verify_proof(index, &proof),
+            None => false,
+        }
+    }
+
+    // the leaves changed from `self` to `other` in index order, only the subtrees whose roots differ are visited
+    pub fn diff<S2: NodeStore>(&self, other: &TreeN<H, S2, ARITY, SIBLINGS>) -> Vec<LeafDiff> {
+        assert_eq!(self.height, other.height, "can not diff trees of different heights");
+        let mut diffs = Vec::new();
+        // (level, idx) of the nodes to visit, the children are pushed in reverse so the leaves come out in order
+        let mut stack: Vec<(usize, LeafIndex)> = vec![(self.height, 0)];
+        while let Some((level, idx)) = stack.pop() {
+            let (old, new) = (self.get_value(level, idx), other.get_value(level, idx));
+            if old == new {
+                continue;
+            }
+            if level == 0 {
+                diffs.push(LeafDiff { idx, old, new });
+                continue;
+            }
+            for child in (idx * ARITY as LeafIndex..(idx + 1) * ARITY as LeafIndex).rev() {
+                stack.push((level - 1, child));
+            }
+        }
+        diffs
+    }
 }
 
 pub fn empty_tree_root(level: usize, leaf: LeafType) -> LeafType {
     Tree::new(level, leaf).get_root()
 }
 
-impl Serialize for Tree {
+impl<H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> Serialize for TreeN<H, S, ARITY, SIBLINGS> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -306,13 +611,13 @@ impl Serialize for Tree {
         #[cfg(not(feature = "fr_string_repr"))]
         {
             tree.serialize_field("default_leaf_node_value", &Wrapper(self.default_nodes[0]))?;
-            let map: MerkleValueMapType<NodeIndex, Wrapper> = self.data.iter().map(|(k, v)| (*k, Wrapper(*v))).collect();
+            let map: MerkleValueMapType<NodeIndex, Wrapper> = self.data.entries().map(|(k, v)| (k, Wrapper(v))).collect();
             tree.serialize_field("data", &map)?;
         }
         #[cfg(feature = "fr_string_repr")]
         {
             tree.serialize_field("default_leaf_node_value", &self.default_nodes[0].to_decimal_string())?;
-            let map: MerkleValueMapType<NodeIndex, String> = self.data.iter().map(|(k, v)| (*k, v.to_decimal_string())).collect();
+            let map: MerkleValueMapType<NodeIndex, String> = self.data.entries().map(|(k, v)| (k, v.to_decimal_string())).collect();
             tree.serialize_field("data", &map)?;
         }
         tree.end()
@@ -320,7 +625,9 @@ impl Serialize for Tree {
 }
 
 #[cfg(not(feature = "fr_string_repr"))]
-impl<'de> Deserialize<'de> for Tree {
+impl<'de, H: MerkleHasher, S: NodeStore + Default, const ARITY: usize, const SIBLINGS: usize> Deserialize<'de>
+    for TreeN<H, S, ARITY, SIBLINGS>
+{
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -338,15 +645,19 @@ impl<'de> Deserialize<'de> for Tree {
 
         let wrapper = TreeWrapper::deserialize(deserializer)?;
 
-        let mut tree = Tree::new(wrapper.height, wrapper.default_leaf_node_value);
-        tree.data = wrapper.data.into_iter().map(|(k, v)| (k, v.0)).collect();
+        let mut tree = Self::new(wrapper.height, wrapper.default_leaf_node_value);
+        for (k, v) in wrapper.data {
+            tree.data.insert(k, v.0);
+        }
 
         Ok(tree)
     }
 }
 
 #[cfg(feature = "fr_string_repr")]
-impl<'de> Deserialize<'de> for Tree {
+impl<'de, H: MerkleHasher, S: NodeStore + Default, const ARITY: usize, const SIBLINGS: usize> Deserialize<'de>
+    for TreeN<H, S, ARITY, SIBLINGS>
+{
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -359,20 +670,19 @@ impl<'de> Deserialize<'de> for Tree {
         }
 
         let wrapper = TreeWrapper::deserialize(deserializer)?;
-        let mut tree = Tree::new(wrapper.height, Fr::from_str(wrapper.default_leaf_node_value.as_str()));
-        tree.data = wrapper.data.into_iter().map(|(k, v)| (k, Fr::from_str(v.as_str()))).collect();
+        let mut tree = Self::new(wrapper.height, Fr::from_str(wrapper.default_leaf_node_value.as_str()));
+        for (k, v) in wrapper.data {
+            tree.data.insert(k, Fr::from_str(v.as_str()));
+        }
 
         Ok(tree)
     }
 }
 
-impl<'a> TreeLeafIter<'a> {
-    fn new(tree: &'a Tree) -> TreeLeafIter<'a> {
+impl<'a, H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> TreeLeafIter<'a, H, S, ARITY, SIBLINGS> {
+    fn new(tree: &'a TreeN<H, S, ARITY, SIBLINGS>) -> Self {
         let max_leaf_num = tree.max_leaf_num() as usize;
-        let iter = tree
-            .data
-            .iter()
-            .filter_map(move |(idx, fr)| if *idx < max_leaf_num { Some((*idx, fr)) } else { None });
+        let iter = tree.data.entries().filter(move |(idx, _)| *idx < max_leaf_num);
 
         Self {
             tree,
@@ -382,8 +692,8 @@ impl<'a> TreeLeafIter<'a> {
     }
 }
 
-impl<'a> Iterator for TreeLeafIter<'a> {
-    type Item = (u32, &'a LeafType);
+impl<'a, H: MerkleHasher, S: NodeStore, const ARITY: usize, const SIBLINGS: usize> Iterator for TreeLeafIter<'a, H, S, ARITY, SIBLINGS> {
+    type Item = (LeafIndex, LeafType);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.data_iter
@@ -438,7 +748,7 @@ mod tests {
         };
         let rand_idx = || {
             let mut rng = rand::thread_rng();
-            rng.gen_range(0..2u32.pow(20u32))
+            rng.gen_range(0..2u64.pow(20))
         };
         for _ in 0..count {
             updates.push((rand_idx(), rand_elem()));
@@ -451,6 +761,165 @@ mod tests {
         assert_eq!(tree1.get_root(), tree2.get_root());
     }
 
+    #[test]
+    #[should_panic(expected = "invalid tree idx 4")]
+    fn test_set_value_out_of_range() {
+        let mut tree = Tree::new(2, Fr::zero());
+        // the default value must not slip through as a no-op
+        tree.set_value(4, Fr::zero());
+    }
+
+    #[test]
+    fn test_binary_root() {
+        let mut tree = Tree::new(2, Fr::zero());
+        let leaves: Vec<Fr> = (1..=4u32).map(Fr::from_u32).collect();
+        for (i, leaf) in leaves.iter().enumerate() {
+            tree.set_value(i as LeafIndex, *leaf);
+        }
+        let left = Fr::hash(&[leaves[0], leaves[1]]);
+        let right = Fr::hash(&[leaves[2], leaves[3]]);
+        assert_eq!(tree.get_root(), Fr::hash(&[left, right]));
+        assert_eq!(tree.get_proof(2).path_elements, vec![[leaves[3]], [left]]);
+    }
+
+    #[test]
+    fn test_quaternary_tree() {
+        let mut tree1 = QuaternaryTree::new(2, Fr::zero());
+        let mut tree2 = tree1.clone();
+        assert_eq!(tree1.max_leaf_num(), 16);
+        let leaves: Vec<Fr> = (1..=16u32).map(Fr::from_u32).collect();
+        let updates: Vec<(LeafIndex, Fr)> = leaves.iter().enumerate().map(|(i, leaf)| (i as LeafIndex, *leaf)).collect();
+        for (idx, value) in updates.iter() {
+            tree1.set_value(*idx, *value);
+        }
+        tree2.set_value_parallel(&updates, 4);
+        assert_eq!(tree1.get_root(), tree2.get_root());
+
+        let mids: Vec<Fr> = leaves.chunks(4).map(Fr::hash).collect();
+        assert_eq!(tree1.get_root(), Fr::hash(&mids));
+        assert_eq!(
+            tree1.get_proof(6).path_elements,
+            vec![[leaves[4], leaves[5], leaves[7]], [mids[0], mids[2], mids[3]]]
+        );
+    }
+
+    #[test]
+    fn test_bulk_build() {
+        let leaves: Vec<Fr> = (0..64u32).map(|i| Fr::from_u32(i % 5)).collect();
+        let mut tree1 = Tree::new(6, Fr::zero());
+        let mut tree2 = Tree::new(6, Fr::zero());
+        for (i, leaf) in leaves.iter().enumerate() {
+            tree1.set_value(i as LeafIndex, *leaf);
+        }
+        tree2.fill_with_leaves_vec(&leaves);
+        assert_eq!(tree1.get_root(), tree2.get_root());
+        assert_eq!(tree1.get_tree_data().len(), tree2.get_tree_data().len());
+
+        let updates: Vec<(LeafIndex, Fr)> = vec![(9, Fr::from_u32(7)), (3, Fr::from_u32(8)), (9, Fr::from_u32(9)), (63, Fr::zero())];
+        for (idx, value) in updates.iter() {
+            tree1.set_value(*idx, *value);
+        }
+        tree2.set_values(&updates);
+        assert_eq!(tree1.get_root(), tree2.get_root());
+        assert_eq!(tree1.get_proof(9).path_elements, tree2.get_proof(9).path_elements);
+    }
+
+    #[test]
+    fn test_default_leaf_proof_and_diff() {
+        let mut tree1 = QuaternaryTree::new(3, Fr::zero());
+        tree1.set_values(&[(5, Fr::from_u32(1)), (40, Fr::from_u32(2))]);
+        assert!(tree1.get_default_leaf_proof(5).is_none());
+        let proof = tree1.get_default_leaf_proof(6).unwrap();
+        assert!(tree1.verify_default_leaf_proof(6, &proof));
+        assert!(!tree1.verify_default_leaf_proof(4, &proof));
+        assert!(QuaternaryTree::verify_proof(40, &tree1.get_proof(40)));
+
+        let mut tree2 = tree1.clone();
+        tree2.set_values(&[(63, Fr::from_u32(3)), (5, Fr::zero()), (40, Fr::from_u32(2))]);
+        assert!(tree2.get_default_leaf_proof(5).is_some());
+        assert_eq!(
+            tree1.diff(&tree2),
+            vec![
+                LeafDiff {
+                    idx: 5,
+                    old: Fr::from_u32(1),
+                    new: Fr::zero()
+                },
+                LeafDiff {
+                    idx: 63,
+                    old: Fr::zero(),
+                    new: Fr::from_u32(3)
+                },
+            ]
+        );
+        assert!(tree2.diff(&tree2.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_compact_proof() {
+        let mut tree = Tree::new(10, Fr::zero());
+        tree.set_values(&[(3, Fr::from_u32(1)), (1000, Fr::from_u32(2))]);
+        let proof = tree.get_compact_proof(3);
+        // only the level where the two leaves meet is not default
+        assert_eq!(proof.siblings.len(), 1);
+        assert_eq!(proof.default_levels, 0b01_1111_1111);
+        assert!(tree.verify_compact_proof(3, &proof));
+        assert!(!tree.verify_compact_proof(2, &proof));
+        assert_eq!(
+            tree.decode_compact_proof(&proof).unwrap().path_elements,
+            tree.get_proof(3).path_elements
+        );
+
+        let mut broken = proof.clone();
+        broken.default_levels |= 1 << 9;
+        assert!(tree.decode_compact_proof(&broken).is_none());
+        let mut broken = proof;
+        broken.default_levels = 1 << 10;
+        assert!(tree.decode_compact_proof(&broken).is_none());
+    }
+
+    #[test]
+    fn test_tree_stats_and_compact() {
+        let mut tree = QuaternaryTree::new(2, Fr::zero());
+        tree.set_value(5, Fr::from_u32(1));
+        tree.set_value(9, Fr::from_u32(2));
+        assert_eq!(tree.stats().nodes_per_level, vec![2, 2, 1]);
+        // setting a leaf back to the default frees its node and the ancestors left default
+        tree.set_value(9, Fr::zero());
+        let stats = tree.stats();
+        assert_eq!(stats.nodes_per_level, vec![1, 1, 1]);
+        assert_eq!(stats.default_nodes, 0);
+        assert_eq!(stats.bytes, 3 * std::mem::size_of::<(NodeIndex, Fr)>());
+
+        let root = tree.get_root();
+        tree.data.insert(9, Fr::zero());
+        tree.data.insert(tree.get_flattened_idx(1, 2), tree.default_nodes[1]);
+        assert_eq!(tree.stats().default_nodes, 2);
+        assert_eq!(tree.compact(), 2);
+        assert_eq!(tree.stats().nodes(), 3);
+        assert_eq!(tree.get_root(), root);
+
+        let mut total = TreeStats::default();
+        total.merge(&tree.stats());
+        total.merge(&Tree::new(3, Fr::zero()).stats());
+        assert_eq!(total.nodes_per_level, vec![1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn test_tall_tree() {
+        let mut tree = Tree::new(40, Fr::zero());
+        let last = tree.max_leaf_num() - 1;
+        assert_eq!(last, (1 << 40) - 1);
+        tree.set_values(&[(last, Fr::from_u32(1)), (1 << 32, Fr::from_u32(2))]);
+        assert_eq!(tree.get_leaf(last), Fr::from_u32(1));
+        assert!(Tree::verify_proof(last, &tree.get_proof(last)));
+        assert!(Tree::verify_proof(1 << 32, &tree.get_proof(1 << 32)));
+        assert!(!Tree::verify_proof(0, &tree.get_proof(1 << 32)));
+        let mut leaves: Vec<(LeafIndex, Fr)> = tree.iter().collect();
+        leaves.sort_by_key(|(idx, _)| *idx);
+        assert_eq!(leaves, vec![(1 << 32, Fr::from_u32(2)), (last, Fr::from_u32(1))]);
+    }
+
     #[test]
     //#[ignore]
     fn bench_tree_parallel() {
@@ -468,7 +937,7 @@ mod tests {
             };
             let rand_idx = || {
                 let mut rng = rand::thread_rng();
-                rng.gen_range(0..2u32.pow(20u32))
+                rng.gen_range(0..2u64.pow(20))
             };
             for _ in 0..inner_count {
                 same_updates.push((i, rand_elem()));
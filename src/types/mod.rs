@@ -1,3 +1,4 @@
 pub mod l2;
 pub mod matchengine;
 pub mod merkle_tree;
+pub mod node_store;
@@ -0,0 +1,333 @@
+use fluidex_common::types::MerkleValueMapType;
+use fluidex_common::Fr;
+
+#[cfg(feature = "persist_sled")]
+pub use sled_store::SledStore;
+
+pub type NodeIndex = usize;
+
+/// Storage of the non-default nodes of a merkle tree, keyed by the flattened node index.
+/// A missing node means it still holds the default value of its level.
+pub trait NodeStore: Send + Sync {
+    fn get(&self, idx: NodeIndex) -> Option<Fr>;
+    fn insert(&mut self, idx: NodeIndex, value: Fr);
+    fn remove(&mut self, idx: NodeIndex);
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // all the stored nodes, in no particular order
+    fn entries(&self) -> Box<dyn Iterator<Item = (NodeIndex, Fr)> + '_>;
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryStore(MerkleValueMapType<NodeIndex, Fr>);
+
+impl NodeStore for MemoryStore {
+    #[inline]
+    fn get(&self, idx: NodeIndex) -> Option<Fr> {
+        self.0.get(&idx).copied()
+    }
+    #[inline]
+    fn insert(&mut self, idx: NodeIndex, value: Fr) {
+        self.0.insert(idx, value);
+    }
+    #[inline]
+    fn remove(&mut self, idx: NodeIndex) {
+        self.0.remove(&idx);
+    }
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn entries(&self) -> Box<dyn Iterator<Item = (NodeIndex, Fr)> + '_> {
+        Box::new(self.0.iter().map(|(idx, value)| (*idx, *value)))
+    }
+}
+
+/// The store of the state trees, picked by the `storage` setting at startup
+#[derive(Clone)]
+pub enum StateStore {
+    Memory(MemoryStore),
+    #[cfg(feature = "persist_sled")]
+    Sled(SledStore),
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        Self::Memory(MemoryStore::default())
+    }
+}
+
+impl StateStore {
+    // an empty store on the same backend, for a new tree
+    pub fn new_empty(&self) -> Self {
+        match self {
+            Self::Memory(_) => Self::default(),
+            #[cfg(feature = "persist_sled")]
+            Self::Sled(store) => Self::Sled(store.new_sibling()),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $store:ident => $e:expr) => {
+        match $self {
+            StateStore::Memory($store) => $e,
+            #[cfg(feature = "persist_sled")]
+            StateStore::Sled($store) => $e,
+        }
+    };
+}
+
+impl NodeStore for StateStore {
+    #[inline]
+    fn get(&self, idx: NodeIndex) -> Option<Fr> {
+        dispatch!(self, store => store.get(idx))
+    }
+    #[inline]
+    fn insert(&mut self, idx: NodeIndex, value: Fr) {
+        dispatch!(self, store => store.insert(idx, value))
+    }
+    #[inline]
+    fn remove(&mut self, idx: NodeIndex) {
+        dispatch!(self, store => store.remove(idx))
+    }
+    fn len(&self) -> usize {
+        dispatch!(self, store => store.len())
+    }
+    fn entries(&self) -> Box<dyn Iterator<Item = (NodeIndex, Fr)> + '_> {
+        dispatch!(self, store => store.entries())
+    }
+}
+
+#[cfg(feature = "persist_sled")]
+mod sled_store {
+    use super::{NodeIndex, NodeStore};
+    use fluidex_common::serde::FrBytes;
+    use fluidex_common::Fr;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Serialize, Deserialize)]
+    struct FrWrapper(#[serde(with = "FrBytes")] Fr);
+
+    // the most recently used nodes, evicted by the tick of their last use
+    struct LruCache {
+        capacity: usize,
+        tick: u64,
+        nodes: HashMap<NodeIndex, (Fr, u64)>,
+        ticks: BTreeMap<u64, NodeIndex>,
+    }
+
+    impl LruCache {
+        fn new(capacity: usize) -> Self {
+            Self {
+                capacity: capacity.max(1),
+                tick: 0,
+                nodes: HashMap::new(),
+                ticks: BTreeMap::new(),
+            }
+        }
+        fn touch(&mut self, idx: NodeIndex, value: Fr) {
+            self.tick += 1;
+            if let Some((_, old_tick)) = self.nodes.insert(idx, (value, self.tick)) {
+                self.ticks.remove(&old_tick);
+            }
+            self.ticks.insert(self.tick, idx);
+            while self.nodes.len() > self.capacity {
+                // `BTreeMap::pop_first` is not stable yet
+                let oldest = *self.ticks.keys().next().unwrap();
+                let evicted = self.ticks.remove(&oldest).unwrap();
+                self.nodes.remove(&evicted);
+            }
+        }
+        fn get(&mut self, idx: NodeIndex) -> Option<Fr> {
+            let value = self.nodes.get(&idx).map(|(value, _)| *value)?;
+            self.touch(idx, value);
+            Some(value)
+        }
+        fn remove(&mut self, idx: NodeIndex) {
+            if let Some((_, tick)) = self.nodes.remove(&idx) {
+                self.ticks.remove(&tick);
+            }
+        }
+    }
+
+    /// Nodes written through to a sled tree, with the hot ones cached in memory.
+    /// Reopening the same sled tree gives back the merkle tree, as long as it is created with the same height.
+    /// Only one store should be opened with [`SledStore::new`] on a sled tree, the other ones are its siblings.
+    pub struct SledStore {
+        db: sled::Tree,
+        // the keys are the prefix followed by the big endian node index,
+        // so the stores of many merkle trees share a single sled tree
+        prefix: [u8; 8],
+        // `sled::Tree::len` scans the whole tree
+        len: usize,
+        // the nodes of a sibling are not reopened, they are removed once it is dropped
+        ephemeral: bool,
+        next_prefix: Arc<AtomicU64>,
+        cache: Mutex<LruCache>,
+    }
+
+    impl SledStore {
+        pub fn new(db: sled::Tree, cache_capacity: usize) -> Self {
+            let prefix = 0u64.to_be_bytes();
+            let len = db.scan_prefix(prefix).count();
+            Self {
+                db,
+                prefix,
+                len,
+                ephemeral: false,
+                next_prefix: Arc::new(AtomicU64::new(1)),
+                cache: Mutex::new(LruCache::new(cache_capacity)),
+            }
+        }
+
+        /// An empty store in the same sled tree, with the same cache capacity
+        pub fn new_sibling(&self) -> Self {
+            let store = Self {
+                db: self.db.clone(),
+                prefix: self.next_prefix.fetch_add(1, Ordering::Relaxed).to_be_bytes(),
+                len: 0,
+                ephemeral: true,
+                next_prefix: self.next_prefix.clone(),
+                cache: Mutex::new(LruCache::new(self.cache.lock().unwrap().capacity)),
+            };
+            // left behind by a crash, while the sled tree was used by another process
+            store.clear().expect("clear merkle nodes");
+            store
+        }
+
+        pub fn flush(&self) -> sled::Result<usize> {
+            self.db.flush()
+        }
+
+        // big endian, so sled iterates the nodes in index order
+        fn key(&self, idx: NodeIndex) -> [u8; 16] {
+            let mut key = [0u8; 16];
+            key[..8].copy_from_slice(&self.prefix);
+            key[8..].copy_from_slice(&(idx as u64).to_be_bytes());
+            key
+        }
+
+        fn clear(&self) -> sled::Result<()> {
+            let mut batch = sled::Batch::default();
+            for key in self.db.scan_prefix(self.prefix).keys() {
+                batch.remove(key?);
+            }
+            self.db.apply_batch(batch)
+        }
+    }
+
+    // the copy is a sibling, so it takes no memory besides its cache
+    impl Clone for SledStore {
+        fn clone(&self) -> Self {
+            let mut store = self.new_sibling();
+            let mut batch = sled::Batch::default();
+            for (idx, value) in self.entries() {
+                batch.insert(
+                    &store.key(idx)[..],
+                    bincode::serialize(&FrWrapper(value)).expect("encode merkle node"),
+                );
+            }
+            store.db.apply_batch(batch).expect("write merkle node");
+            store.len = self.len;
+            store
+        }
+    }
+
+    impl Drop for SledStore {
+        fn drop(&mut self) {
+            if self.ephemeral {
+                if let Err(e) = self.clear() {
+                    log::warn!("failed to remove the merkle nodes of a dropped tree: {}", e);
+                }
+            }
+        }
+    }
+
+    impl NodeStore for SledStore {
+        fn get(&self, idx: NodeIndex) -> Option<Fr> {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(idx) {
+                return Some(value);
+            }
+            let value = self
+                .db
+                .get(self.key(idx))
+                .expect("read merkle node")
+                .map(|bytes| bincode::deserialize::<FrWrapper>(bytes.as_ref()).expect("decode merkle node").0)?;
+            cache.touch(idx, value);
+            Some(value)
+        }
+        fn insert(&mut self, idx: NodeIndex, value: Fr) {
+            let bytes = bincode::serialize(&FrWrapper(value)).expect("encode merkle node");
+            if self.db.insert(self.key(idx), bytes).expect("write merkle node").is_none() {
+                self.len += 1;
+            }
+            self.cache.get_mut().unwrap().touch(idx, value);
+        }
+        fn remove(&mut self, idx: NodeIndex) {
+            if self.db.remove(self.key(idx)).expect("remove merkle node").is_some() {
+                self.len -= 1;
+            }
+            self.cache.get_mut().unwrap().remove(idx);
+        }
+        fn len(&self) -> usize {
+            self.len
+        }
+        fn entries(&self) -> Box<dyn Iterator<Item = (NodeIndex, Fr)> + '_> {
+            Box::new(self.db.scan_prefix(self.prefix).map(|entry| {
+                let (key, bytes) = entry.expect("read merkle node");
+                let mut idx = [0u8; 8];
+                idx.copy_from_slice(&key[8..]);
+                let value = bincode::deserialize::<FrWrapper>(bytes.as_ref()).expect("decode merkle node").0;
+                (u64::from_be_bytes(idx) as NodeIndex, value)
+            }))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "persist_sled"))]
+mod tests {
+    use super::*;
+    use crate::types::merkle_tree::{SledTree, Tree};
+    use fluidex_common::ff::Field;
+    use fluidex_common::types::FrExt;
+
+    #[test]
+    fn test_sled_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let updates: Vec<(u64, Fr)> = (0..64u32).map(|i| (u64::from((i * 37) % 1024), Fr::from_u32(i + 1))).collect();
+        let mut mem_tree = Tree::new(10, Fr::zero());
+        // a tiny cache, so most of the nodes are read back from sled
+        let mut sled_tree = SledTree::with_store(10, Fr::zero(), SledStore::new(db.open_tree("nodes").unwrap(), 16));
+        mem_tree.set_value_parallel(&updates, 4);
+        for (idx, value) in &updates {
+            sled_tree.set_value(*idx, *value);
+        }
+        assert_eq!(mem_tree.get_root(), sled_tree.get_root());
+        assert_eq!(mem_tree.get_tree_data().len(), sled_tree.get_tree_data().len());
+        for idx in [0, 37, 500, 1023] {
+            assert_eq!(mem_tree.get_proof(idx).path_elements, sled_tree.get_proof(idx).path_elements);
+        }
+
+        // the copy lives in the same sled tree until it is dropped
+        let copy = sled_tree.clone();
+        assert_eq!(copy.get_root(), mem_tree.get_root());
+        assert_eq!(copy.get_tree_data().len(), sled_tree.get_tree_data().len());
+        drop(copy);
+        assert_eq!(db.open_tree("nodes").unwrap().len(), sled_tree.get_tree_data().len());
+
+        let reopened = SledTree::with_store(10, Fr::zero(), SledStore::new(db.open_tree("nodes").unwrap(), 16));
+        assert_eq!(reopened.get_root(), mem_tree.get_root());
+        assert_eq!(reopened.get_tree_data().len(), sled_tree.get_tree_data().len());
+        let mut leaves: Vec<(u64, Fr)> = reopened.iter().collect();
+        leaves.sort_by_key(|(idx, _)| *idx);
+        let mut expected: Vec<(u64, Fr)> = mem_tree.iter().collect();
+        expected.sort_by_key(|(idx, _)| *idx);
+        assert_eq!(leaves, expected);
+    }
+}
@@ -2,13 +2,13 @@ use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::types::{DecimalExt, FrExt};
 use fluidex_common::Fr;
 use rollup_state_manager::account::Account;
+use rollup_state_manager::config::Settings;
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
 use rollup_state_manager::test_utils::circuit::{CircuitSource, CircuitTestCase, CircuitTestData};
 use rollup_state_manager::test_utils::types::prec_token_id;
 use rollup_state_manager::types::l2::{self, DepositTx, L2BlockSerde, L2Key, OrderInput, SpotTradeTx, TransferTx, UpdateKeyTx, WithdrawTx};
 use serde_json::json;
 
-use rollup_state_manager::params;
 use std::option::Option::None;
 use std::sync::{Arc, RwLock};
 
@@ -278,21 +278,15 @@ impl Block {
 }
 
 pub fn get_l2_block_test_case() -> CircuitTestCase {
+    let levels = Settings::tree_levels();
     let main = format!(
         "Block({}, {}, {}, {})",
-        *params::NTXS,
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS
+        Settings::ntxs(),
+        levels.balance,
+        levels.order,
+        levels.account
     );
-    let test_data = Block::new(
-        *params::NTXS,
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS,
-        *params::VERBOSE,
-    )
-    .test_data();
+    let test_data = Block::new(Settings::ntxs(), levels.balance, levels.order, levels.account, Settings::verbose()).test_data();
     CircuitTestCase {
         source: CircuitSource {
             src: "src/block.circom".to_owned(),
@@ -4,8 +4,8 @@ use anyhow::Result;
 use fluidex_common::rust_decimal_macros::dec;
 use fluidex_common::types::{Decimal, DecimalExt};
 use rollup_state_manager::account::Account;
+use rollup_state_manager::config::Settings;
 use rollup_state_manager::msg::msg_processor;
-use rollup_state_manager::params;
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
 use rollup_state_manager::test_utils::messages::{parse_msg, WrappedMessage};
 use rollup_state_manager::test_utils::types::{get_mnemonic_by_account_id, prec_token_id};
@@ -22,19 +22,21 @@ use {pprof::protos::Message, std::io::Write};
 
 fn bench_with_dummy_transfers() -> Result<()> {
     GlobalState::print_config();
+    let levels = Settings::tree_levels();
     let state = Arc::new(RwLock::new(GlobalState::new(
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS,
-        *params::VERBOSE,
+        levels.balance,
+        levels.order,
+        levels.account,
+        Settings::verbose(),
     )));
 
     let mut processor = msg_processor::Processor {
         enable_check_sig: false,
+        allow_missing_nonce: true,
         ..Default::default()
     };
 
-    let mut manager = ManagerWrapper::new(state, *params::NTXS, None, *params::VERBOSE);
+    let mut manager = ManagerWrapper::new(state, Settings::ntxs(), None, Settings::verbose());
 
     // step1: create users
     let user1 = Account::from_mnemonic(1, &get_mnemonic_by_account_id(1)).unwrap();
@@ -50,8 +52,8 @@ fn bench_with_dummy_transfers() -> Result<()> {
         l2_pubkey: user2.bjj_pub_key(),
     };
     println!("user1 {:?} user2 {:?}", user1_msg, user2_msg);
-    processor.handle_user_msg(&mut manager, user1_msg.into());
-    processor.handle_user_msg(&mut manager, user2_msg.into());
+    processor.handle_user_msg(&mut manager, user1_msg.into()).unwrap();
+    processor.handle_user_msg(&mut manager, user2_msg.into()).unwrap();
 
     // step2: deposit assets
 
@@ -67,7 +69,7 @@ fn bench_with_dummy_transfers() -> Result<()> {
         detail: "none".to_string(),
     };
 
-    processor.handle_deposit_msg(&mut manager, deposit.into());
+    processor.handle_deposit_msg(&mut manager, deposit.into()).unwrap();
 
     // step3: bench transfer
     let amount = dec!(1).to_u64(prec_token_id(0));
@@ -104,16 +106,18 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     println!("prepare bench: {} records", messages.len());
 
     GlobalState::print_config();
+    let levels = Settings::tree_levels();
     let state = Arc::new(RwLock::new(GlobalState::new(
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS,
-        *params::VERBOSE,
+        levels.balance,
+        levels.order,
+        levels.account,
+        Settings::verbose(),
     )));
 
     //amplify the records: in each iter we run records on a group of new accounts
     let mut processor = msg_processor::Processor {
         enable_check_sig: false,
+        allow_missing_nonce: true,
         ..Default::default()
     };
 
@@ -123,7 +127,7 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     // by clone accounts with same trades
     let loop_num = 50;
 
-    let mut manager = ManagerWrapper::new(state, *params::NTXS, None, *params::VERBOSE);
+    let mut manager = ManagerWrapper::new(state, Settings::ntxs(), None, Settings::verbose());
     let timing = Instant::now();
     let mut inner_timing = Instant::now();
 
@@ -134,12 +138,12 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
                 WrappedMessage::DEPOSIT(deposit) => {
                     let mut deposit = deposit.clone();
                     deposit.user_id += account_offset;
-                    processor.handle_deposit_msg(&mut manager, deposit);
+                    processor.handle_deposit_msg(&mut manager, deposit).unwrap();
                 }
                 WrappedMessage::ORDER(order) => {
                     let mut order = order.clone();
                     order.order.user += account_offset;
-                    processor.handle_order_msg(&mut manager, order);
+                    processor.handle_order_msg(&mut manager, order).unwrap();
                 }
                 WrappedMessage::TRADE(trade) => {
                     let mut trade = trade.clone();
@@ -155,23 +159,23 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
                         o.user += account_offset;
                         o
                     });
-                    processor.handle_trade_msg(&mut manager, trade);
+                    processor.handle_trade_msg(&mut manager, trade).unwrap();
                 }
                 WrappedMessage::TRANSFER(transfer) => {
                     let mut transfer = transfer.clone();
                     transfer.user_from += account_offset;
                     transfer.user_to += account_offset;
-                    processor.handle_transfer_msg(&mut manager, transfer);
+                    processor.handle_transfer_msg(&mut manager, transfer).unwrap();
                 }
                 WrappedMessage::USER(user) => {
                     let mut user = user.clone();
                     user.user_id += account_offset;
-                    processor.handle_user_msg(&mut manager, user);
+                    processor.handle_user_msg(&mut manager, user).unwrap();
                 }
                 WrappedMessage::WITHDRAW(withdraw) => {
                     let mut withdraw = withdraw.clone();
                     withdraw.user_id += account_offset;
-                    processor.handle_withdraw_msg(&mut manager, withdraw);
+                    processor.handle_withdraw_msg(&mut manager, withdraw).unwrap();
                 }
                 _ => unreachable!(),
             }
@@ -196,7 +200,7 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     println!(
         "bench for {} blocks (TPS: {})",
         blocks.len(),
-        (*params::NTXS * blocks.len()) as f32 / timing.elapsed().as_secs_f32()
+        (Settings::ntxs() * blocks.len()) as f32 / timing.elapsed().as_secs_f32()
     );
     Ok(blocks)
 }
@@ -239,5 +243,7 @@ fn profile_bench() {
 }
 
 fn main() {
+    dotenv::dotenv().ok();
+    Settings::init_default();
     profile_bench();
 }
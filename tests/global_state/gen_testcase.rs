@@ -6,7 +6,6 @@
 
 use anyhow::Result;
 use normpath::PathExt;
-use rollup_state_manager::params;
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
 use rollup_state_manager::test_utils;
 use rollup_state_manager::test_utils::circuit::{write_test_case, CircuitTestCase, CircuitTestData};
@@ -25,40 +24,45 @@ fn replay_msgs(
     block_sender: crossbeam_channel::Sender<L2Block>,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
+        let levels = Settings::tree_levels();
         let state = Arc::new(RwLock::new(GlobalState::new(
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS,
-            *params::VERBOSE,
+            levels.balance,
+            levels.order,
+            levels.account,
+            Settings::verbose(),
         )));
-        let mut manager = ManagerWrapper::new(state, *params::NTXS, None, *params::VERBOSE);
+        let mut manager = ManagerWrapper::new(state, Settings::ntxs(), None, Settings::verbose());
 
         println!("genesis root {}", manager.root());
 
-        let mut processor = msg_processor::Processor::default();
+        // the test data carries no nonces, its signatures cover nonce 0
+        let mut processor = msg_processor::Processor {
+            allow_missing_nonce: true,
+            ..Default::default()
+        };
 
         let timing = Instant::now();
         for msg in msg_receiver.iter() {
             match msg {
                 WrappedMessage::DEPOSIT(deposit) => {
-                    processor.handle_deposit_msg(&mut manager, deposit);
+                    processor.handle_deposit_msg(&mut manager, deposit).unwrap();
                 }
                 WrappedMessage::ORDER(order) => {
-                    processor.handle_order_msg(&mut manager, order);
+                    processor.handle_order_msg(&mut manager, order).unwrap();
                 }
                 WrappedMessage::TRADE(trade) => {
                     let trade_id = trade.id;
-                    processor.handle_trade_msg(&mut manager, trade);
+                    processor.handle_trade_msg(&mut manager, trade).unwrap();
                     println!("trade {} test done", trade_id);
                 }
                 WrappedMessage::TRANSFER(transfer) => {
-                    processor.handle_transfer_msg(&mut manager, transfer);
+                    processor.handle_transfer_msg(&mut manager, transfer).unwrap();
                 }
                 WrappedMessage::USER(user) => {
-                    processor.handle_user_msg(&mut manager, user);
+                    processor.handle_user_msg(&mut manager, user).unwrap();
                 }
                 WrappedMessage::WITHDRAW(withdraw) => {
-                    processor.handle_withdraw_msg(&mut manager, withdraw);
+                    processor.handle_withdraw_msg(&mut manager, withdraw).unwrap();
                 }
                 _ => {
                     //other msg is omitted
@@ -83,7 +87,7 @@ fn replay_msgs(
         println!(
             "genesis {} blocks (TPS: {})",
             block_num,
-            (*params::NTXS * block_num) as f32 / timing.elapsed().as_secs_f32()
+            (Settings::ntxs() * block_num) as f32 / timing.elapsed().as_secs_f32()
         );
         Ok(())
     }))
@@ -105,7 +109,9 @@ pub fn run(src: &str) -> Result<()> {
 
     let blocks: Vec<_> = blk_receiver.iter().collect();
 
-    loader_thread.map(|h| h.join().expect("loader thread failed"));
+    if let Some(handle) = loader_thread {
+        handle.join().expect("loader thread failed")?;
+    }
     replay_thread.map(|h| h.join().expect("replay thread failed"));
 
     export_circuit_and_testdata(&circuit_repo, blocks)?;
@@ -113,15 +119,16 @@ pub fn run(src: &str) -> Result<()> {
 }
 
 pub fn export_circuit_and_testdata(circuit_repo: &Path, blocks: Vec<L2Block>) -> Result<()> {
+    let levels = Settings::tree_levels();
     let test_case = CircuitTestCase {
         source: test_utils::circuit::CircuitSource {
             src: String::from("src/block.circom"),
             main: format!(
                 "Block({}, {}, {}, {})",
-                *params::NTXS,
-                *params::BALANCELEVELS,
-                *params::ORDERLEVELS,
-                *params::ACCOUNTLEVELS
+                Settings::ntxs(),
+                levels.balance,
+                levels.order,
+                levels.account
             ),
         },
         data: blocks